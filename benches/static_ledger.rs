@@ -0,0 +1,63 @@
+use criterion::{BatchSize, BenchmarkId, Criterion, criterion_group, criterion_main};
+use lasagna_blockchain::{
+    block::Block, blockchain::Blockchain, genesis::GenesisConfig, keys::SecretKey,
+    params::ChainParams,
+};
+
+fn next_block(blockchain: &Blockchain, sk: &SecretKey) -> Block {
+    let head = blockchain.get_block(blockchain.best_path_head()).unwrap();
+    (head.header.timeslot + 1..)
+        .find_map(|timeslot| blockchain.make_block_at(sk, timeslot))
+        .unwrap()
+}
+
+// Every root account is in the ledger, so it has as many accounts as there are root accounts.
+// Blocks are added before measuring, so the static ledger has started to move
+fn blockchain_with_accounts(accounts: usize) -> (Blockchain, SecretKey) {
+    let sks = (0..accounts)
+        .map(|_| SecretKey::generate())
+        .collect::<Vec<_>>();
+    let root_accounts = sks
+        .iter()
+        .map(SecretKey::get_public_key)
+        .collect::<Vec<_>>();
+    let params = ChainParams::mainnet();
+    let blocks = 3 * params.seed_age + 10;
+    let genesis = GenesisConfig::with_root_accounts(params, root_accounts);
+    let genesis_block = Blockchain::produce_genesis_block(&genesis, &sks[0]);
+    let mut blockchain = Blockchain::start(genesis, genesis_block);
+
+    for _ in 0..blocks {
+        let block = next_block(&blockchain, &sks[0]);
+        blockchain.add_block(block).unwrap();
+    }
+
+    (blockchain, sks[0].clone())
+}
+
+fn bench_ledger_size(c: &mut Criterion) {
+    let mut group = c.benchmark_group("static_ledger");
+    for accounts in [10, 100, 1_000] {
+        let (blockchain, sk) = blockchain_with_accounts(accounts);
+        let seed_age = blockchain.genesis.params.seed_age;
+        let len = blockchain.best_path.len() as i64;
+
+        group.bench_function(BenchmarkId::new("add_block", accounts), |b| {
+            b.iter_batched(
+                || (blockchain.clone(), next_block(&blockchain, &sk)),
+                |(mut blockchain, block)| blockchain.add_block(block).unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_function(BenchmarkId::new("of_next_block", accounts), |b| {
+            b.iter(|| blockchain.get_static_ledger_of(len).unwrap())
+        });
+        group.bench_function(BenchmarkId::new("seed_age_back", accounts), |b| {
+            b.iter(|| blockchain.get_static_ledger_of(len - seed_age).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_ledger_size);
+criterion_main!(benches);
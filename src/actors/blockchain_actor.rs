@@ -0,0 +1,412 @@
+use std::collections::HashSet;
+
+use actix::{Actor, Context, Handler, Message, MessageResult, Recipient};
+use anyhow::Result;
+use iroh::NodeId;
+
+use crate::{
+    actors::{
+        clock_actor::NewTimeslot,
+        network_actor::{PublishBlock, PublishTransaction, RequestParent},
+    },
+    block::Block,
+    blockchain::Blockchain,
+    header::BlockHeader,
+    keys::{PublicKey, SecretKey},
+    params::ChainParams,
+    storage::BlockStore,
+    transaction::Transaction,
+    util::{BlockPtr, MiniLas},
+};
+
+/// Owns the blockchain of the node and tries to produce a block on every timeslot
+pub struct BlockchainActor {
+    blockchain: Blockchain,
+    sk: SecretKey,
+    store: Option<BlockStore>,
+    subscribers: HashSet<Recipient<PublishBlock>>,
+    transaction_subscribers: HashSet<Recipient<PublishTransaction>>,
+    parent_requesters: HashSet<Recipient<RequestParent>>,
+}
+
+/// Subscribe to the blocks produced by this node
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Subscribe(pub Recipient<PublishBlock>);
+
+/// Subscribe to the transactions accepted into the transaction buffer, except those received from peers
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SubscribeTransactions(pub Recipient<PublishTransaction>);
+
+/// Subscribe to the parents of orphans, so they can be fetched from the network
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SubscribeOrphans(pub Recipient<RequestParent>);
+
+#[derive(Message)]
+#[rtype(result = "Result<()>")]
+pub struct AddBlock(pub Block);
+
+/// A block received from a peer, the peer is asked for the parent if the block is an orphan
+#[derive(Message)]
+#[rtype(result = "Result<()>")]
+pub struct AddBlockFrom(pub Block, pub NodeId);
+
+/// A transaction made by this node, it is published once it is accepted
+#[derive(Message)]
+#[rtype(result = "Result<()>")]
+pub struct AddTransaction(pub Transaction);
+
+/// A transaction received from a peer, the gossip protocol already relays it to the others
+#[derive(Message)]
+#[rtype(result = "Result<()>")]
+pub struct AddTransactionFrom(pub Transaction, pub NodeId);
+
+#[derive(Message)]
+#[rtype(result = "MiniLas")]
+pub struct GetBalance(pub PublicKey);
+
+#[derive(Message)]
+#[rtype(result = "BlockPtr")]
+pub struct GetBestHead;
+
+#[derive(Message)]
+#[rtype(result = "ChainParams")]
+pub struct GetChainParams;
+
+#[derive(Message)]
+#[rtype(result = "Vec<BlockHeader>")]
+pub struct GetHeaders {
+    pub from_depth: i64,
+    pub count: usize,
+}
+
+/// The transactions of each of the blocks, `None` if any of them is unknown
+#[derive(Message)]
+#[rtype(result = "Option<Vec<Vec<Transaction>>>")]
+pub struct GetBodies(pub Vec<BlockPtr>);
+
+/// A block of the chain, on the best path or not
+#[derive(Message)]
+#[rtype(result = "Option<Block>")]
+pub struct GetBlock(pub BlockPtr);
+
+/// Whether the block is in the chain or waiting in the orphan pool
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct HasBlock(pub BlockPtr);
+
+/// Validates a chain of headers and returns those of the blocks we do not have yet
+#[derive(Message)]
+#[rtype(result = "Result<Vec<BlockHeader>>")]
+pub struct ValidateHeaders(pub Vec<BlockHeader>);
+
+impl BlockchainActor {
+    pub fn new(blockchain: Blockchain, sk: SecretKey) -> Self {
+        Self {
+            blockchain,
+            sk,
+            store: None,
+            subscribers: Default::default(),
+            transaction_subscribers: Default::default(),
+            parent_requesters: Default::default(),
+        }
+    }
+
+    /// Persist every accepted block, the blockchain must be the one the store was opened with
+    pub fn with_store(mut self, store: BlockStore) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    fn add_block(&mut self, block: Block, peer: Option<NodeId>) -> Result<()> {
+        // Once the store failed, a block added in memory could not be stored, and neither could its children
+        if let Some(store) = self.store.as_ref() {
+            store.check()?;
+        }
+
+        let is_new = self.blockchain.get_block(&block.ptr()).is_none();
+        self.blockchain.add_block_from(block.clone(), peer)?;
+
+        if let Some(store) = self.store.as_mut().filter(|_| is_new) {
+            store.append_added_block(&self.blockchain, &block)?;
+            store.write_best_path(&self.blockchain.best_path)?;
+            store.compact_if_due(&self.blockchain)?;
+        }
+
+        let parent = BlockPtr::new(block.header.prev_hash, block.header.depth - 1);
+        // A parent that is an orphan itself is already being requested
+        if self.blockchain.orphans.contains(&block.ptr())
+            && !self.blockchain.orphans.contains(&parent)
+        {
+            self.parent_requesters.iter().for_each(|sub| {
+                sub.do_send(RequestParent {
+                    parent: parent.clone(),
+                    peer,
+                })
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Actor for BlockchainActor {
+    type Context = Context<Self>;
+}
+
+impl Handler<NewTimeslot> for BlockchainActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: NewTimeslot, _: &mut Self::Context) -> Self::Result {
+        self.blockchain.orphans.expire(msg.0);
+
+        let Some(block) = self.blockchain.make_block_at(&self.sk, msg.0) else {
+            return;
+        };
+
+        if self.add_block(block.clone(), None).is_ok() {
+            self.subscribers
+                .iter()
+                .for_each(|sub| sub.do_send(PublishBlock(block.clone())));
+        }
+    }
+}
+
+impl Handler<Subscribe> for BlockchainActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Self::Context) -> Self::Result {
+        self.subscribers.insert(msg.0);
+    }
+}
+
+impl Handler<SubscribeTransactions> for BlockchainActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeTransactions, _: &mut Self::Context) -> Self::Result {
+        self.transaction_subscribers.insert(msg.0);
+    }
+}
+
+impl Handler<SubscribeOrphans> for BlockchainActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeOrphans, _: &mut Self::Context) -> Self::Result {
+        self.parent_requesters.insert(msg.0);
+    }
+}
+
+impl Handler<AddBlock> for BlockchainActor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: AddBlock, _: &mut Self::Context) -> Self::Result {
+        self.add_block(msg.0, None)
+    }
+}
+
+impl Handler<AddBlockFrom> for BlockchainActor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: AddBlockFrom, _: &mut Self::Context) -> Self::Result {
+        self.add_block(msg.0, Some(msg.1))
+    }
+}
+
+impl Handler<AddTransaction> for BlockchainActor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: AddTransaction, _: &mut Self::Context) -> Self::Result {
+        self.blockchain.add_transaction(msg.0.clone())?;
+        self.transaction_subscribers
+            .iter()
+            .for_each(|sub| sub.do_send(PublishTransaction(msg.0.clone())));
+        Ok(())
+    }
+}
+
+impl Handler<AddTransactionFrom> for BlockchainActor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: AddTransactionFrom, _: &mut Self::Context) -> Self::Result {
+        self.blockchain.add_transaction(msg.0)
+    }
+}
+
+impl Handler<GetBalance> for BlockchainActor {
+    type Result = MiniLas;
+
+    fn handle(&mut self, msg: GetBalance, _: &mut Self::Context) -> Self::Result {
+        self.blockchain.dynamic_ledger.get_balance(&msg.0)
+    }
+}
+
+impl Handler<GetBestHead> for BlockchainActor {
+    type Result = MessageResult<GetBestHead>;
+
+    fn handle(&mut self, _: GetBestHead, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.blockchain.best_path_head().clone())
+    }
+}
+
+impl Handler<GetChainParams> for BlockchainActor {
+    type Result = MessageResult<GetChainParams>;
+
+    fn handle(&mut self, _: GetChainParams, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.blockchain.params().clone())
+    }
+}
+
+impl Handler<GetHeaders> for BlockchainActor {
+    type Result = MessageResult<GetHeaders>;
+
+    fn handle(&mut self, msg: GetHeaders, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.blockchain.get_headers(msg.from_depth, msg.count))
+    }
+}
+
+impl Handler<GetBodies> for BlockchainActor {
+    type Result = Option<Vec<Vec<Transaction>>>;
+
+    fn handle(&mut self, msg: GetBodies, _: &mut Self::Context) -> Self::Result {
+        msg.0
+            .iter()
+            .map(|ptr| {
+                self.blockchain
+                    .get_block(ptr)
+                    .map(|block| block.transactions.clone())
+            })
+            .collect()
+    }
+}
+
+impl Handler<GetBlock> for BlockchainActor {
+    type Result = Option<Block>;
+
+    fn handle(&mut self, msg: GetBlock, _: &mut Self::Context) -> Self::Result {
+        self.blockchain.get_block(&msg.0).cloned()
+    }
+}
+
+impl Handler<HasBlock> for BlockchainActor {
+    type Result = bool;
+
+    fn handle(&mut self, msg: HasBlock, _: &mut Self::Context) -> Self::Result {
+        self.blockchain.get_block(&msg.0).is_some() || self.blockchain.orphans.contains(&msg.0)
+    }
+}
+
+impl Handler<ValidateHeaders> for BlockchainActor {
+    type Result = Result<Vec<BlockHeader>>;
+
+    fn handle(&mut self, msg: ValidateHeaders, _: &mut Self::Context) -> Self::Result {
+        self.blockchain.validate_header_chain(msg.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{Las, genesis::GenesisConfig, mempool::MIN_RELAY_FEE};
+
+    // Keeps the transactions it is asked to publish
+    struct Recorder(Arc<Mutex<Vec<Transaction>>>);
+
+    impl Actor for Recorder {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<PublishTransaction> for Recorder {
+        type Result = Result<()>;
+
+        fn handle(&mut self, msg: PublishTransaction, _: &mut Self::Context) -> Self::Result {
+            self.0.lock().unwrap().push(msg.0);
+            Ok(())
+        }
+    }
+
+    #[actix::test]
+    async fn test_blocks_are_made_on_timeslots() {
+        let params = ChainParams::devnet();
+        let sk1 = SecretKey::generate();
+        let sk2 = SecretKey::generate();
+        let genesis = GenesisConfig::with_root_accounts(params.clone(), vec![sk1.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk1);
+        let blockchain = Blockchain::start(genesis, genesis_block);
+        let actor = BlockchainActor::new(blockchain, sk1.clone()).start();
+
+        let transaction = Transaction::new(&sk1, sk2.get_public_key(), Las(1), MIN_RELAY_FEE, 0);
+        actor
+            .send(AddTransaction(transaction))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            actor.send(GetBalance(sk1.get_public_key())).await.unwrap(),
+            params.root_amount
+        );
+
+        let mut timeslot = 0;
+        while actor.send(GetBestHead).await.unwrap().depth == 0 {
+            timeslot += 1;
+            actor.send(NewTimeslot(timeslot)).await.unwrap();
+        }
+
+        assert_eq!(
+            actor.send(GetBalance(sk1.get_public_key())).await.unwrap(),
+            params.root_amount + params.block_reward - Las(1).into_minilas()
+        );
+        assert_eq!(
+            actor.send(GetBalance(sk2.get_public_key())).await.unwrap(),
+            Las(1).into_minilas()
+        );
+    }
+
+    #[actix::test]
+    async fn test_accepted_transactions_are_published() {
+        let sk = SecretKey::generate();
+        let to = SecretKey::generate().get_public_key();
+        let genesis =
+            GenesisConfig::with_root_accounts(ChainParams::devnet(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+        let blockchain = Blockchain::start(genesis, genesis_block);
+        let actor = BlockchainActor::new(blockchain, sk.clone()).start();
+
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let recorder = Recorder(published.clone()).start();
+        actor
+            .send(SubscribeTransactions(recorder.clone().recipient()))
+            .await
+            .unwrap();
+
+        let transaction = Transaction::new(&sk, to.clone(), Las(1), MIN_RELAY_FEE, 0);
+        actor
+            .send(AddTransaction(transaction.clone()))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Rejected transactions and those received from peers are not published
+        let invalid = Transaction::new(&sk, to.clone(), Las(1), MIN_RELAY_FEE, 0);
+        assert!(actor.send(AddTransaction(invalid)).await.unwrap().is_err());
+        let received = Transaction::new(&sk, to, Las(1), MIN_RELAY_FEE, 1);
+        let peer = iroh::SecretKey::from(&SecretKey::generate()).public();
+        actor
+            .send(AddTransactionFrom(received, peer))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // The recorder gets the messages in the order they were sent, so it has seen all of them by now
+        let marker = Transaction::new(&sk, SecretKey::generate().get_public_key(), Las(1), 1, 9);
+        recorder
+            .send(PublishTransaction(marker.clone()))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(*published.lock().unwrap(), vec![transaction, marker]);
+    }
+}
@@ -1,8 +1,8 @@
-use std::{collections::HashSet, time::Duration};
+use std::collections::HashSet;
 
 use actix::{Actor, Addr, Context, Handler, Message, Recipient};
 
-use crate::util::{Timeslot, SLOT_LENGTH};
+use crate::{clock::Clock, params::ChainParams, util::Timeslot};
 
 
 /// Notifies subscribers when a new timeslot is reached
@@ -14,6 +14,12 @@ pub struct ClockActor {
 #[rtype(result = "()")]
 pub struct Subscribe(pub Recipient<NewTimeslot>);
 
+impl Default for ClockActor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ClockActor {
     pub fn new() -> Self {
         Self {
@@ -21,15 +27,23 @@ impl ClockActor {
         }
     }
 
-    pub async fn run_loop(addr: Addr<Self>, start_time: u128) {
-        let mut curr_timeslot = crate::util::calculate_timeslot(start_time);
+    /// Notifies the actor of every timeslot reached according to `clock`
+    pub async fn run_loop(
+        addr: Addr<Self>,
+        start_time: u128,
+        params: ChainParams,
+        clock: impl Clock,
+    ) {
+        let slot_length = params.slot_length as u128;
+        let mut curr_timeslot =
+            crate::util::calculate_timeslot(clock.now(), start_time, slot_length);
         addr.do_send(NewTimeslot(curr_timeslot));
         loop {
             let next_timeslot = curr_timeslot + 1;
-            let next_timeslot_start = start_time + SLOT_LENGTH * (next_timeslot as u128);
-            let time_to_sleep = next_timeslot_start.saturating_sub(crate::util::get_unix_timestamp());
-            tokio::time::sleep(Duration::from_micros(time_to_sleep as _)).await;
-            let new_timeslot = crate::util::calculate_timeslot(start_time);
+            let next_timeslot_start = start_time + slot_length * (next_timeslot as u128);
+            clock.sleep_until(next_timeslot_start).await;
+            let new_timeslot =
+                crate::util::calculate_timeslot(clock.now(), start_time, slot_length);
             if new_timeslot != curr_timeslot {
                 curr_timeslot = new_timeslot;
                 addr.do_send(NewTimeslot(new_timeslot));
@@ -62,3 +76,70 @@ impl Actor for ClockActor {
 #[rtype(result = "()")]
 pub struct NewTimeslot(pub Timeslot);
 
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::clock::MockClock;
+
+    // Keeps every timeslot it is notified of
+    struct Recorder(Arc<Mutex<Vec<Timeslot>>>);
+
+    impl Actor for Recorder {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<NewTimeslot> for Recorder {
+        type Result = ();
+
+        fn handle(&mut self, msg: NewTimeslot, _: &mut Self::Context) -> Self::Result {
+            self.0.lock().unwrap().push(msg.0);
+        }
+    }
+
+    async fn wait_for(timeslots: &Mutex<Vec<Timeslot>>, expected: &[Timeslot]) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while timeslots.lock().unwrap().as_slice() != expected {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[actix::test]
+    async fn test_timeslots_follow_the_clock() {
+        let params = ChainParams::devnet();
+        let slot_length = params.slot_length as u128;
+        let start_time = 1_000_000;
+        let clock = MockClock::new(start_time + 2 * slot_length);
+
+        let timeslots = Arc::new(Mutex::new(Vec::new()));
+        let clock_actor = ClockActor::new().start();
+        let recorder = Recorder(timeslots.clone()).start();
+        clock_actor
+            .send(Subscribe(recorder.recipient()))
+            .await
+            .unwrap();
+        actix::spawn(ClockActor::run_loop(
+            clock_actor,
+            start_time,
+            params,
+            clock.clone(),
+        ));
+        wait_for(&timeslots, &[2]).await;
+
+        // Nothing happens until the next timeslot is reached
+        clock.advance(slot_length / 2);
+        clock.advance(slot_length / 2);
+        wait_for(&timeslots, &[2, 3]).await;
+
+        // Skipped timeslots are not notified
+        clock.advance(3 * slot_length);
+        wait_for(&timeslots, &[2, 3, 6]).await;
+    }
+}
@@ -1,2 +1,4 @@
+pub mod blockchain_actor;
 pub mod clock_actor;
+pub mod network_actor;
 pub mod print_actor;
\ No newline at end of file
@@ -0,0 +1,613 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    net::{Ipv4Addr, SocketAddrV4},
+};
+
+use actix::{
+    Actor, ActorFutureExt, Addr, AsyncContext, Context, Handler, Message, MessageResult,
+    ResponseFuture, StreamHandler, WrapFuture,
+};
+use anyhow::Result;
+use iroh::{
+    Endpoint, NodeAddr, NodeId, RelayMode, discovery::static_provider::StaticProvider,
+    protocol::Router,
+};
+use iroh_gossip::{
+    ALPN as GOSSIP_ALPN,
+    api::{ApiError, Event, GossipSender},
+    net::Gossip,
+    proto::TopicId,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    actors::blockchain_actor::{AddBlockFrom, AddTransactionFrom, BlockchainActor},
+    block::Block,
+    keys::SecretKey,
+    orphan_pool::MAX_ORPHANS,
+    sync::{RemoteSource, SYNC_ALPN, SyncProtocol, fetch_ancestors, headers_first_sync},
+    transaction::Transaction,
+    util::{BlockPtr, FromBytes, SerToBytes, Sha256Hash, hash},
+};
+
+/// What is sent over the gossip topic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum NetworkMessage {
+    Block(Block),
+    Transaction(Transaction),
+}
+
+pub struct NetworkConfig {
+    pub secret_key: SecretKey,
+    pub bind_addr: SocketAddrV4,
+    pub bootstrap: Vec<NodeAddr>,
+    pub relay_mode: RelayMode,
+}
+
+impl NetworkConfig {
+    pub fn new(secret_key: SecretKey) -> Self {
+        Self {
+            secret_key,
+            bind_addr: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+            bootstrap: Vec::new(),
+            relay_mode: RelayMode::Default,
+        }
+    }
+
+    /// Loopback only and no relays, used for running several nodes in one process
+    pub fn local(secret_key: SecretKey) -> Self {
+        Self {
+            bind_addr: SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0),
+            relay_mode: RelayMode::Disabled,
+            ..Self::new(secret_key)
+        }
+    }
+}
+
+/// Gossips blocks and transactions with the other nodes on the topic of our genesis block.
+/// Received blocks and transactions are sent to the blockchain actor, the gossip protocol takes care of relaying them.
+pub struct NetworkActor {
+    endpoint: Endpoint,
+    sender: GossipSender,
+    static_provider: StaticProvider,
+    blockchain: Addr<BlockchainActor>,
+    neighbors: HashSet<NodeId>,
+    // Missing parents of orphans, fetched one at a time so a burst of orphans does not start a fetch for each
+    pending_parents: VecDeque<RequestParent>,
+    fetching_parent: bool,
+    _router: Router,
+}
+
+/// Broadcasts a block that has already been added to the local blockchain
+#[derive(Message)]
+#[rtype(result = "Result<()>")]
+pub struct PublishBlock(pub Block);
+
+/// Broadcasts a transaction that has already been accepted into the local transaction buffer
+#[derive(Message)]
+#[rtype(result = "Result<()>")]
+pub struct PublishTransaction(pub Transaction);
+
+#[derive(Message)]
+#[rtype(result = "Result<()>")]
+pub struct JoinPeers(pub Vec<NodeAddr>);
+
+#[derive(Message)]
+#[rtype(result = "NodeAddr")]
+pub struct GetNodeAddr;
+
+/// Our direct neighbors on the topic, broadcasts are lost until there is at least one
+#[derive(Message)]
+#[rtype(result = "Vec<NodeId>")]
+pub struct GetNeighbors;
+
+/// Downloads the blocks we are missing from our neighbors, returns how many were added
+#[derive(Message)]
+#[rtype(result = "Result<usize>")]
+pub struct SyncFromNeighbors;
+
+/// Fetches the missing parent of an orphan and its missing ancestors, from the peer that sent the orphan if it
+/// is known and from our neighbors otherwise
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RequestParent {
+    pub parent: BlockPtr,
+    pub peer: Option<NodeId>,
+}
+
+pub fn topic_id(genesis_hash: &Sha256Hash) -> TopicId {
+    TopicId::from_bytes(hash(&("Lasagna", genesis_hash).into_bytes()))
+}
+
+impl NetworkActor {
+    pub async fn spawn(
+        config: NetworkConfig,
+        genesis_hash: Sha256Hash,
+        blockchain: Addr<BlockchainActor>,
+    ) -> Result<Addr<Self>> {
+        let static_provider = StaticProvider::new();
+        for node_addr in config.bootstrap.iter() {
+            static_provider.add_node_info(node_addr.clone());
+        }
+
+        let endpoint = Endpoint::builder()
+            .secret_key((&config.secret_key).into())
+            .relay_mode(config.relay_mode)
+            .bind_addr_v4(config.bind_addr)
+            .discovery(static_provider.clone())
+            .bind()
+            .await?;
+
+        let gossip = Gossip::builder().spawn(endpoint.clone());
+        let router = Router::builder(endpoint.clone())
+            .accept(GOSSIP_ALPN, gossip.clone())
+            .accept(SYNC_ALPN, SyncProtocol::new(blockchain.clone()))
+            .spawn();
+
+        let bootstrap = config
+            .bootstrap
+            .iter()
+            .map(|node_addr| node_addr.node_id)
+            .collect();
+        let (sender, receiver) = gossip
+            .subscribe(topic_id(&genesis_hash), bootstrap)
+            .await?
+            .split();
+
+        Ok(Self::create(|ctx| {
+            ctx.add_stream(receiver);
+            Self {
+                endpoint,
+                sender,
+                static_provider,
+                blockchain,
+                neighbors: Default::default(),
+                pending_parents: Default::default(),
+                fetching_parent: false,
+                _router: router,
+            }
+        }))
+    }
+
+    fn broadcast(&self, message: NetworkMessage) -> ResponseFuture<Result<()>> {
+        let sender = self.sender.clone();
+        Box::pin(async move {
+            sender.broadcast(message.into_bytes().into()).await?;
+            Ok(())
+        })
+    }
+
+    // Fetches the next pending parent once the previous one is done, from the peer that sent the orphan or
+    // from our neighbors when it is not known
+    fn fetch_next_parent(&mut self, ctx: &mut Context<Self>) {
+        if self.fetching_parent {
+            return;
+        }
+        let Some(request) = self.pending_parents.pop_front() else {
+            return;
+        };
+        self.fetching_parent = true;
+
+        let endpoint = self.endpoint.clone();
+        let peers = match request.peer {
+            Some(peer) => vec![peer],
+            None => self.neighbors.iter().copied().collect(),
+        };
+        let blockchain = self.blockchain.clone();
+        let fetch = async move {
+            let mut sources = Vec::new();
+            for node_id in peers {
+                if let Ok(source) = RemoteSource::connect(&endpoint, node_id).await {
+                    sources.push(source);
+                }
+            }
+            fetch_ancestors(&blockchain, &sources, request.parent, request.peer).await
+        };
+        ctx.spawn(fetch.into_actor(self).map(|_, actor, ctx| {
+            actor.fetching_parent = false;
+            actor.fetch_next_parent(ctx);
+        }));
+    }
+}
+
+impl Actor for NetworkActor {
+    type Context = Context<Self>;
+}
+
+impl StreamHandler<Result<Event, ApiError>> for NetworkActor {
+    fn handle(&mut self, event: Result<Event, ApiError>, _: &mut Self::Context) {
+        let Ok(event) = event else {
+            return;
+        };
+
+        match event {
+            Event::NeighborUp(node_id) => {
+                self.neighbors.insert(node_id);
+            }
+            Event::NeighborDown(node_id) => {
+                self.neighbors.remove(&node_id);
+            }
+            Event::Received(message) => {
+                let peer = message.delivered_from;
+                // Invalid messages are dropped, a peer might be on a different version or simply malicious
+                let Ok(message) = NetworkMessage::from_bytes(&message.content) else {
+                    return;
+                };
+
+                match message {
+                    NetworkMessage::Block(block) => {
+                        self.blockchain.do_send(AddBlockFrom(block, peer))
+                    }
+                    NetworkMessage::Transaction(transaction) => self
+                        .blockchain
+                        .do_send(AddTransactionFrom(transaction, peer)),
+                }
+            }
+            Event::Lagged => {}
+        }
+    }
+}
+
+impl Handler<PublishBlock> for NetworkActor {
+    type Result = ResponseFuture<Result<()>>;
+
+    fn handle(&mut self, msg: PublishBlock, _: &mut Self::Context) -> Self::Result {
+        self.broadcast(NetworkMessage::Block(msg.0))
+    }
+}
+
+impl Handler<PublishTransaction> for NetworkActor {
+    type Result = ResponseFuture<Result<()>>;
+
+    fn handle(&mut self, msg: PublishTransaction, _: &mut Self::Context) -> Self::Result {
+        self.broadcast(NetworkMessage::Transaction(msg.0))
+    }
+}
+
+impl Handler<JoinPeers> for NetworkActor {
+    type Result = ResponseFuture<Result<()>>;
+
+    fn handle(&mut self, msg: JoinPeers, _: &mut Self::Context) -> Self::Result {
+        for node_addr in msg.0.iter() {
+            self.static_provider.add_node_info(node_addr.clone());
+        }
+        let peers = msg.0.iter().map(|node_addr| node_addr.node_id).collect();
+
+        let sender = self.sender.clone();
+        Box::pin(async move {
+            sender.join_peers(peers).await?;
+            Ok(())
+        })
+    }
+}
+
+impl Handler<GetNodeAddr> for NetworkActor {
+    type Result = MessageResult<GetNodeAddr>;
+
+    fn handle(&mut self, _: GetNodeAddr, _: &mut Self::Context) -> Self::Result {
+        let node_addr = self.endpoint.node_addr();
+        // Include the sockets we are bound to, when bound to loopback they are the only way to reach us
+        let direct_addresses = node_addr
+            .direct_addresses()
+            .cloned()
+            .chain(self.endpoint.bound_sockets())
+            .filter(|addr| !addr.ip().is_unspecified())
+            .collect::<Vec<_>>();
+        MessageResult(node_addr.with_direct_addresses(direct_addresses))
+    }
+}
+
+impl Handler<GetNeighbors> for NetworkActor {
+    type Result = MessageResult<GetNeighbors>;
+
+    fn handle(&mut self, _: GetNeighbors, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.neighbors.iter().copied().collect())
+    }
+}
+
+impl Handler<SyncFromNeighbors> for NetworkActor {
+    type Result = ResponseFuture<Result<usize>>;
+
+    fn handle(&mut self, _: SyncFromNeighbors, _: &mut Self::Context) -> Self::Result {
+        let endpoint = self.endpoint.clone();
+        let neighbors = self.neighbors.clone();
+        let blockchain = self.blockchain.clone();
+        Box::pin(async move {
+            let mut sources = Vec::new();
+            for node_id in neighbors {
+                if let Ok(source) = RemoteSource::connect(&endpoint, node_id).await {
+                    sources.push(source);
+                }
+            }
+            headers_first_sync(&blockchain, &sources).await
+        })
+    }
+}
+
+impl Handler<RequestParent> for NetworkActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: RequestParent, ctx: &mut Self::Context) -> Self::Result {
+        // Requests for the same parent are merged, and there are not more of them than orphans waiting
+        let is_pending = self
+            .pending_parents
+            .iter()
+            .any(|pending| pending.parent == msg.parent);
+        if !is_pending && self.pending_parents.len() < MAX_ORPHANS {
+            self.pending_parents.push_back(msg);
+        }
+        self.fetch_next_parent(ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        Las,
+        actors::{
+            blockchain_actor::{
+                AddTransaction, GetBalance, GetBestHead, HasBlock, Subscribe, SubscribeOrphans,
+                SubscribeTransactions,
+            },
+            clock_actor::NewTimeslot,
+        },
+        blockchain::Blockchain,
+        genesis::GenesisConfig,
+        mempool::MIN_RELAY_FEE,
+        params::ChainParams,
+    };
+
+    async fn wait_until<F: Future<Output = bool>>(mut condition: impl FnMut() -> F) {
+        for _ in 0..200 {
+            if condition().await {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("condition was not met in time");
+    }
+
+    #[actix::test]
+    async fn test_gossip_between_local_nodes() {
+        let sk = SecretKey::generate();
+        let receiver = SecretKey::generate().get_public_key();
+        let genesis =
+            GenesisConfig::with_root_accounts(ChainParams::devnet(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+
+        let mut blockchains = Vec::new();
+        let mut nodes = Vec::new();
+        for _ in 0..3 {
+            let blockchain = Blockchain::start(genesis.clone(), genesis_block.clone());
+            let blockchain = BlockchainActor::new(blockchain, sk.clone()).start();
+            let mut config = NetworkConfig::local(SecretKey::generate());
+            if let Some(first) = nodes.first() {
+                let first: &Addr<NetworkActor> = first;
+                config.bootstrap = vec![first.send(GetNodeAddr).await.unwrap()];
+            }
+            let node = NetworkActor::spawn(config, genesis_block.header.hash, blockchain.clone())
+                .await
+                .unwrap();
+            blockchain.do_send(Subscribe(node.clone().recipient()));
+            blockchain.do_send(SubscribeTransactions(node.clone().recipient()));
+            nodes.push(node);
+            blockchains.push(blockchain);
+        }
+
+        for node in nodes.iter() {
+            wait_until(async || !node.send(GetNeighbors).await.unwrap().is_empty()).await;
+        }
+
+        // Only the second node produces blocks, the others must learn about them through gossip
+        let mut timeslot = 0;
+        while blockchains[1].send(GetBestHead).await.unwrap().depth == 0 {
+            timeslot += 1;
+            blockchains[1].send(NewTimeslot(timeslot)).await.unwrap();
+        }
+
+        wait_until(async || {
+            for blockchain in blockchains.iter() {
+                if blockchain.send(GetBestHead).await.unwrap().depth != 1 {
+                    return false;
+                }
+            }
+            true
+        })
+        .await;
+
+        // An accepted transaction is published without being asked to
+        let transaction = Transaction::new(&sk, receiver.clone(), Las(1), MIN_RELAY_FEE, 0);
+        blockchains[2]
+            .send(AddTransaction(transaction))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // The transaction can only end up in the ledger if the first node received it
+        while blockchains[0]
+            .send(GetBalance(receiver.clone()))
+            .await
+            .unwrap()
+            == 0
+        {
+            timeslot += 1;
+            blockchains[0].send(NewTimeslot(timeslot)).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        wait_until(async || {
+            for blockchain in blockchains.iter() {
+                if blockchain.send(GetBalance(receiver.clone())).await.unwrap()
+                    != Las(1).into_minilas()
+                {
+                    return false;
+                }
+            }
+            true
+        })
+        .await;
+    }
+
+    #[actix::test]
+    async fn test_sync_from_neighbors() {
+        let params = ChainParams::devnet();
+        let sk = SecretKey::generate();
+        let genesis = GenesisConfig::with_root_accounts(params.clone(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+        let empty = Blockchain::start(genesis, genesis_block.clone());
+
+        // Blocks made before the nodes met are never gossiped, they can only be synced.
+        // There are few enough that the first node has not pruned any of them
+        let len = 2 * params.seed_age as usize;
+        let mut ahead = empty.clone();
+        for _ in 0..len {
+            let block = ahead.make_next_block(&sk);
+            ahead.add_block(block).unwrap();
+        }
+
+        let first = BlockchainActor::new(ahead.clone(), sk.clone()).start();
+        let first_node = NetworkActor::spawn(
+            NetworkConfig::local(SecretKey::generate()),
+            genesis_block.header.hash,
+            first,
+        )
+        .await
+        .unwrap();
+
+        let second = BlockchainActor::new(empty, sk.clone()).start();
+        let mut config = NetworkConfig::local(SecretKey::generate());
+        config.bootstrap = vec![first_node.send(GetNodeAddr).await.unwrap()];
+        let second_node = NetworkActor::spawn(config, genesis_block.header.hash, second.clone())
+            .await
+            .unwrap();
+
+        wait_until(async || !second_node.send(GetNeighbors).await.unwrap().is_empty()).await;
+        let added = second_node.send(SyncFromNeighbors).await.unwrap().unwrap();
+        assert_eq!(added, len);
+        assert_eq!(
+            second.send(GetBestHead).await.unwrap(),
+            ahead.best_path_head().clone()
+        );
+    }
+
+    #[actix::test]
+    async fn test_parent_of_gossiped_orphan_is_fetched() {
+        let sk = SecretKey::generate();
+        let genesis =
+            GenesisConfig::with_root_accounts(ChainParams::devnet(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+        let empty = Blockchain::start(genesis, genesis_block.clone());
+
+        // The first node is ahead with blocks that were never gossiped
+        let mut ahead = empty.clone();
+        for _ in 0..10 {
+            let block = ahead.make_next_block(&sk);
+            ahead.add_block(block).unwrap();
+        }
+
+        let first = BlockchainActor::new(ahead, sk.clone()).start();
+        let first_node = NetworkActor::spawn(
+            NetworkConfig::local(SecretKey::generate()),
+            genesis_block.header.hash,
+            first.clone(),
+        )
+        .await
+        .unwrap();
+        first.do_send(Subscribe(first_node.clone().recipient()));
+
+        let second = BlockchainActor::new(empty, sk.clone()).start();
+        let mut config = NetworkConfig::local(SecretKey::generate());
+        config.bootstrap = vec![first_node.send(GetNodeAddr).await.unwrap()];
+        let second_node = NetworkActor::spawn(config, genesis_block.header.hash, second.clone())
+            .await
+            .unwrap();
+        second.do_send(SubscribeOrphans(second_node.clone().recipient()));
+        wait_until(async || !second_node.send(GetNeighbors).await.unwrap().is_empty()).await;
+        wait_until(async || !first_node.send(GetNeighbors).await.unwrap().is_empty()).await;
+
+        // The next block of the first node is an orphan to the second node, which then fetches its ancestors
+        let mut timeslot = 0;
+        while first.send(GetBestHead).await.unwrap().depth == 10 {
+            timeslot += 1;
+            first.send(NewTimeslot(timeslot)).await.unwrap();
+        }
+        let head = first.send(GetBestHead).await.unwrap();
+        wait_until(async || second.send(GetBestHead).await.unwrap() == head).await;
+    }
+
+    #[actix::test]
+    async fn test_parents_on_several_forks_are_fetched() {
+        let sk = SecretKey::generate();
+        let genesis =
+            GenesisConfig::with_root_accounts(ChainParams::devnet(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+        let mut base = Blockchain::start(genesis, genesis_block.clone());
+        for _ in 0..3 {
+            let block = base.make_next_block(&sk);
+            base.add_block(block).unwrap();
+        }
+
+        // The first node has two forks of the base, the second node only has the base
+        let mut first_chain = base.clone();
+        let mut heads = Vec::new();
+        let mut timeslot = base
+            .get_block(base.best_path_head())
+            .unwrap()
+            .header
+            .timeslot;
+        for _ in 0..2 {
+            let mut fork = base.clone();
+            let block = (timeslot + 1..)
+                .find_map(|timeslot| fork.make_block_at(&sk, timeslot))
+                .unwrap();
+            timeslot = block.header.timeslot;
+            fork.add_block(block.clone()).unwrap();
+            let next = fork.make_next_block(&sk);
+            fork.add_block(next.clone()).unwrap();
+            first_chain.add_block(block).unwrap();
+            first_chain.add_block(next).unwrap();
+            heads.push(fork.best_path_head().clone());
+        }
+
+        let first = BlockchainActor::new(first_chain, sk.clone()).start();
+        let first_node = NetworkActor::spawn(
+            NetworkConfig::local(SecretKey::generate()),
+            genesis_block.header.hash,
+            first,
+        )
+        .await
+        .unwrap();
+
+        let second = BlockchainActor::new(base, sk.clone()).start();
+        let first_addr = first_node.send(GetNodeAddr).await.unwrap();
+        let mut config = NetworkConfig::local(SecretKey::generate());
+        config.bootstrap = vec![first_addr.clone()];
+        let second_node = NetworkActor::spawn(config, genesis_block.header.hash, second.clone())
+            .await
+            .unwrap();
+        wait_until(async || !second_node.send(GetNeighbors).await.unwrap().is_empty()).await;
+
+        // Both requests arrive while the first one is being fetched
+        for head in heads.iter() {
+            second_node
+                .send(RequestParent {
+                    parent: head.clone(),
+                    peer: Some(first_addr.node_id),
+                })
+                .await
+                .unwrap();
+        }
+        wait_until(async || {
+            for head in heads.iter() {
+                if !second.send(HasBlock(head.clone())).await.unwrap() {
+                    return false;
+                }
+            }
+            true
+        })
+        .await;
+    }
+}
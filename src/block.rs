@@ -1,19 +1,12 @@
-use std::collections::HashSet;
-
 use serde::{Deserialize, Serialize};
 
-use crate::{draw::{Draw, Seed}, keys::{PublicKey, SecretKey, Signature}, transaction::Transaction, util::{hash, BlockPtr, SerToBytes, Sha256Hash, Timeslot}};
+use crate::{draw::Seed, genesis::GenesisConfig, header::BlockHeader, keys::SecretKey, merkle::{merkle_root, MerkleProof}, transaction::Transaction, util::{BlockPtr, SerToBytes, Sha256Hash, Timeslot}};
 use anyhow::{anyhow, Result};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Eq)]
 pub struct Block {
-    pub timeslot: Timeslot,
-    pub prev_hash: Sha256Hash,
-    pub depth: i64,
+    pub header: BlockHeader,
     pub transactions: Vec<Transaction>,
-    pub draw: Draw,
-    pub signature: Signature,
-    pub hash: Sha256Hash,
 }
 
 impl Block {
@@ -22,110 +15,151 @@ impl Block {
         prev_hash: Sha256Hash,
         depth: i64,
         transactions: Vec<Transaction>,
+        state_root: Sha256Hash,
         sk: &SecretKey,
         seed: Seed,
     ) -> Self {
-        let draw = Draw::new(timeslot, seed, sk);
-        let data = (timeslot, prev_hash, depth, &draw, &transactions).into_bytes();
-        let hash = hash(&data);
-        let signature = Signature::sign(sk, &hash);
+        let tx_root = Self::calculate_tx_root(&transactions);
+        let header = BlockHeader::new(timeslot, prev_hash, depth, tx_root, state_root, sk, seed);
         Self {
-            timeslot,
-            prev_hash,
-            depth,
+            header,
             transactions,
-            draw,
-            signature,
-            hash,
         }
     } 
-    
+
+    /// Puts a header and the transactions fetched for it back together
+    pub fn from_parts(header: BlockHeader, transactions: Vec<Transaction>) -> Result<Self> {
+        if Self::calculate_tx_root(&transactions) != header.tx_root {
+            return Err(anyhow!("Transactions do not match the transaction root"));
+        }
+
+        Ok(Self {
+            header,
+            transactions,
+        })
+    }
+
+    // The leaves are hashed from the contents, so the root does not rely on the `hash` fields
+    fn tx_leaves(transactions: &[Transaction]) -> Vec<Sha256Hash> {
+        transactions.iter().map(Transaction::compute_hash).collect()
+    }
+
+    pub fn calculate_tx_root(transactions: &[Transaction]) -> Sha256Hash {
+        merkle_root(&Self::tx_leaves(transactions))
+    }
+
+    /// Proof that the transaction with the given hash is in this block, verifiable against `tx_root`
+    pub fn prove_transaction(&self, transaction_hash: &Sha256Hash) -> Option<MerkleProof> {
+        let leaves = Self::tx_leaves(&self.transactions);
+        let index = leaves.iter().position(|leaf| leaf == transaction_hash)?;
+        MerkleProof::generate(&leaves, index)
+    }
+
     pub fn verify_signature(&self) -> Result<()> {
-        let timeslot = self.timeslot;
-        let prev_hash = self.prev_hash;
-        let depth = self.depth;
-        let draw = &self.draw;
-        let transactions = &self.transactions;
-    
-        let data = (timeslot, prev_hash, depth, draw, transactions).into_bytes();
-        let hash = hash(&data);
-        if hash != self.hash {
-            return Err(anyhow!("Computed hash does not match provided hash"));
+        if Self::calculate_tx_root(&self.transactions) != self.header.tx_root {
+            return Err(anyhow!("Transactions do not match the transaction root"));
         }
 
-        self.signature.verify(&self.draw.signed_by, &hash)
+        self.header.verify_signature()
     }
 
-    pub fn verify_transactions(&self, prev_transactions: &HashSet<Sha256Hash>) -> Result<()> {
-        if !self.transactions.iter().all(|t| {
-            t.verify_signature().is_ok() && !prev_transactions.contains(&t.hash)
-        }) {
+    pub fn verify_transactions(&self) -> Result<()> {
+        if !self.transactions.iter().all(|t| t.verify_signature().is_ok()) {
             return Err(anyhow!("Unable to verify some signatures"))
         }
         
         Ok(())
     }
 
-    pub fn verify_all(&self, prev_transactions: &HashSet<Sha256Hash>) -> Result<()> {
-        self.verify_signature()?;
+    pub fn verify_all(&self) -> Result<()> {
         self.verify_signature()?;
-        self.verify_transactions(prev_transactions)?;
+        self.verify_transactions()?;
         Ok(())
     }
 
-    pub fn verify_geneis(&self, root_accounts: &Vec<PublicKey>) -> Result<()> {
-        let genesis_hash = Self::produce_genesis_hash(root_accounts);
+    pub fn verify_geneis(&self, genesis: &GenesisConfig) -> Result<()> {
+        let genesis_hash = genesis.hash();
         if !self.transactions.is_empty() {
             return Err(anyhow!("Transactions can't be in the genesis block"));
         }
 
-        if self.prev_hash != genesis_hash {
-            return Err(anyhow!("Seed hash does not match root accounts"));
+        if self.header.prev_hash != genesis_hash {
+            return Err(anyhow!("Seed hash does not match the genesis config"));
         }
         
         self.verify_signature()
     }
 
     pub fn is_genesis(&self) -> bool {
-        self.depth == 0
+        self.header.depth == 0
     }
 
-    pub fn produce_genesis_hash(root_accounts: &Vec<PublicKey>) -> Sha256Hash {
-        let data = root_accounts.iter().map(|accnt| accnt.into_bytes()).flatten().collect::<Vec<u8>>();
-        let seed_hash = hash(&data);
-        seed_hash
+    /// Size of the block when serialized, which is what is sent over the network and stored
+    pub fn size(&self) -> usize {
+        self.into_bytes().len()
     }
 
     pub fn ptr(&self) -> BlockPtr {
-        BlockPtr::new(self.hash, self.depth)
+        BlockPtr::new(self.header.hash, self.header.depth)
     }
 }
 
 impl PartialEq for Block {
     fn eq(&self, other: &Self) -> bool {
-        self.hash == other.hash
+        self.header.hash == other.header.hash
     }
 }
 
 impl PartialOrd for Block {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        use std::cmp::Ordering::*;
-        
-        if self.timeslot < other.timeslot {
-            return Some(Greater)
-        }
-        if self.timeslot > other.timeslot {
-            return Some(Less)
-        }
+        // The earlier timeslot is greater, then the block with more transactions, then the greater hash
+        Some(
+            other
+                .header
+                .timeslot
+                .cmp(&self.header.timeslot)
+                .then(self.transactions.len().cmp(&other.transactions.len()))
+                .then(self.header.hash.cmp(&other.header.hash)),
+        )
+    }
+}
 
-        if self.transactions.len() > other.transactions.len() {
-            return Some(Greater)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Las, mempool::MIN_RELAY_FEE, util::BlockPtr};
+
+    #[test]
+    fn test_transaction_inclusion() {
+        let sk = SecretKey::generate();
+        let to = SecretKey::generate().get_public_key();
+        let transactions = (0..5)
+            .map(|nonce| Transaction::new(&sk, to.clone(), Las(1), MIN_RELAY_FEE, nonce))
+            .collect::<Vec<_>>();
+        let seed = Seed { block_ptr: BlockPtr::new([0; 32], 0) };
+        let block = Block::new(1, [0; 32], 1, transactions.clone(), [0; 32], &sk, seed);
+        block.verify_signature().unwrap();
+
+        for t in transactions.iter() {
+            let proof = block.prove_transaction(&t.hash).unwrap();
+            proof.verify(&t.hash, &block.header.tx_root).unwrap();
         }
-        else if self.transactions.len() > other.transactions.len() {
-            return Some(Less);
-        }
-
-        // Third tiebreak, lexicographically hash
-        Some(self.hash.cmp(&other.hash))
+        let other = Transaction::new(&sk, to.clone(), Las(1), MIN_RELAY_FEE, 5);
+        assert!(block.prove_transaction(&other.hash).is_none());
+
+        // Swapping a transaction breaks the root, and changing the root breaks the hash
+        let mut tampered = block.clone();
+        tampered.transactions[0] = other;
+        assert!(tampered.verify_signature().is_err());
+        tampered.header.tx_root = Block::calculate_tx_root(&tampered.transactions);
+        assert!(tampered.verify_signature().is_err());
+
+        // Another body under the hash of the original transaction changes the root as well
+        let mut tampered = block.clone();
+        let original_hash = tampered.transactions[0].hash;
+        tampered.transactions[0] = Transaction::new(&sk, to.clone(), Las(2), MIN_RELAY_FEE, 0);
+        tampered.transactions[0].hash = original_hash;
+        assert!(tampered.verify_signature().is_err());
+        assert!(tampered.verify_transactions().is_err());
     }
 }
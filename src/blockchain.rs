@@ -1,37 +1,106 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+};
 
-use num_bigint::BigUint;
+use iroh::NodeId;
 use serde::{Deserialize, Serialize};
 
 use crate::{
     block::Block,
-    draw::{Draw, SEED_AGE, Seed},
+    header::BlockHeader,
+    clock::{Clock, SystemClock},
+    draw::{Draw, Seed},
+    fork_choice::{DeepestChain, ForkChoice},
+    genesis::GenesisConfig,
     keys::{PublicKey, SecretKey},
-    ledger::Ledger,
+    ledger::{BalanceProof, Ledger, UndoRecord},
+    ledger_cache::LedgerCache,
+    mempool::Mempool,
+    orphan_pool::OrphanPool,
+    params::ChainParams,
+    snapshot::ChainSnapshot,
     transaction::Transaction,
-    util::{BlockPtr, MiniLas, START_TIME, Sha256Hash, calculate_timeslot},
+    util::{BlockPtr, MiniLas, Sha256Hash, Timeslot},
 };
 use anyhow::{Result, anyhow, ensure};
 
-pub const BLOCK_REWARD: MiniLas = 3_000000;
-pub const ROOT_AMOUNT: MiniLas = 100_000000;
-pub const TRANSACTION_FEE: MiniLas = 0_010000;
-
-#[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
-pub struct Blockchain {
+// A block exceeding either limit is invalid, so every node only has to process bounded blocks
+pub const MAX_BLOCK_SIZE: usize = 128 * 1024; // Serialized size in bytes
+pub const MAX_BLOCK_TRANSACTIONS: usize = 1024;
+// A copy of the static ledger is kept every this many depths, so any static ledger near the best head
+// is at most half of this many blocks away from a known one
+pub const STATIC_CHECKPOINT_INTERVAL: i64 = 10;
+// Copies further below the current static ledger than this many seed ages are dropped
+const STATIC_CHECKPOINT_SEED_AGES: i64 = 2;
+// Upper bound of how much the length prefix of the transactions grows when they are added to an empty block
+const LENGTH_PREFIX_SIZE: usize = 9;
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Blockchain<F = DeepestChain, C = SystemClock> {
     pub blocks: Vec<HashMap<Sha256Hash, Block>>,
     pub best_path: Vec<BlockPtr>,
     pub dynamic_ledger: Ledger,
     pub static_ledger: Ledger,
-    pub root_accounts: Vec<PublicKey>,
-    pub orphans: HashMap<Sha256Hash, Vec<Block>>,
-    pub transaction_buffer: HashSet<Transaction>,
-    start_time: u128,
+    pub genesis: GenesisConfig,
+    pub orphans: OrphanPool,
+    // How to undo each block on the best path except genesis, keyed by block hash
+    pub undo_records: HashMap<Sha256Hash, UndoRecord>,
+    #[serde(skip)]
+    static_checkpoints: LedgerCache, // Keyed by the static block pointer of the ledger
+    pub transaction_buffer: Mempool,
+    #[serde(skip)]
+    fork_choice: F,
+    #[serde(skip)]
+    clock: C,
+    pub finalized: Finalized,
+}
+
+// The checkpoints only make finding static ledgers faster, so they are not part of what makes two blockchains equal
+impl<F: PartialEq, C: PartialEq> PartialEq for Blockchain<F, C> {
+    fn eq(&self, other: &Self) -> bool {
+        let Self {
+            blocks,
+            best_path,
+            dynamic_ledger,
+            static_ledger,
+            genesis,
+            orphans,
+            undo_records,
+            static_checkpoints: _,
+            transaction_buffer,
+            fork_choice,
+            clock,
+            finalized,
+        } = self;
+        *blocks == other.blocks
+            && *best_path == other.best_path
+            && *dynamic_ledger == other.dynamic_ledger
+            && *static_ledger == other.static_ledger
+            && *genesis == other.genesis
+            && *orphans == other.orphans
+            && *undo_records == other.undo_records
+            && *transaction_buffer == other.transaction_buffer
+            && *fork_choice == other.fork_choice
+            && *clock == other.clock
+            && *finalized == other.finalized
+    }
+}
+
+impl<F: Eq, C: Eq> Eq for Blockchain<F, C> {}
+
+/// The state at the final block, the deepest block on the best path that can no longer be rolled back.
+/// The chain can be replayed from it, so the blocks more than a seed age below it are not kept
+#[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct Finalized {
+    pub block_ptr: BlockPtr,
+    pub dynamic_ledger: Ledger, // The ledger after the final block
+    pub static_ledger: Ledger,  // The static ledger of the block after the final block
 }
 
 impl Blockchain {
-    pub fn produce_genesis_block(root_accounts: Vec<PublicKey>, any_sk: &SecretKey) -> Block {
-        let genesis_hash = Block::produce_genesis_hash(&root_accounts);
+    pub fn produce_genesis_block(genesis: &GenesisConfig, any_sk: &SecretKey) -> Block {
+        let genesis_hash = genesis.hash();
         let seed = Seed {
             block_ptr: BlockPtr {
                 hash: genesis_hash,
@@ -39,23 +108,51 @@ impl Blockchain {
             },
         };
 
-        Block::new(0, genesis_hash, 0, Vec::new(), any_sk, seed)
+        let state_root = Self::genesis_ledger(genesis).state_root();
+        Block::new(0, genesis_hash, 0, Vec::new(), state_root, any_sk, seed)
     }
 
-    pub fn start(root_accounts: Vec<PublicKey>, genesis_block: Block) -> Self {
+    pub(crate) fn genesis_ledger(genesis: &GenesisConfig) -> Ledger {
+        let mut ledger = Ledger::new(genesis.root_accounts(), genesis.params.minimum_stake_amount);
+        genesis.allocations.iter().for_each(|allocation| {
+            ledger
+                .reward_winner(&allocation.account, allocation.amount)
+                .expect("the total allocation of a valid genesis config does not overflow")
+        });
+        ledger
+    }
+
+    pub fn start(genesis: GenesisConfig, genesis_block: Block) -> Self {
+        Self::start_with_fork_choice(genesis, genesis_block, DeepestChain)
+    }
+
+    /// Starts from a snapshot made by a node on the network of `genesis_block`
+    pub fn start_from_snapshot(snapshot: ChainSnapshot, genesis_block: &Block) -> Result<Self> {
+        Self::start_from_snapshot_with_fork_choice(snapshot, genesis_block, DeepestChain)
+    }
+}
+
+impl<F: ForkChoice> Blockchain<F> {
+    pub fn start_with_fork_choice(
+        genesis: GenesisConfig,
+        genesis_block: Block,
+        fork_choice: F,
+    ) -> Self {
         let block = genesis_block;
-        let hash = block.hash;
+        let hash = block.header.hash;
         let mut map = HashMap::new();
         map.insert(hash, block);
 
-        let mut ledger = Ledger::new(root_accounts.clone());
-        root_accounts
-            .iter()
-            .for_each(|accnt| ledger.reward_winner(accnt, ROOT_AMOUNT));
+        let ledger = Blockchain::genesis_ledger(&genesis);
 
         let blocks = vec![map];
         let best_path = vec![BlockPtr { hash, depth: 0 }];
 
+        let finalized = Finalized {
+            block_ptr: best_path[0].clone(),
+            dynamic_ledger: ledger.clone(),
+            static_ledger: ledger.clone(),
+        };
         let static_ledger = ledger.clone();
         let dynamic_ledger = ledger;
 
@@ -64,220 +161,593 @@ impl Blockchain {
             best_path,
             static_ledger,
             dynamic_ledger,
-            root_accounts,
+            genesis,
             orphans: Default::default(),
+            undo_records: Default::default(),
+            static_checkpoints: Default::default(),
             transaction_buffer: Default::default(),
-            start_time: START_TIME,
+            fork_choice,
+            clock: SystemClock,
+            finalized,
         }
     }
 
+    /// The ledgers at the final block and the blocks from a seed age below it are taken from the snapshot.
+    /// Those blocks are replayed on the finalized static ledger, which checks that their state roots match
+    /// the ledgers and gives the undo records needed to roll back to the final block.
+    /// Their draws and seeds are checked, but not that they won the lottery, as their static ledgers
+    /// are older than the snapshot. See `ChainSnapshot::check_hash` for how a snapshot can be trusted
+    pub fn start_from_snapshot_with_fork_choice(
+        snapshot: ChainSnapshot,
+        genesis_block: &Block,
+        fork_choice: F,
+    ) -> Result<Self> {
+        let ChainSnapshot {
+            genesis,
+            genesis_block: snapshot_genesis,
+            best_path,
+            blocks,
+            finalized,
+        } = snapshot;
+        ensure!(
+            snapshot_genesis == *genesis_block,
+            "Snapshot is of a different genesis block"
+        );
+        ensure!(
+            genesis_block.header.prev_hash == genesis.hash(),
+            "Seed hash does not match the genesis config"
+        );
+        ensure!(
+            best_path.first() == Some(&genesis_block.ptr())
+                && best_path.last() == Some(&finalized.block_ptr)
+                && best_path
+                    .iter()
+                    .enumerate()
+                    .all(|(i, ptr)| ptr.depth == i as i64),
+            "Best path of the snapshot does not lead from genesis to its final block"
+        );
+
+        let mut blockchain =
+            Self::start_with_fork_choice(genesis, genesis_block.clone(), fork_choice);
+        let final_depth = finalized.block_ptr.depth;
+        let static_depth = (final_depth - blockchain.seed_age()).max(0);
+        let first_depth = static_depth.max(1);
+        ensure!(
+            blocks.len() as i64 == final_depth - first_depth + 1,
+            "Snapshot does not have the blocks from a seed age below its final block"
+        );
+
+        // The genesis block is not rewarded, so the static ledger after it is the genesis ledger
+        let static_block = match static_depth {
+            0 => genesis_block,
+            _ => &blocks[0],
+        };
+        let mut ledger = finalized.static_ledger.clone();
+        ensure!(
+            ledger.state_root() == static_block.header.state_root,
+            "Finalized static ledger does not match its block"
+        );
+        blockchain
+            .blocks
+            .resize(final_depth as usize + 1, HashMap::new());
+        for (depth, block) in (first_depth..).zip(blocks) {
+            ensure!(
+                best_path[depth as usize] == block.ptr()
+                    && best_path[depth as usize - 1].hash == block.header.prev_hash,
+                "Block of the snapshot is not on its best path"
+            );
+            block.verify_signature()?;
+            block.header.verify_draw()?;
+            // The seed blocks are below the snapshot, so they can only be checked against its best path
+            let expected_seed = match depth - blockchain.seed_age() {
+                ..0 => genesis_block.header.draw.seed.clone(),
+                seed_depth => Seed {
+                    block_ptr: best_path[seed_depth as usize].clone(),
+                },
+            };
+            ensure!(
+                block.header.draw.seed == expected_seed,
+                "Seed of a block of the snapshot is not on its best path"
+            );
+            if depth > static_depth {
+                let record = ledger.apply_block(
+                    &block.transactions,
+                    &block.header.draw.signed_by,
+                    blockchain.calculate_reward(&block),
+                )?;
+                ensure!(
+                    ledger.state_root() == block.header.state_root,
+                    "State root does not match the resulting ledger"
+                );
+                blockchain.undo_records.insert(block.header.hash, record);
+            }
+            blockchain.blocks[depth as usize].insert(block.header.hash, block);
+        }
+        ensure!(
+            ledger == finalized.dynamic_ledger,
+            "Finalized ledger does not match the final block"
+        );
+
+        blockchain.best_path = best_path;
+        blockchain.dynamic_ledger = finalized.dynamic_ledger.clone();
+        blockchain.static_ledger = finalized.static_ledger.clone();
+        blockchain.finalized = finalized;
+        Ok(blockchain)
+    }
+}
+
+impl<F: ForkChoice, C: Clock> Blockchain<F, C> {
+    /// The same blockchain, reading the time from `clock` to know which timeslots have been reached
+    pub fn with_clock<D: Clock>(self, clock: D) -> Blockchain<F, D> {
+        let Self {
+            blocks,
+            best_path,
+            dynamic_ledger,
+            static_ledger,
+            genesis,
+            orphans,
+            undo_records,
+            static_checkpoints,
+            transaction_buffer,
+            fork_choice,
+            clock: _,
+            finalized,
+        } = self;
+        Blockchain {
+            blocks,
+            best_path,
+            dynamic_ledger,
+            static_ledger,
+            genesis,
+            orphans,
+            undo_records,
+            static_checkpoints,
+            transaction_buffer,
+            fork_choice,
+            clock,
+            finalized,
+        }
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
     pub fn best_path_head(&self) -> &BlockPtr {
         self.best_path.last().expect("no blocks in best path")
     }
 
-    fn check_seed(&self, block: &Block) -> Result<()> {
-        let block_seed = &block.draw.seed;
-        let depth = block.depth;
-        if depth < SEED_AGE {
+    // The seed of a block at `depth` with the parent at `parent_ptr`, found through its own ancestors
+    fn expected_seed(
+        &self,
+        parent_ptr: &BlockPtr,
+        ancestors: &[BlockHeader],
+        depth: i64,
+    ) -> Result<Seed> {
+        if depth < self.seed_age() {
             // Block is close to genesis and must have the same seed as the genesis block
-            let genesis_block_ptr = &self.best_path[0];
             let genesis_block = self
-                .get_block(&genesis_block_ptr)
-                .ok_or_else(|| anyhow!("Could not find genesis block"))?;
-            let genesis_seed = &genesis_block.draw.seed;
-
-            if block_seed != genesis_seed {
-                return Err(anyhow!("seed mismatch"));
-            }
+                .get_block(&self.best_path[0])
+                .ok_or(anyhow!("Could not find genesis block"))?;
+            Ok(genesis_block.header.draw.seed.clone())
         } else {
-            // Block seed should be the hash of the block from 50 rounds ago
-            let seed_depth = (depth - SEED_AGE) as usize;
-            let seed_block_ptr = &self.best_path[seed_depth];
-            let _seed_block = &self
-                .get_block(seed_block_ptr)
-                .ok_or_else(|| anyhow!("Could not find seed block"))?;
-
-            let seed = Seed {
-                block_ptr: seed_block_ptr.clone(),
-            };
-
-            if block_seed != &seed {
-                return Err(anyhow!("seed mismatch"));
-            }
+            // Block seed should be the hash of its ancestor from a seed age ago
+            let block_ptr = self
+                .ancestor_ptr(parent_ptr.clone(), ancestors, depth - self.seed_age())
+                .ok_or(anyhow!("Could not find seed block"))?;
+            Ok(Seed { block_ptr })
         }
-
-        Ok(())
     }
 
     pub fn stake(&self, draw: Draw, wallet: &PublicKey) -> bool {
-        is_winner(&self.static_ledger, draw, wallet)
+        self.is_winner(&self.static_ledger, draw, wallet)
     }
 
     pub fn add_transaction(&mut self, transaction: Transaction) -> Result<()> {
-        transaction.verify_signature()?;
-        self.dynamic_ledger.is_transaction_valid(&transaction)?;
-        self.transaction_buffer.insert(transaction);
-        Ok(())
+        self.transaction_buffer
+            .insert(transaction, &self.dynamic_ledger)
     }
 
     pub fn can_block_be_added(&self, block: &Block) -> Result<()> {
+        self.validate_block(block).map(|_| ())
+    }
+
+    // Validates the block against its own ancestors, which do not have to be on the best path,
+    // and returns the ledger after the block and how to undo it.
+    // Only what does not need the parent is checked for an orphan, the rest is checked when it is added again
+    fn validate_block(&self, block: &Block) -> Result<Option<(Ledger, UndoRecord)>> {
+        // Checked first so an oversized block is rejected before any expensive work
+        ensure!(
+            block.transactions.len() <= MAX_BLOCK_TRANSACTIONS,
+            "Block has {} transactions, the maximum is {MAX_BLOCK_TRANSACTIONS}",
+            block.transactions.len()
+        );
+        ensure!(
+            block.size() <= MAX_BLOCK_SIZE,
+            "Block is {} bytes, the maximum is {MAX_BLOCK_SIZE}",
+            block.size()
+        );
+
         block.verify_signature()?;
+        block.header.verify_draw()?;
 
-        for t in block.transactions.iter() {
-            self.dynamic_ledger.is_transaction_valid(t)?
+        if block.header.timeslot > self.genesis.current_timeslot(&self.clock) {
+            return Err(anyhow!("Invalid timeslot"));
+        }
+
+        // A fork branching off below the final block can never become the best path
+        let final_depth = self.finalized.block_ptr.depth;
+        ensure!(
+            block.header.depth > final_depth,
+            "Block is not above the final block at depth {final_depth}"
+        );
+
+        let Some(parent) = self.get_parent(block) else {
+            ensure!(
+                block.header.depth > final_depth + 1,
+                "Parent of the block is not the final block at depth {final_depth}"
+            );
+            return Ok(None);
+        };
+
+        if block.header.timeslot <= parent.header.timeslot {
+            return Err(anyhow!("Invalid timeslot in relation to parents"));
+        }
+
+        if parent.header.hash == block.header.hash {
+            return Err(anyhow!("Duplicate hash"));
+        }
+
+        let parent_ptr = parent.ptr();
+        let depth = block.header.depth;
+        if block.header.draw.seed != self.expected_seed(&parent_ptr, &[], depth)? {
+            return Err(anyhow!("seed mismatch"));
+        }
+
+        let static_ptr = self
+            .ancestor_ptr(parent_ptr.clone(), &[], self.static_depth_of(depth))
+            .ok_or(anyhow!("Could not find static block"))?;
+        ensure!(
+            self.is_winner(
+                self.static_ledger_from(&static_ptr, depth)?.as_ref(),
+                block.header.draw.clone(),
+                &block.header.draw.signed_by
+            ),
+            "Draw is not a winner"
+        );
+
+        // Transactions are applied in order, a sender can have several transactions with consecutive nonces
+        let mut ledger = self.ledger_at(&parent_ptr)?.into_owned();
+        let record = ledger.apply_block(
+            &block.transactions,
+            &block.header.draw.signed_by,
+            self.calculate_reward(block),
+        )?;
+        ensure!(
+            ledger.state_root() == block.header.state_root,
+            "State root does not match the resulting ledger"
+        );
+
+        Ok(Some((ledger, record)))
+    }
+
+    // The dynamic ledger after the block at `ptr`, found by undoing the best path back to where the fork
+    // of the block leaves it and then applying the fork
+    fn ledger_at(&self, ptr: &BlockPtr) -> Result<Cow<'_, Ledger>> {
+        if ptr == self.best_path_head() {
+            return Ok(Cow::Borrowed(&self.dynamic_ledger));
+        }
+
+        let mut fork = Vec::new();
+        let mut fork_point = ptr.clone();
+        while self.best_path.get(fork_point.depth as usize) != Some(&fork_point) {
+            let block = self
+                .get_block(&fork_point)
+                .ok_or(anyhow!("Unknown block on fork"))?;
+            fork.push(block);
+            fork_point = BlockPtr::new(block.header.prev_hash, fork_point.depth - 1);
+        }
+
+        let mut ledger = self.dynamic_ledger.clone();
+        for ptr in self.best_path[fork_point.depth as usize + 1..].iter().rev() {
+            let record = self
+                .undo_records
+                .get(&ptr.hash)
+                .ok_or(anyhow!("No undo record of block on best path"))?;
+            ledger.undo(record);
         }
-        self.check_seed(&block)?;
+        for block in fork.iter().rev() {
+            ledger.apply_block(
+                &block.transactions,
+                &block.header.draw.signed_by,
+                self.calculate_reward(block),
+            )?;
+        }
+
+        Ok(Cow::Owned(ledger))
+    }
+
+    // The static ledger of a block at `depth` whose ancestor at the static depth is `static_ptr`
+    fn static_ledger_from(&self, static_ptr: &BlockPtr, depth: i64) -> Result<Cow<'_, Ledger>> {
+        let on_best_path = self.best_path.get(static_ptr.depth as usize) == Some(static_ptr);
+        if on_best_path && static_ptr.depth + 1 < self.best_path.len() as i64 {
+            // The cached static ledgers can be used
+            self.get_static_ledger_of(depth)
+        } else if static_ptr.depth == 0 {
+            // The genesis block is not rewarded in the dynamic ledger either
+            Ok(Cow::Owned(Blockchain::genesis_ledger(&self.genesis)))
+        } else {
+            self.ledger_at(static_ptr)
+        }
+    }
 
-        if block.timeslot > calculate_timeslot(START_TIME) {
+    /// Validates what can be validated without the transactions: the signature, the draw and the seed.
+    /// `ancestors` are the headers between a known block and `header`, which allows validating a chain of
+    /// headers before any of their blocks are known.
+    /// The lottery can only be checked when the block of the static ledger of the header is known,
+    /// otherwise it is left to `can_block_be_added`.
+    pub fn validate_header(&self, header: &BlockHeader, ancestors: &[BlockHeader]) -> Result<()> {
+        header.verify_signature()?;
+        header.verify_draw()?;
+
+        if header.timeslot > self.genesis.current_timeslot(&self.clock) {
             return Err(anyhow!("Invalid timeslot"));
         }
 
-        let parent = self.get_parent(&block);
+        let final_depth = self.finalized.block_ptr.depth;
+        ensure!(
+            header.depth > final_depth,
+            "Header is not above the final block at depth {final_depth}"
+        );
 
-        if let Some(parent) = parent {
-            if block.timeslot <= parent.timeslot {
-                return Err(anyhow!("Invalid timeslot in relation to parents"));
+        let parent_ptr = BlockPtr::new(header.prev_hash, header.depth - 1);
+        let parent_timeslot = match ancestors.last() {
+            Some(parent) if parent.ptr() == parent_ptr => parent.timeslot,
+            Some(_) => return Err(anyhow!("Header does not extend the previous header")),
+            None => {
+                self.get_block(&parent_ptr)
+                    .ok_or(anyhow!("Unknown parent"))?
+                    .header
+                    .timeslot
             }
+        };
+        if header.timeslot <= parent_timeslot {
+            return Err(anyhow!("Invalid timeslot in relation to parents"));
+        }
 
-            if parent.hash == block.hash {
-                return Err(anyhow!("Duplicate hash"));
-            }
+        if header.draw.seed != self.expected_seed(&parent_ptr, ancestors, header.depth)? {
+            return Err(anyhow!("seed mismatch"));
         }
 
-        ensure!(is_winner(
-            &self.get_static_ledger_of(block.depth)?,
-            block.draw.clone(),
-            &block.draw.signed_by
-        ));
+        let static_ptr = self
+            .ancestor_ptr(parent_ptr, ancestors, self.static_depth_of(header.depth))
+            .filter(|ptr| self.get_block(ptr).is_some());
+        if let Some(static_ptr) = static_ptr {
+            ensure!(
+                self.is_winner(
+                    self.static_ledger_from(&static_ptr, header.depth)?.as_ref(),
+                    header.draw.clone(),
+                    &header.draw.signed_by
+                ),
+                "Draw is not a winner"
+            );
+        }
 
         Ok(())
     }
 
-    pub fn get_static_ledger_of(&self, dynamic_depth: i64) -> Result<Ledger> {
-        let current_static_ledger = &self.static_ledger;
-        let current_static_ptr = self.get_static_block_ptr(self.best_path.len() as _);
+    // Walks back from `ptr` through `ancestors` and then the known blocks to the block at `depth`
+    fn ancestor_ptr(
+        &self,
+        mut ptr: BlockPtr,
+        ancestors: &[BlockHeader],
+        depth: i64,
+    ) -> Option<BlockPtr> {
+        let first_depth = ancestors.first().map_or(i64::MAX, |h| h.depth);
+        while ptr.depth > depth {
+            let ancestor = usize::try_from(ptr.depth - first_depth)
+                .ok()
+                .and_then(|i| ancestors.get(i))
+                .filter(|h| h.hash == ptr.hash);
+            let prev_hash = match ancestor {
+                Some(header) => header.prev_hash,
+                None => self.get_block(&ptr)?.header.prev_hash,
+            };
+            ptr = BlockPtr::new(prev_hash, ptr.depth - 1);
+        }
+
+        (ptr.depth == depth).then_some(ptr)
+    }
 
-        let target_static_ptr = self.get_static_block_ptr(dynamic_depth as _);
+    /// Validates a chain of headers that starts at a known block or right after it,
+    /// and returns the headers of the blocks we do not have yet
+    pub fn validate_header_chain(&self, headers: Vec<BlockHeader>) -> Result<Vec<BlockHeader>> {
+        let new_headers = headers
+            .into_iter()
+            .skip_while(|header| self.get_block(&header.ptr()).is_some())
+            .collect::<Vec<_>>();
 
-        if current_static_ptr == target_static_ptr {
-            return Ok(current_static_ledger.clone());
+        for (i, header) in new_headers.iter().enumerate() {
+            self.validate_header(header, &new_headers[..i])?;
         }
 
-        let mut current_static_ledger = current_static_ledger.clone();
-        if current_static_ptr.depth > target_static_ptr.depth {
-            let from = current_static_ptr.depth as usize;
-            let to = target_static_ptr.depth as usize;
-            let path = &self.best_path[to..from];
-            for ptr in path.iter().rev() {
-                let block = self.get_block(ptr).ok_or(anyhow!("invalid deref"))?;
-                let reward = self.calculate_reward(block);
+        Ok(new_headers)
+    }
 
-                current_static_ledger.rollback_reward(&block.draw.signed_by, reward);
-                for t in &block.transactions {
-                    current_static_ledger.rollback_transaction(&t, block.depth);
+    /// Headers of up to `count` blocks on the best path, starting at `from_depth`
+    pub fn get_headers(&self, from_depth: i64, count: usize) -> Vec<BlockHeader> {
+        let from_depth = from_depth.max(0) as usize;
+        self.best_path
+            .iter()
+            .skip(from_depth)
+            .take(count)
+            .filter_map(|ptr| self.get_block(ptr))
+            .map(|block| block.header.clone())
+            .collect()
+    }
+
+    /// The static ledger of a block at `dynamic_depth` on the best path. It is found from the closest of the
+    /// current static ledger and the checkpoints, and only copied when it isn't one of them
+    pub fn get_static_ledger_of(&self, dynamic_depth: i64) -> Result<Cow<'_, Ledger>> {
+        let target_static_ptr = self.get_static_block_ptr(dynamic_depth);
+        let current_static_ptr = self.get_static_block_ptr(self.best_path.len() as _);
+
+        let (start_ptr, start_ledger) = self
+            .static_checkpoints
+            .iter()
+            .filter(|(ptr, _)| self.best_path.get(ptr.depth as usize) == Some(*ptr))
+            .chain([(current_static_ptr, &self.static_ledger)])
+            .min_by_key(|(ptr, _)| (ptr.depth - target_static_ptr.depth).abs())
+            .expect("the current static ledger is a candidate");
+
+        if start_ptr == target_static_ptr {
+            return Ok(Cow::Borrowed(start_ledger));
+        }
+
+        let mut ledger = start_ledger.clone();
+        let from = start_ptr.depth as usize;
+        let to = target_static_ptr.depth as usize;
+        if from > to {
+            for ptr in self.best_path[to..from].iter().rev() {
+                // The genesis block is not rewarded in the dynamic ledger either
+                if ptr.depth == 0 {
+                    continue;
                 }
+                let record = self
+                    .undo_records
+                    .get(&ptr.hash)
+                    .ok_or(anyhow!("No undo record of block on best path"))?;
+                ledger.undo(record);
             }
-
-            return Ok(current_static_ledger);
         } else {
-            let from = current_static_ptr.depth as usize;
-            let to = target_static_ptr.depth as usize;
-            let path = &self.best_path[from..to];
-            for ptr in path.iter() {
+            for ptr in self.best_path[from..to].iter() {
                 let block = self.get_block(ptr).ok_or(anyhow!("invalid deref"))?;
-                let reward = self.calculate_reward(block);
-                current_static_ledger.reward_winner(&block.draw.signed_by, reward);
-                for t in &block.transactions {
-                    current_static_ledger.process_transaction(&t)?;
+                if block.is_genesis() {
+                    continue;
                 }
+                ledger.apply_block(
+                    &block.transactions,
+                    &block.header.draw.signed_by,
+                    self.calculate_reward(block),
+                )?;
             }
+        }
+
+        Ok(Cow::Owned(ledger))
+    }
+
+    // Moves the static ledger along with the best path before it is changed to a length of `best_path_len`
+    fn move_static_ledger(&mut self, best_path_len: i64) -> Result<()> {
+        self.static_ledger = self.get_static_ledger_of(best_path_len)?.into_owned();
 
-            return Ok(current_static_ledger);
+        let static_ptr = self.get_static_block_ptr(best_path_len).clone();
+        if best_path_len > self.best_path.len() as i64
+            && static_ptr.depth % STATIC_CHECKPOINT_INTERVAL == 0
+        {
+            self.static_checkpoints
+                .insert(static_ptr.clone(), self.static_ledger.clone());
         }
+        self.static_checkpoints
+            .prune_below(static_ptr.depth - STATIC_CHECKPOINT_SEED_AGES * self.seed_age());
+
+        Ok(())
+    }
+
+    // Forks above the final block that branch off below it can never become the best path,
+    // and can't be replayed either as their blocks below the final block are gone
+    fn prune_cut_off_forks(&mut self) {
+        let final_depth = self.finalized.block_ptr.depth as usize;
+        let mut parents = HashSet::from([self.finalized.block_ptr.hash]);
+        for blocks in self.blocks[final_depth + 1..].iter_mut() {
+            blocks.retain(|_, block| parents.contains(&block.header.prev_hash));
+            parents = blocks.keys().copied().collect();
+        }
+    }
+
+    /// Proof of the stake the producer of a header on the best path had in its static ledger,
+    /// which lets a light client check the lottery without the ledger
+    pub fn prove_stake(&self, header: &BlockHeader) -> Result<BalanceProof> {
+        let static_ledger = self.get_static_ledger_of(header.depth)?;
+        Ok(static_ledger.prove_balance(&header.draw.signed_by))
     }
 
     pub fn add_block(&mut self, block: Block) -> Result<()> {
-        self.can_block_be_added(&block)?;
+        self.add_block_from(block, None)
+    }
 
-        // Check if the prev_block is valid
-        let parent_block = self.get_parent(&block);
-        let Some(_) = parent_block else {
+    /// Adds a block that was received from `peer`, which is only used to limit how many orphans it can leave
+    pub fn add_block_from(&mut self, block: Block, peer: Option<NodeId>) -> Result<()> {
+        // The resulting state is checked before the block is stored, so a block we disagree with is never added
+        let Some((next_ledger, record)) = self.validate_block(&block)? else {
             // This block is an orphan
-            if let Some(orphans) = self.orphans.get_mut(&block.prev_hash) {
-                orphans.push(block);
-            } else {
-                self.orphans.insert(block.prev_hash, vec![block]);
-            }
-            return Ok(());
+            return self.orphans.insert(block, peer);
         };
+        let parent_ptr = BlockPtr::new(block.header.prev_hash, block.header.depth - 1);
 
-        while block.depth as usize >= self.blocks.len() {
+        while block.header.depth as usize >= self.blocks.len() {
             // Create empty hashmaps if the block is in the future, this will usually just be done once
             self.blocks.push(HashMap::new());
         }
 
         // Add block to the chain
         self.blocks
-            .get_mut(block.depth as usize)
+            .get_mut(block.header.depth as usize)
             .expect("unreachable")
-            .insert(block.hash, block.clone());
+            .insert(block.header.hash, block.clone());
 
         let block_ptr = &block.ptr();
-        let parent_ptr = self
-            .get_parent(&block)
-            .expect("no parent but we are not in orphan case")
-            .ptr();
         let old_best_path = self.best_path_head().clone();
 
         if old_best_path == parent_ptr {
             // This is an extension of the best path
-            // Remove transactions from the block from the transaction buffer
-            for t in block.transactions.iter() {
-                self.transaction_buffer.remove(t);
-            }
-
-            self.proccess_transactions(&block.transactions)?;
-            self.dynamic_ledger
-                .reward_winner(&block.draw.signed_by, self.calculate_reward(&block));
+            self.dynamic_ledger = next_ledger;
+            self.undo_records.insert(block.header.hash, record);
+            // The static ledger follows the best path, so it must be moved before the path changes
+            self.move_static_ledger(self.best_path.len() as i64 + 1)?;
             self.best_path.push(block_ptr.clone());
-        } else if block > *self.get_block(&old_best_path).expect("unreachable") {
+            self.finalize()?;
+
+            // Drops the transactions of the block and those that can no longer be executed
+            self.transaction_buffer.revalidate(&self.dynamic_ledger);
+        } else if self
+            .fork_choice
+            .prefers(&block, self.get_block(&old_best_path).expect("unreachable"))
+            && self.is_above_finality(&old_best_path, block_ptr)
+        {
             // This block is the new best one and we must rollback
-            self.rollback(&old_best_path, &block_ptr)?;
+            self.rollback(&old_best_path, block_ptr)?;
         }
 
-        // Check if this block has any orphans. If yes, add them after
-        if let Some(orphans) = self.orphans.remove(&block.hash) {
-            for orphan in orphans {
-                self.add_block(orphan.clone())?;
-            }
+        // Check if this block has any orphans. If yes, add them after. This block is already added, so an
+        // invalid orphan is only dropped, as it would have been had it arrived after this block
+        for orphan in self.orphans.remove_children(&block.header.hash) {
+            let _ = self.add_block(orphan);
         }
 
-        self.update_static_ledger()?;
-
-        Ok(())
-    }
-
-    fn update_static_ledger(&mut self) -> Result<()> {
-        let new_depth = self.best_path.len() as i64;
-        let new_static_ledger = self.get_static_ledger_of(new_depth)?;
-        self.static_ledger = new_static_ledger;
         Ok(())
     }
 
+    /// Switches the best path from `from` to `to`, the blocks that are rolled back are kept as a fork.
+    /// Refuses to roll back blocks that are final
     pub fn rollback(&mut self, from: &BlockPtr, to: &BlockPtr) -> Result<()> {
         // Now we are at from, we must first find the common ancestor of from and to
         let common = self
             .find_common_ancestor(from.clone(), to.clone())
             .ok_or(anyhow!("No common ancestor of the rollback"))?;
+        let finality_depth = self.fork_choice.finality_depth(self.params());
+        ensure!(
+            common.depth >= self.finalized.block_ptr.depth,
+            "Cannot rollback the final block"
+        );
+        ensure!(
+            from.depth - common.depth <= finality_depth,
+            "Cannot rollback {} blocks, blocks deeper than {finality_depth} are final",
+            from.depth - common.depth
+        );
 
         // Revert from `from` to `common`
-        let mut from = from.clone();
-        while from != common {
-            self.rollback_block(&from)?;
-            from = self
-                .get_parent_from_ptr(&from)
-                .ok_or(anyhow!("no parent"))?
-                .ptr();
+        while *self.best_path_head() != common {
+            self.unapply_head()?;
         }
 
         // Apply from `common` to `to`
@@ -301,40 +771,105 @@ impl Blockchain {
         Ok(())
     }
 
-    fn rollback_block(&mut self, block_ptr: &BlockPtr) -> Result<()> {
-        if block_ptr != self.best_path_head() {
-            return Err(anyhow!("Cannot rollback a block that is not best"));
+    // Whether switching from the best head to `to` only rolls back blocks that are not final
+    fn is_above_finality(&self, head: &BlockPtr, to: &BlockPtr) -> bool {
+        self.find_common_ancestor(head.clone(), to.clone())
+            .is_some_and(|common| {
+                common.depth >= self.finalized.block_ptr.depth
+                    && head.depth - common.depth <= self.fork_choice.finality_depth(self.params())
+            })
+    }
+
+    // Moves the final block up to `finality_depth` below the best head. Forks and orphans that can no longer
+    // become the best path are dropped, and so are the blocks on the best path that are too old to be needed
+    // to validate a block or roll back to the final block
+    fn finalize(&mut self) -> Result<()> {
+        let final_depth =
+            self.best_path_head().depth - self.fork_choice.finality_depth(self.params());
+        let old_final_depth = self.finalized.block_ptr.depth;
+        while self.finalized.block_ptr.depth < final_depth {
+            let depth = self.finalized.block_ptr.depth + 1;
+            let ptr = self.best_path[depth as usize].clone();
+            let block_reward = self.genesis.params.block_reward;
+            let block = self.blocks[depth as usize]
+                .get(&ptr.hash)
+                .ok_or(anyhow!("invalid deref"))?;
+            self.finalized.dynamic_ledger.apply_block(
+                &block.transactions,
+                &block.header.draw.signed_by,
+                reward_of(&block.transactions, block_reward),
+            )?;
+
+            // The static ledger of the block after the final one is the ledger after the block a seed age below it
+            let static_depth = depth - self.seed_age();
+            if static_depth > 0 {
+                let static_ptr = &self.best_path[static_depth as usize];
+                let block = self.blocks[static_depth as usize]
+                    .get(&static_ptr.hash)
+                    .ok_or(anyhow!("invalid deref"))?;
+                self.finalized.static_ledger.apply_block(
+                    &block.transactions,
+                    &block.header.draw.signed_by,
+                    reward_of(&block.transactions, block_reward),
+                )?;
+                // No static ledger is before this block anymore, so it is never undone
+                self.undo_records.remove(&static_ptr.hash);
+            }
+
+            self.blocks[depth as usize].retain(|hash, _| *hash == ptr.hash);
+            // The finalized static ledger is after the block at `static_depth`, blocks below it are not needed
+            // anymore except for genesis
+            let pruned_depth = static_depth - 1;
+            if pruned_depth > 0 {
+                self.blocks[pruned_depth as usize] = HashMap::new();
+            }
+
+            self.finalized.block_ptr = ptr;
         }
 
-        self.best_path
-            .pop()
-            .ok_or(anyhow!("Cannot rollback genesis"))?;
+        if self.finalized.block_ptr.depth > old_final_depth {
+            self.prune_cut_off_forks();
+        }
+
+        // The parent of an orphan at most one above the final block is not on the best path
+        let final_depth = self.finalized.block_ptr.depth;
+        self.orphans
+            .retain(|orphan| orphan.header.depth > final_depth + 1);
+        self.static_checkpoints
+            .prune_below(final_depth - self.seed_age());
+
+        Ok(())
+    }
 
+    // Takes the best head off the best path and the ledgers, it is still stored
+    fn unapply_head(&mut self) -> Result<Block> {
+        if self.best_path.len() == 1 {
+            return Err(anyhow!("Cannot rollback genesis"));
+        }
+        let block_ptr = self.best_path_head().clone();
         let block = self
-            .get_block(block_ptr)
+            .get_block(&block_ptr)
             .ok_or(anyhow!("Cannot rollback a block that doesn't exist"))?
             .clone();
+        // Everything that can fail is done before the chain is changed
+        let static_ledger = self
+            .get_static_ledger_of(self.best_path.len() as i64 - 1)?
+            .into_owned();
+        let record = self
+            .undo_records
+            .remove(&block_ptr.hash)
+            .ok_or(anyhow!("No undo record of the block"))?;
+
+        self.static_ledger = static_ledger;
+        self.best_path.pop();
+        self.dynamic_ledger.undo(&record);
         for t in block.transactions.iter().rev() {
-            self.dynamic_ledger.rollback_transaction(t, block.depth);
-            self.transaction_buffer.insert(t.clone());
-        }
-
-        self.dynamic_ledger
-            .rollback_reward(&block.draw.signed_by, self.calculate_reward(&block));
-
-        self.blocks[block.depth as usize]
-            .remove_entry(&block_ptr.hash)
-            .ok_or(anyhow!("No block to remove"))?;
-
-        if block.depth >= self.best_path.len() as i64
-            && self.blocks[block.depth as usize].len() == 0
-        {
-            self.blocks.remove(block.depth as usize);
+            self.transaction_buffer.insert_unchecked(t.clone());
         }
 
-        self.update_static_ledger()?;
+        self.transaction_buffer.revalidate(&self.dynamic_ledger);
 
-        Ok(())
+        Ok(block)
     }
 
     fn find_common_ancestor(&self, mut left: BlockPtr, mut right: BlockPtr) -> Option<BlockPtr> {
@@ -351,10 +886,6 @@ impl Blockchain {
         while left != right {
             left = self.get_parent_from_ptr(&left)?.ptr();
             right = self.get_parent_from_ptr(&right)?.ptr();
-
-            if left.depth == 0 || right.depth == 0 {
-                return None;
-            }
         }
 
         // now left == right, we have found the common ancestor
@@ -362,29 +893,45 @@ impl Blockchain {
     }
 
     pub fn make_block(&self, sk: &SecretKey) -> Option<Block> {
+        self.make_block_at(sk, self.genesis.current_timeslot(&self.clock))
+    }
+
+    /// Makes a block extending the best head if we win the lottery at `timeslot`
+    pub fn make_block_at(&self, sk: &SecretKey, timeslot: Timeslot) -> Option<Block> {
         let depth = self.best_path_head().depth + 1;
-        let timeslot = calculate_timeslot(START_TIME);
         let prev_hash = self.best_path_head().hash;
-        let transactions = self.transaction_buffer.clone().into_iter().collect();
         let seed = {
-            if depth >= SEED_AGE {
+            if depth >= self.seed_age() {
                 Seed {
-                    block_ptr: self.best_path[(depth - SEED_AGE) as usize].clone(),
+                    block_ptr: self.best_path[(depth - self.seed_age()) as usize].clone(),
                 }
             } else {
                 let genesis_block = self.get_block(&self.best_path[0]).unwrap();
-                genesis_block.draw.seed.clone()
+                genesis_block.header.draw.seed.clone()
             }
         };
         let new_static_ledger = self
             .get_static_ledger_of(depth)
             .expect("unable to create new static ledger");
-        if is_winner(
+        if self.is_winner(
             &new_static_ledger,
             Draw::new(timeslot, seed.clone(), sk),
             &sk.get_public_key(),
         ) {
-            let block = Block::new(timeslot, prev_hash, depth, transactions, sk, seed);
+            // The transactions are only selected when we won, the rest of the block takes up the same space
+            let empty_block =
+                Block::new(timeslot, prev_hash, depth, Vec::new(), [0; 32], sk, seed.clone());
+            let max_bytes = MAX_BLOCK_SIZE.saturating_sub(empty_block.size() + LENGTH_PREFIX_SIZE);
+            let transactions = self.transaction_buffer.select(
+                &self.dynamic_ledger,
+                MAX_BLOCK_TRANSACTIONS,
+                max_bytes,
+            );
+            let (ledger, _) = self
+                .apply_to_dynamic_ledger(&transactions, &sk.get_public_key())
+                .expect("selected transactions can be applied");
+            let state_root = ledger.state_root();
+            let block = Block::new(timeslot, prev_hash, depth, transactions, state_root, sk, seed);
 
             Some(block)
         } else {
@@ -399,8 +946,8 @@ impl Blockchain {
     }
 
     pub fn get_parent(&self, block: &Block) -> Option<&Block> {
-        let parent_hash = block.prev_hash;
-        let parent_depth = block.depth - 1;
+        let parent_hash = block.header.prev_hash;
+        let parent_depth = block.header.depth - 1;
         let parent_ptr = BlockPtr::new(parent_hash, parent_depth);
         self.get_block(&parent_ptr)
     }
@@ -411,11 +958,17 @@ impl Blockchain {
         self.get_parent(block)
     }
 
-    pub fn verify_chain(&self) -> Result<()> {
+    /// Replays the blocks above the final block on the finalized state, and checks that it results in the
+    /// same best path and ledgers. Also checks that the pending transactions can be added to the best path
+    pub fn verify_chain(&self) -> Result<()>
+    where
+        F: Clone,
+        C: Clone,
+    {
         let genesis_block = {
             let mut blocks = self.blocks[0].values();
             if blocks.len() == 1 {
-                BlockPtr::new(blocks.next().unwrap().hash, 0)
+                BlockPtr::new(blocks.next().unwrap().header.hash, 0)
             } else {
                 return Err(anyhow!("There are too many blocks in genesis depth"));
             }
@@ -426,11 +979,25 @@ impl Blockchain {
             .ok_or(anyhow!("No genesis block"))?
             .clone();
 
-        // We take all the blocks and add them to a new blockchain, if we get the same then it is ok
-        let mut track_blockchain = Blockchain::start(self.root_accounts.clone(), genesis_block);
+        ensure!(
+            genesis_block.header.state_root
+                == Blockchain::genesis_ledger(&self.genesis).state_root(),
+            "Genesis state root does not match the genesis config"
+        );
 
-        let max_depth = self.best_path.len();
-        for depth in 1..max_depth {
+        // We start from the chain as it was at the final block, then take the blocks above it and add them,
+        // if we get the same then it is ok
+        let mut track_blockchain = Blockchain::start_from_snapshot_with_fork_choice(
+            self.snapshot()?,
+            &genesis_block,
+            self.fork_choice.clone(),
+        )?
+        .with_clock(self.clock.clone());
+        track_blockchain.orphans = OrphanPool::new(self.orphans.limits());
+
+        let final_depth = self.finalized.block_ptr.depth as usize;
+        let max_depth = self.blocks.len();
+        for depth in final_depth + 1..max_depth {
             let blocks_at_depth = self.blocks[depth].values();
             for block in blocks_at_depth {
                 track_blockchain.add_block(block.clone())?;
@@ -438,107 +1005,219 @@ impl Blockchain {
         }
 
         // We also add the orphans
-        let orphan_blocks = self.orphans.values().flatten();
+        let orphan_blocks = self.orphans.iter();
         for block in orphan_blocks {
             track_blockchain.add_block(block.clone())?;
         }
 
+        if self.chain_state() != track_blockchain.chain_state() {
+            return Err(anyhow!("Mismatch in resulting blockchains"));
+        }
+
+        // Every pending transaction can be executed on the dynamic ledger, now or after the pending ones
+        // before it, so none of them is already on the best path
         for transaction in self.transaction_buffer.iter() {
             transaction.verify_signature()?;
-            if self
-                .dynamic_ledger
-                .previous_transactions
-                .contains(&transaction.hash)
-            {
-                return Err(anyhow!("Transaction both in prev and in buffer"));
+            if transaction.nonce < self.dynamic_ledger.get_nonce(&transaction.from) {
+                return Err(anyhow!("Transaction in buffer has a nonce that was already used"));
             }
         }
-
-        if self != &track_blockchain {
-            return Err(anyhow!("Mismatch in resulting blockchains"));
-        }
+        let mut revalidated = self.transaction_buffer.clone();
+        revalidated.revalidate(&self.dynamic_ledger);
+        ensure!(
+            revalidated == self.transaction_buffer,
+            "Transaction in buffer can not be executed on the dynamic ledger"
+        );
 
         Ok(())
     }
 
+    // What follows from the blocks, unlike the forks, orphans and pending transactions,
+    // which depend on what was seen and in which order
+    fn chain_state(&self) -> impl PartialEq + std::fmt::Debug + '_ {
+        (
+            &self.best_path,
+            &self.dynamic_ledger,
+            &self.static_ledger,
+            &self.undo_records,
+            &self.finalized,
+        )
+    }
+
+    /// The chain at the final block, which a node can start from with `start_from_snapshot`
+    pub fn snapshot(&self) -> Result<ChainSnapshot> {
+        let final_depth = self.finalized.block_ptr.depth;
+        let first_depth = (final_depth - self.seed_age()).max(1);
+        let blocks = self.best_path[first_depth as usize..=final_depth as usize]
+            .iter()
+            .map(|ptr| self.get_block(ptr).cloned().ok_or(anyhow!("invalid deref")))
+            .collect::<Result<Vec<_>>>()?;
+        let genesis_block = self
+            .get_block(&self.best_path[0])
+            .ok_or(anyhow!("Could not find genesis block"))?;
+
+        Ok(ChainSnapshot {
+            genesis: self.genesis.clone(),
+            genesis_block: genesis_block.clone(),
+            best_path: self.best_path[..=final_depth as usize].to_vec(),
+            blocks,
+            finalized: self.finalized.clone(),
+        })
+    }
+
     pub fn get_static_block_ptr(&self, dynamic_depth: i64) -> &BlockPtr {
         let dynamic_depth = dynamic_depth as usize;
-        let idx = dynamic_depth.saturating_sub(SEED_AGE as _);
+        let idx = dynamic_depth.saturating_sub(self.seed_age() as _);
         &self.best_path[idx]
     }
 
     pub fn calculate_reward(&self, block: &Block) -> MiniLas {
-        block.transactions.len() as MiniLas * TRANSACTION_FEE + BLOCK_REWARD
+        reward_of(&block.transactions, self.genesis.params.block_reward)
     }
 
-    fn proccess_transactions(&mut self, transactions: &Vec<Transaction>) -> Result<()> {
-        for t in transactions.iter() {
-            self.dynamic_ledger.process_transaction(t)?;
-        }
-        Ok(())
+    pub fn params(&self) -> &ChainParams {
+        &self.genesis.params
+    }
+
+    pub fn seed_age(&self) -> i64 {
+        self.params().seed_age
+    }
+
+    // The depth of the block whose resulting ledger is the static ledger of a block at `depth`
+    fn static_depth_of(&self, depth: i64) -> i64 {
+        (depth - self.seed_age() - 1).max(0)
+    }
+
+    fn is_winner(&self, ledger: &Ledger, draw: Draw, wallet: &PublicKey) -> bool {
+        is_winner(ledger, draw, wallet, self.params())
+    }
+
+    // The dynamic ledger as it would be after a block with these transactions extends the best path
+    fn apply_to_dynamic_ledger(
+        &self,
+        transactions: &[Transaction],
+        winner: &PublicKey,
+    ) -> Result<(Ledger, UndoRecord)> {
+        let mut ledger = self.dynamic_ledger.clone();
+        let record = ledger.apply_block(
+            transactions,
+            winner,
+            reward_of(transactions, self.genesis.params.block_reward),
+        )?;
+        Ok((ledger, record))
     }
 }
 
-fn is_winner(ledger: &Ledger, draw: Draw, wallet: &PublicKey) -> bool {
+fn reward_of(transactions: &[Transaction], block_reward: MiniLas) -> MiniLas {
+    let fees = transactions.iter().map(|t| t.fee).sum::<MiniLas>();
+    fees + block_reward
+}
+
+fn is_winner(ledger: &Ledger, draw: Draw, wallet: &PublicKey, params: &ChainParams) -> bool {
     if !ledger.can_stake(wallet) {
         return false;
     }
 
-    let balance = BigUint::from(ledger.get_balance(wallet));
-    let total_money = ledger.get_total_money_in_ledger();
-    let max_hash = BigUint::from(2u64).pow(256);
+    draw.is_winner(
+        ledger.get_balance(wallet),
+        ledger.get_total_money_in_ledger(),
+        params,
+    )
+}
+
+#[cfg(test)]
+impl<F: ForkChoice, C: Clock> Blockchain<F, C> {
+    // Rolls back the best head and removes it
+    pub(crate) fn rollback_block(&mut self, block_ptr: &BlockPtr) -> Result<()> {
+        if block_ptr != self.best_path_head() {
+            return Err(anyhow!("Cannot rollback a block that is not best"));
+        }
+
+        let block = self.unapply_head()?;
 
-    // the entire network has a total 10% chance of beating this at a given timeslot
-    let hardness = BigUint::from(10421u64) * (BigUint::from(10u64).pow(73));
+        self.blocks[block.header.depth as usize]
+            .remove_entry(&block_ptr.hash)
+            .ok_or(anyhow!("No block to remove"))?;
 
-    // we must map the draw value which is in [0, 2^256] to [0, h + c(2^256 - h)] where h is hardness and c is the ratio of money we have
-    // we can map this by multiplying the draw with (h + c(2^256 - h))/(2^256)
-    // we can describe c as balance/total_money. Therefore we can multiply total_money to the hardness and write the multiplication factor as:
-    let mult_factor =
-        (hardness.clone() * total_money) + (balance * (max_hash.clone() - hardness.clone()));
+        if block.header.depth >= self.best_path.len() as i64
+            && self.blocks[block.header.depth as usize].is_empty()
+        {
+            self.blocks.remove(block.header.depth as usize);
+        }
 
-    // We win if we have a good draw and a big enough fraction of the money
-    draw.value.clone() * mult_factor > hardness * total_money * max_hash.clone()
-}
+        Ok(())
+    }
 
-#[cfg(test)]
-impl Blockchain {}
+    // Makes a block at the first timeslot after the best head that `sk` wins, whatever the clock says
+    pub(crate) fn make_next_block(&self, sk: &SecretKey) -> Block {
+        let head = self
+            .get_block(self.best_path_head())
+            .expect("the best head is kept");
+        (head.header.timeslot + 1..)
+            .find_map(|timeslot| self.make_block_at(sk, timeslot))
+            .expect("some timeslot is won")
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::Las;
+    use crate::{Las, clock::MockClock, mempool::MIN_RELAY_FEE, util::SerToBytes};
     use pretty_assertions::assert_eq;
+    use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
+    use std::collections::VecDeque;
 
-    fn mine_new_block(blockchain: &Blockchain, sk: &SecretKey) -> Option<Block> {
-        let mut max_iter = 10_000;
-        let mut new_block = None;
-        while new_block == None && max_iter > 0 {
-            new_block = blockchain.make_block(sk);
-            max_iter -= 1;
-        }
+    type TestBlockchain<F = DeepestChain> = Blockchain<F, MockClock>;
+
+    const RANDOM_HISTORIES: u64 = 48;
+    const RANDOM_HISTORY_STEPS: usize = 60;
 
-        return new_block;
+    // Starts at timeslot 0 of the genesis config, with a clock that only moves when a block is mined
+    fn start(genesis: GenesisConfig, genesis_block: Block) -> TestBlockchain {
+        let clock = MockClock::new(genesis.start_time as u128);
+        Blockchain::start(genesis, genesis_block).with_clock(clock)
+    }
+
+    // Steps through the timeslots until `sk` wins one
+    fn mine_new_block<F: ForkChoice>(
+        blockchain: &TestBlockchain<F>,
+        sk: &SecretKey,
+    ) -> Option<Block> {
+        let slot_length = blockchain.params().slot_length as u128;
+        (0..10_000).find_map(|_| {
+            blockchain.clock().advance(slot_length);
+            blockchain.make_block(sk)
+        })
     }
 
     #[test]
     fn test_start() {
+        let params = ChainParams::devnet();
         let sk1 = SecretKey::generate();
         let sk2 = SecretKey::generate();
 
         let transaction_amount = Las(5);
 
-        let transaction = Transaction::new(&sk1, sk2.get_public_key(), transaction_amount, 42);
+        let transaction = Transaction::new(
+            &sk1,
+            sk2.get_public_key(),
+            transaction_amount,
+            MIN_RELAY_FEE,
+            0,
+        );
 
-        let root_accounts = vec![sk1.get_public_key(), sk2.get_public_key()];
-        let genesis_block = Blockchain::produce_genesis_block(root_accounts.clone(), &sk1);
-        let mut blockchain = Blockchain::start(root_accounts, genesis_block);
+        let genesis = GenesisConfig::with_root_accounts(
+            params.clone(),
+            vec![sk1.get_public_key(), sk2.get_public_key()],
+        );
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk1);
+        let mut blockchain = start(genesis, genesis_block);
 
         blockchain.add_transaction(transaction).unwrap();
         assert_eq!(blockchain.best_path.len(), 1);
         assert_eq!(
             blockchain.dynamic_ledger.get_balance(&sk1.get_public_key()),
-            ROOT_AMOUNT
+            params.root_amount
         );
 
         let new_block = mine_new_block(&blockchain, &sk1).unwrap();
@@ -547,17 +1226,24 @@ mod tests {
         assert_eq!(blockchain.best_path.len(), 2);
         assert_eq!(
             blockchain.dynamic_ledger.get_balance(&sk1.get_public_key()),
-            ROOT_AMOUNT + BLOCK_REWARD - transaction_amount.into_minilas()
+            params.root_amount + params.block_reward - transaction_amount.into_minilas()
         );
 
         assert_eq!(
             blockchain.dynamic_ledger.get_balance(&sk2.get_public_key()),
-            ROOT_AMOUNT + transaction_amount.into_minilas()
+            params.root_amount + transaction_amount.into_minilas()
         );
 
         let transaction2_amount = Las(2);
-
-        let transaction = Transaction::new(&sk2, sk1.get_public_key(), transaction2_amount, 54);
+        let transaction2_fee = 5 * MIN_RELAY_FEE;
+
+        let transaction = Transaction::new(
+            &sk2,
+            sk1.get_public_key(),
+            transaction2_amount,
+            transaction2_fee,
+            0,
+        );
         blockchain.add_transaction(transaction.clone()).unwrap();
         let new_block = mine_new_block(&blockchain, &sk1).unwrap();
         blockchain.add_block(new_block).unwrap();
@@ -565,16 +1251,19 @@ mod tests {
         assert_eq!(blockchain.best_path.len(), 3);
         assert_eq!(
             blockchain.dynamic_ledger.get_balance(&sk1.get_public_key()),
-            ROOT_AMOUNT + 2 * BLOCK_REWARD + TRANSACTION_FEE + transaction2_amount.into_minilas()
+            params.root_amount
+                + 2 * params.block_reward
+                + transaction2_fee
+                + transaction2_amount.into_minilas()
                 - transaction_amount.into_minilas()
         );
 
         assert_eq!(
             blockchain.dynamic_ledger.get_balance(&sk2.get_public_key()),
-            ROOT_AMOUNT - TRANSACTION_FEE - transaction2_amount.into_minilas()
+            params.root_amount - transaction2_fee - transaction2_amount.into_minilas()
                 + transaction_amount.into_minilas()
         );
-        assert_eq!(blockchain.transaction_buffer, vec![].into_iter().collect());
+        assert!(blockchain.transaction_buffer.is_empty());
 
         blockchain
             .rollback_block(&blockchain.best_path_head().clone())
@@ -583,30 +1272,29 @@ mod tests {
         assert_eq!(blockchain.best_path.len(), 2);
         assert_eq!(
             blockchain.dynamic_ledger.get_balance(&sk1.get_public_key()),
-            ROOT_AMOUNT + BLOCK_REWARD - transaction_amount.into_minilas()
+            params.root_amount + params.block_reward - transaction_amount.into_minilas()
         );
 
         assert_eq!(
             blockchain.dynamic_ledger.get_balance(&sk2.get_public_key()),
-            ROOT_AMOUNT + transaction_amount.into_minilas()
+            params.root_amount + transaction_amount.into_minilas()
         );
 
-        assert_eq!(
-            blockchain.transaction_buffer,
-            vec![transaction].into_iter().collect()
-        );
+        assert_eq!(blockchain.transaction_buffer.len(), 1);
+        assert!(blockchain.transaction_buffer.contains(&transaction));
     }
 
     #[test]
     fn test_simple_rollback() {
+        let params = ChainParams::devnet();
         let sk = SecretKey::generate();
-        let root_accounts = vec![sk.get_public_key()];
-        let genesis_block = Blockchain::produce_genesis_block(root_accounts.clone(), &sk);
-        let mut blockchain = Blockchain::start(root_accounts, genesis_block);
+        let genesis = GenesisConfig::with_root_accounts(params.clone(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+        let mut blockchain = start(genesis, genesis_block);
         assert_eq!(blockchain.best_path.len(), 1);
         assert_eq!(
             blockchain.dynamic_ledger.get_balance(&sk.get_public_key()),
-            ROOT_AMOUNT
+            params.root_amount
         );
 
         let initial_blockchain = blockchain.clone();
@@ -615,7 +1303,7 @@ mod tests {
         blockchain.add_block(new_block).unwrap();
 
         assert_eq!(blockchain.best_path.len(), 2);
-        assert!(blockchain.dynamic_ledger.get_balance(&sk.get_public_key()) > ROOT_AMOUNT);
+        assert!(blockchain.dynamic_ledger.get_balance(&sk.get_public_key()) > params.root_amount);
 
         blockchain
             .rollback_block(&blockchain.best_path_head().clone())
@@ -624,21 +1312,22 @@ mod tests {
         assert_eq!(blockchain.best_path.len(), 1);
         assert_eq!(
             blockchain.dynamic_ledger.get_balance(&sk.get_public_key()),
-            ROOT_AMOUNT
+            params.root_amount
         );
         assert_eq!(blockchain, initial_blockchain);
     }
 
     #[test]
     fn test_multiple_blocks() {
+        let params = ChainParams::devnet();
         let sk = SecretKey::generate();
-        let root_accounts = vec![sk.get_public_key()];
-        let genesis_block = Blockchain::produce_genesis_block(root_accounts.clone(), &sk);
-        let mut blockchain = Blockchain::start(root_accounts, genesis_block);
+        let genesis = GenesisConfig::with_root_accounts(params.clone(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+        let mut blockchain = start(genesis, genesis_block);
         assert_eq!(blockchain.best_path.len(), 1);
         assert_eq!(
             blockchain.dynamic_ledger.get_balance(&sk.get_public_key()),
-            ROOT_AMOUNT
+            params.root_amount
         );
 
         let new_block = mine_new_block(&blockchain, &sk).unwrap();
@@ -647,27 +1336,33 @@ mod tests {
         blockchain.add_block(new_block2).unwrap();
 
         assert_eq!(blockchain.best_path.len(), 2);
-        assert!(blockchain.dynamic_ledger.get_balance(&sk.get_public_key()) > ROOT_AMOUNT);
+        assert!(blockchain.dynamic_ledger.get_balance(&sk.get_public_key()) > params.root_amount);
     }
 
     #[test]
     fn many_blocks_and_verify_chain() {
+        let params = ChainParams::devnet();
         let sk1 = SecretKey::generate();
         let sk2 = SecretKey::generate();
         let transaction_amount = Las(1);
 
-        let root_accounts = vec![sk1.get_public_key()];
-        let genesis_block = Blockchain::produce_genesis_block(root_accounts.clone(), &sk1);
-        let mut blockchain = Blockchain::start(root_accounts, genesis_block);
+        let genesis = GenesisConfig::with_root_accounts(params.clone(), vec![sk1.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk1);
+        let mut blockchain = start(genesis, genesis_block);
         assert_eq!(blockchain.best_path.len(), 1);
         assert_eq!(
             blockchain.dynamic_ledger.get_balance(&sk1.get_public_key()),
-            ROOT_AMOUNT
+            params.root_amount
         );
 
-        for nonce in 1..150 {
-            let transaction =
-                Transaction::new(&sk1, sk2.get_public_key(), transaction_amount, nonce);
+        for nonce in 0..149 {
+            let transaction = Transaction::new(
+                &sk1,
+                sk2.get_public_key(),
+                transaction_amount,
+                MIN_RELAY_FEE,
+                nonce,
+            );
             blockchain.add_transaction(transaction).unwrap();
             let new_block = mine_new_block(&blockchain, &sk1).unwrap();
             blockchain.add_block(new_block).unwrap();
@@ -675,32 +1370,69 @@ mod tests {
 
         assert_eq!(blockchain.best_path.len(), 150);
         blockchain.verify_chain().unwrap();
-        blockchain.best_path = blockchain.best_path[..(blockchain.best_path.len() - 1)]
-            .iter()
-            .cloned()
-            .collect();
+        blockchain.best_path = blockchain.best_path[..(blockchain.best_path.len() - 1)].to_vec();
         assert!(blockchain.verify_chain().is_err());
     }
 
+    #[test]
+    fn test_verify_chain_checks_the_mempool() {
+        let sk1 = SecretKey::generate();
+        let sk2 = SecretKey::generate();
+        let genesis =
+            GenesisConfig::with_root_accounts(ChainParams::devnet(), vec![sk1.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk1);
+        let mut blockchain = start(genesis, genesis_block);
+        let transaction = |nonce, amount: Las| {
+            Transaction::new(&sk1, sk2.get_public_key(), amount, MIN_RELAY_FEE, nonce)
+        };
+
+        blockchain.add_transaction(transaction(0, Las(1))).unwrap();
+        let new_block = mine_new_block(&blockchain, &sk1).unwrap();
+        blockchain.add_block(new_block).unwrap();
+
+        // Which transactions are pending is not replayed, it only has to fit the best path
+        blockchain.add_transaction(transaction(1, Las(1))).unwrap();
+        blockchain.verify_chain().unwrap();
+
+        let mut included = blockchain.clone();
+        included
+            .transaction_buffer
+            .insert_unchecked(transaction(0, Las(1)));
+        assert!(included.verify_chain().is_err());
+
+        let balance = blockchain.dynamic_ledger.get_balance(&sk1.get_public_key());
+        let mut unaffordable = blockchain.clone();
+        unaffordable
+            .transaction_buffer
+            .insert_unchecked(transaction(2, Las(balance / 1_000_000)));
+        assert!(unaffordable.verify_chain().is_err());
+    }
+
     #[test]
     fn test_account_publishing() {
+        let params = ChainParams::devnet();
         let sk1 = SecretKey::generate();
         let sk2 = SecretKey::generate();
         let transaction_amount = Las(1);
 
-        let root_accounts = vec![sk1.get_public_key()];
-        let genesis_block = Blockchain::produce_genesis_block(root_accounts.clone(), &sk1);
-        let mut blockchain = Blockchain::start(root_accounts, genesis_block);
+        let genesis = GenesisConfig::with_root_accounts(params.clone(), vec![sk1.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk1);
+        let mut blockchain = start(genesis, genesis_block);
         assert_eq!(blockchain.best_path.len(), 1);
         assert_eq!(
             blockchain.dynamic_ledger.get_balance(&sk1.get_public_key()),
-            ROOT_AMOUNT
+            params.root_amount
         );
 
-        for nonce in 0..50 {
-            if nonce == 5 {
-                let transaction =
-                    Transaction::new(&sk1, sk2.get_public_key(), transaction_amount, nonce);
+        for i in 0..50 {
+            if i == 5 {
+                let transaction = Transaction::new(
+                    &sk1,
+                    sk2.get_public_key(),
+                    transaction_amount,
+                    MIN_RELAY_FEE,
+                    0,
+                );
                 blockchain.add_transaction(transaction).unwrap();
             }
             let new_block = mine_new_block(&blockchain, &sk1).unwrap();
@@ -716,4 +1448,751 @@ mod tests {
 
         assert!(!blockchain.static_ledger.can_stake(&sk2.get_public_key()));
     }
+
+    #[test]
+    fn test_oversized_blocks_are_rejected() {
+        let sk = SecretKey::generate();
+        let genesis =
+            GenesisConfig::with_root_accounts(ChainParams::devnet(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+        let blockchain = start(genesis, genesis_block);
+
+        // The draw does not depend on the transactions, so the same slot can be reused for crafted blocks
+        let block = mine_new_block(&blockchain, &sk).unwrap();
+        let with_transactions = |transactions: Vec<Transaction>| {
+            Block::new(
+                block.header.timeslot,
+                block.header.prev_hash,
+                block.header.depth,
+                transactions,
+                block.header.state_root,
+                &sk,
+                block.header.draw.seed.clone(),
+            )
+        };
+        blockchain
+            .can_block_be_added(&with_transactions(Vec::new()))
+            .unwrap();
+
+        let transaction = Transaction::new(
+            &sk,
+            SecretKey::generate().get_public_key(),
+            Las(1),
+            MIN_RELAY_FEE,
+            0,
+        );
+        let too_many = with_transactions(vec![transaction.clone(); MAX_BLOCK_TRANSACTIONS + 1]);
+        let err = blockchain.can_block_be_added(&too_many).unwrap_err();
+        assert!(err.to_string().contains("transactions"));
+
+        let count = MAX_BLOCK_SIZE / transaction.into_bytes().len() + 1;
+        assert!(count <= MAX_BLOCK_TRANSACTIONS);
+        let too_big = with_transactions(vec![transaction; count]);
+        let err = blockchain.can_block_be_added(&too_big).unwrap_err();
+        assert!(err.to_string().contains("bytes"));
+    }
+
+    #[test]
+    fn test_make_block_respects_size_limit() {
+        let sk1 = SecretKey::generate();
+        let sk2 = SecretKey::generate();
+        let genesis =
+            GenesisConfig::with_root_accounts(ChainParams::devnet(), vec![sk1.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk1);
+        let mut blockchain = start(genesis, genesis_block);
+
+        let transaction = |nonce| {
+            Transaction::new(&sk1, sk2.get_public_key(), 1u64, MIN_RELAY_FEE, nonce)
+        };
+        // More transactions than fit in a block
+        let count = (MAX_BLOCK_SIZE / transaction(0).into_bytes().len() + 1) as u64;
+        for nonce in 0..count {
+            blockchain.add_transaction(transaction(nonce)).unwrap();
+        }
+
+        let new_block = mine_new_block(&blockchain, &sk1).unwrap();
+        assert!(new_block.size() <= MAX_BLOCK_SIZE);
+        assert!((new_block.transactions.len() as u64) < count);
+        let included = new_block.transactions.len();
+        blockchain.add_block(new_block).unwrap();
+        assert_eq!(blockchain.transaction_buffer.len(), count as usize - included);
+
+        // The rest goes into the next block
+        let new_block = mine_new_block(&blockchain, &sk1).unwrap();
+        assert_eq!(new_block.transactions.len(), count as usize - included);
+        blockchain.add_block(new_block).unwrap();
+        assert!(blockchain.transaction_buffer.is_empty());
+    }
+
+    #[test]
+    fn test_static_ledger_follows_best_path() {
+        let params = ChainParams::devnet();
+        let sk1 = SecretKey::generate();
+        let sk2 = SecretKey::generate();
+        let genesis = GenesisConfig::with_root_accounts(params.clone(), vec![sk1.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk1);
+        let mut blockchain = start(genesis, genesis_block);
+
+        let mut balances = vec![blockchain.dynamic_ledger.get_balance(&sk1.get_public_key())];
+        for nonce in 0..params.seed_age as u64 + 10 {
+            let transaction =
+                Transaction::new(&sk1, sk2.get_public_key(), Las(1), MIN_RELAY_FEE, nonce);
+            blockchain.add_transaction(transaction).unwrap();
+            let new_block = mine_new_block(&blockchain, &sk1).unwrap();
+            blockchain.add_block(new_block).unwrap();
+            balances.push(blockchain.dynamic_ledger.get_balance(&sk1.get_public_key()));
+        }
+
+        // The static ledger is the dynamic ledger as it was a seed age of blocks ago
+        let static_balance = |blockchain: &TestBlockchain| {
+            blockchain.static_ledger.get_balance(&sk1.get_public_key())
+        };
+        let depth = blockchain.best_path.len();
+        assert_eq!(
+            static_balance(&blockchain),
+            balances[depth - params.seed_age as usize - 1]
+        );
+
+        let head = blockchain.best_path_head().clone();
+        blockchain.rollback_block(&head).unwrap();
+        assert_eq!(
+            static_balance(&blockchain),
+            balances[depth - params.seed_age as usize - 2]
+        );
+    }
+
+    #[test]
+    fn test_genesis_config_is_followed() {
+        let params = ChainParams::devnet();
+        let sk1 = SecretKey::generate();
+        let sk2 = SecretKey::generate();
+        let mut genesis =
+            GenesisConfig::with_root_accounts(params.clone(), vec![sk1.get_public_key()]);
+        genesis.allocations.push(crate::genesis::Allocation {
+            account: sk2.get_public_key(),
+            amount: 7,
+        });
+        genesis.params.block_reward = 1_000000;
+        genesis.params.seed_age = 5;
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk1);
+        let mut blockchain = start(genesis.clone(), genesis_block.clone());
+        assert_eq!(blockchain.dynamic_ledger.get_balance(&sk2.get_public_key()), 7);
+
+        for _ in 0..10 {
+            let new_block = mine_new_block(&blockchain, &sk1).unwrap();
+            blockchain.add_block(new_block).unwrap();
+        }
+        let balance = |ledger: &Ledger| ledger.get_balance(&sk1.get_public_key());
+        assert_eq!(
+            balance(&blockchain.dynamic_ledger),
+            params.root_amount + 10 * 1_000000
+        );
+        // The static ledger of the next block is after the block a seed age + 1 below it
+        assert_eq!(
+            balance(&blockchain.static_ledger),
+            params.root_amount + 5 * 1_000000
+        );
+        let head = blockchain.get_block(blockchain.best_path_head()).unwrap();
+        assert_eq!(head.header.draw.seed.block_ptr, blockchain.best_path[5]);
+        blockchain.verify_chain().unwrap();
+
+        // Another chain id gives another genesis block
+        genesis.chain_id = "other".to_string();
+        let other_genesis_block = Blockchain::produce_genesis_block(&genesis, &sk1);
+        assert_ne!(
+            other_genesis_block.header.prev_hash,
+            genesis_block.header.prev_hash
+        );
+    }
+
+    #[test]
+    fn test_state_root_is_checked() {
+        let params = ChainParams::devnet();
+        let sk1 = SecretKey::generate();
+        let sk2 = SecretKey::generate();
+        let genesis = GenesisConfig::with_root_accounts(params.clone(), vec![sk1.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk1);
+        let mut blockchain = start(genesis, genesis_block);
+
+        let transaction = Transaction::new(&sk1, sk2.get_public_key(), Las(5), MIN_RELAY_FEE, 0);
+        blockchain.add_transaction(transaction).unwrap();
+        let block = mine_new_block(&blockchain, &sk1).unwrap();
+
+        // A block claiming a state where the winner got more than the reward
+        let mut ledger = blockchain.dynamic_ledger.clone();
+        for t in block.transactions.iter() {
+            ledger.process_transaction(t).unwrap();
+        }
+        ledger
+            .reward_winner(&sk1.get_public_key(), params.block_reward * 2)
+            .unwrap();
+        let inflated = Block::new(
+            block.header.timeslot,
+            block.header.prev_hash,
+            block.header.depth,
+            block.transactions.clone(),
+            ledger.state_root(),
+            &sk1,
+            block.header.draw.seed.clone(),
+        );
+        let err = blockchain.add_block(inflated.clone()).unwrap_err();
+        assert!(err.to_string().contains("State root"));
+        assert!(blockchain.get_block(&inflated.ptr()).is_none());
+        assert_eq!(blockchain.best_path.len(), 1);
+
+        blockchain.add_block(block).unwrap();
+        assert_eq!(
+            blockchain.get_block(blockchain.best_path_head()).unwrap().header.state_root,
+            blockchain.dynamic_ledger.state_root()
+        );
+        blockchain.verify_chain().unwrap();
+    }
+
+    #[test]
+    fn test_rollback_uses_undo_records() {
+        let sk = SecretKey::generate();
+        let genesis =
+            GenesisConfig::with_root_accounts(ChainParams::devnet(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+        let mut blockchain = start(genesis, genesis_block);
+        let initial_blockchain = blockchain.clone();
+
+        let new_block = mine_new_block(&blockchain, &sk).unwrap();
+        blockchain.add_block(new_block.clone()).unwrap();
+        assert!(blockchain.undo_records.contains_key(&new_block.header.hash));
+
+        // Without its record the block can't be rolled back, and nothing is changed
+        let mut without_record = blockchain.clone();
+        without_record.undo_records.clear();
+        let before = without_record.clone();
+        assert!(without_record.rollback_block(&new_block.ptr()).is_err());
+        assert_eq!(without_record, before);
+
+        blockchain.rollback_block(&new_block.ptr()).unwrap();
+        assert_eq!(blockchain, initial_blockchain);
+    }
+
+    #[test]
+    fn test_static_ledger_checkpoints() {
+        // Long enough for the static ledgers of the blocks above the final block to span several checkpoints
+        let params = ChainParams {
+            seed_age: 5 * STATIC_CHECKPOINT_INTERVAL,
+            ..ChainParams::devnet()
+        };
+        let sk1 = SecretKey::generate();
+        let sk2 = SecretKey::generate();
+        let genesis = GenesisConfig::with_root_accounts(params.clone(), vec![sk1.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk1);
+        let mut blockchain = start(genesis.clone(), genesis_block);
+
+        let mut ledgers = vec![Blockchain::genesis_ledger(&genesis)];
+        for nonce in 0..(params.seed_age + 3 * STATIC_CHECKPOINT_INTERVAL) as u64 {
+            let transaction =
+                Transaction::new(&sk1, sk2.get_public_key(), Las(1), MIN_RELAY_FEE, nonce);
+            blockchain.add_transaction(transaction).unwrap();
+            let new_block = mine_new_block(&blockchain, &sk1).unwrap();
+            blockchain.add_block(new_block).unwrap();
+            ledgers.push(blockchain.dynamic_ledger.clone());
+        }
+        assert!(blockchain.static_checkpoints.iter().next().is_some());
+        let mut without_checkpoints = blockchain.clone();
+        without_checkpoints.static_checkpoints = Default::default();
+        assert_eq!(without_checkpoints, blockchain);
+
+        // Whichever ledger it starts from, the static ledger is the dynamic ledger a seed age + 1 blocks earlier
+        let len = blockchain.best_path.len() as i64;
+        for dynamic_depth in params.seed_age + 1..=len {
+            let static_ledger = blockchain.get_static_ledger_of(dynamic_depth).unwrap();
+            assert_eq!(
+                *static_ledger,
+                ledgers[(dynamic_depth - params.seed_age - 1) as usize]
+            );
+        }
+
+        // Checkpoints of blocks that were rolled back are not used
+        for _ in 0..STATIC_CHECKPOINT_INTERVAL {
+            let head = blockchain.best_path_head().clone();
+            blockchain.rollback_block(&head).unwrap();
+        }
+        let len = blockchain.best_path.len() as i64;
+        for dynamic_depth in params.seed_age + 1..=len {
+            let static_ledger = blockchain.get_static_ledger_of(dynamic_depth).unwrap();
+            assert_eq!(
+                *static_ledger,
+                ledgers[(dynamic_depth - params.seed_age - 1) as usize]
+            );
+        }
+    }
+
+    #[test]
+    fn test_side_fork_is_validated_against_its_own_ledger() {
+        let sk = SecretKey::generate();
+        let to = SecretKey::generate().get_public_key();
+        let genesis =
+            GenesisConfig::with_root_accounts(ChainParams::devnet(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+        let mut blockchain = start(genesis, genesis_block);
+        let mut fork = blockchain.clone();
+
+        // The best path spends nonce 0, the fork is mined later so it does not become the best path
+        let transaction = |nonce| Transaction::new(&sk, to.clone(), Las(1), MIN_RELAY_FEE, nonce);
+        blockchain.add_transaction(transaction(0)).unwrap();
+        let block = mine_new_block(&blockchain, &sk).unwrap();
+        blockchain.add_block(block).unwrap();
+
+        let fork_block = mine_new_block(&fork, &sk).unwrap();
+        fork.add_block(fork_block.clone()).unwrap();
+        fork.add_transaction(transaction(0)).unwrap();
+        let valid = mine_new_block(&fork, &sk).unwrap();
+        assert_eq!(valid.transactions, vec![transaction(0)]);
+
+        blockchain.add_block(fork_block.clone()).unwrap();
+        assert_ne!(blockchain.best_path_head(), &fork_block.ptr());
+        blockchain.can_block_be_added(&valid).unwrap();
+
+        // Nonce 1 is next on the best path, but not on the fork
+        let invalid = Block::new(
+            valid.header.timeslot,
+            valid.header.prev_hash,
+            valid.header.depth,
+            vec![transaction(1)],
+            valid.header.state_root,
+            &sk,
+            valid.header.draw.seed.clone(),
+        );
+        let err = blockchain.can_block_be_added(&invalid).unwrap_err();
+        assert!(err.to_string().contains("Nonce"));
+
+        // The state root of a side block is checked as well
+        let wrong_root = Block::new(
+            valid.header.timeslot,
+            valid.header.prev_hash,
+            valid.header.depth,
+            valid.transactions.clone(),
+            blockchain.dynamic_ledger.state_root(),
+            &sk,
+            valid.header.draw.seed.clone(),
+        );
+        let err = blockchain.can_block_be_added(&wrong_root).unwrap_err();
+        assert!(err.to_string().contains("State root"));
+    }
+
+    #[test]
+    fn test_deepest_chain_wins() {
+        let sk = SecretKey::generate();
+        let genesis =
+            GenesisConfig::with_root_accounts(ChainParams::devnet(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+        let mut blockchain = start(genesis, genesis_block);
+        let mut fork = blockchain.clone();
+
+        let block = mine_new_block(&blockchain, &sk).unwrap();
+        blockchain.add_block(block.clone()).unwrap();
+        for _ in 0..2 {
+            let fork_block = mine_new_block(&fork, &sk).unwrap();
+            fork.add_block(fork_block).unwrap();
+        }
+
+        // The fork has later timeslots, but is deeper once both its blocks are added
+        let fork_blocks = fork.best_path[1..]
+            .iter()
+            .map(|ptr| fork.get_block(ptr).unwrap().clone())
+            .collect::<Vec<_>>();
+        blockchain.add_block(fork_blocks[0].clone()).unwrap();
+        assert_eq!(blockchain.best_path_head(), &block.ptr());
+        blockchain.add_block(fork_blocks[1].clone()).unwrap();
+        assert_eq!(blockchain.best_path, fork.best_path);
+        assert_eq!(blockchain.dynamic_ledger, fork.dynamic_ledger);
+
+        // The block that was rolled back is kept as a fork
+        assert!(blockchain.get_block(&block.ptr()).is_some());
+        blockchain.verify_chain().unwrap();
+    }
+
+    #[test]
+    fn test_final_blocks_are_not_rolled_back() {
+        #[derive(Clone, PartialEq, Eq, Debug)]
+        struct ShallowFinality;
+
+        impl ForkChoice for ShallowFinality {
+            fn prefers(&self, candidate: &Block, head: &Block) -> bool {
+                DeepestChain.prefers(candidate, head)
+            }
+
+            fn finality_depth(&self, _: &ChainParams) -> i64 {
+                1
+            }
+        }
+
+        let sk = SecretKey::generate();
+        let genesis =
+            GenesisConfig::with_root_accounts(ChainParams::devnet(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+        let clock = MockClock::new(genesis.start_time as u128);
+        let mut blockchain =
+            Blockchain::start_with_fork_choice(genesis, genesis_block, ShallowFinality)
+                .with_clock(clock);
+        let mut fork = blockchain.clone();
+
+        for _ in 0..2 {
+            let block = mine_new_block(&blockchain, &sk).unwrap();
+            blockchain.add_block(block).unwrap();
+        }
+        for _ in 0..3 {
+            let fork_block = mine_new_block(&fork, &sk).unwrap();
+            fork.add_block(fork_block).unwrap();
+        }
+
+        // The fork branches off below the final block, so its blocks are not even kept
+        let best_path = blockchain.best_path.clone();
+        for ptr in fork.best_path[1..3].iter() {
+            let fork_block = fork.get_block(ptr).unwrap().clone();
+            let err = blockchain.add_block(fork_block).unwrap_err();
+            assert!(err.to_string().contains("final"));
+        }
+        let fork_head = fork.get_block(fork.best_path_head()).unwrap().clone();
+        blockchain.add_block(fork_head).unwrap();
+        assert_eq!(blockchain.best_path, best_path);
+
+        let head = blockchain.best_path_head().clone();
+        let err = blockchain
+            .rollback(&head, fork.best_path_head())
+            .unwrap_err();
+        assert!(err.to_string().contains("common ancestor"));
+        assert_eq!(blockchain.best_path, best_path);
+    }
+
+    #[test]
+    fn test_invalid_orphan_does_not_fail_its_parent() {
+        let sk = SecretKey::generate();
+        let genesis =
+            GenesisConfig::with_root_accounts(ChainParams::devnet(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+        let mut blockchain = start(genesis, genesis_block);
+
+        let mut ahead = blockchain.clone();
+        let parent = mine_new_block(&ahead, &sk).unwrap();
+        ahead.add_block(parent.clone()).unwrap();
+        let valid = mine_new_block(&ahead, &sk).unwrap();
+        let invalid = Block::new(
+            valid.header.timeslot,
+            valid.header.prev_hash,
+            valid.header.depth,
+            Vec::new(),
+            [0; 32],
+            &sk,
+            valid.header.draw.seed.clone(),
+        );
+
+        blockchain.add_block(invalid).unwrap();
+        assert_eq!(blockchain.orphans.len(), 1);
+        blockchain.add_block(parent.clone()).unwrap();
+        assert_eq!(blockchain.best_path_head(), &parent.ptr());
+        assert!(blockchain.orphans.is_empty());
+        blockchain.verify_chain().unwrap();
+    }
+
+    #[test]
+    fn test_finalization_prunes_old_blocks() {
+        let params = ChainParams::devnet();
+        let sk = SecretKey::generate();
+        let genesis = GenesisConfig::with_root_accounts(params.clone(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+        let mut blockchain = start(genesis, genesis_block);
+        let mut fork = blockchain.clone();
+
+        let block = mine_new_block(&blockchain, &sk).unwrap();
+        blockchain.add_block(block).unwrap();
+        for _ in 0..3 {
+            let fork_block = mine_new_block(&fork, &sk).unwrap();
+            fork.add_block(fork_block).unwrap();
+        }
+        let fork_blocks = fork.best_path[1..]
+            .iter()
+            .map(|ptr| fork.get_block(ptr).unwrap().clone())
+            .collect::<Vec<_>>();
+
+        // The first fork block is a side block and the last one an orphan
+        blockchain.add_block(fork_blocks[0].clone()).unwrap();
+        blockchain.add_block(fork_blocks[2].clone()).unwrap();
+        assert_eq!(blockchain.orphans.len(), 1);
+
+        let finality_depth = DeepestChain.finality_depth(&params);
+        for nonce in 0..(2 * params.seed_age + finality_depth) as u64 {
+            let transaction = Transaction::new(
+                &sk,
+                SecretKey::generate().get_public_key(),
+                Las(1),
+                MIN_RELAY_FEE,
+                nonce,
+            );
+            blockchain.add_transaction(transaction).unwrap();
+            let new_block = mine_new_block(&blockchain, &sk).unwrap();
+            blockchain.add_block(new_block).unwrap();
+        }
+
+        let head_depth = blockchain.best_path_head().depth;
+        let final_depth = head_depth - finality_depth;
+        assert_eq!(
+            blockchain.finalized.block_ptr,
+            blockchain.best_path[final_depth as usize]
+        );
+        assert_eq!(
+            blockchain.finalized.dynamic_ledger,
+            *blockchain
+                .ledger_at(&blockchain.finalized.block_ptr)
+                .unwrap()
+        );
+        assert_eq!(
+            blockchain.finalized.static_ledger,
+            *blockchain.get_static_ledger_of(final_depth + 1).unwrap()
+        );
+
+        // Forks and orphans below the final block are dropped, and so are blocks far below it
+        assert!(blockchain.get_block(&fork_blocks[0].ptr()).is_none());
+        assert!(blockchain.orphans.is_empty());
+        let oldest_kept = final_depth - params.seed_age;
+        for ptr in &blockchain.best_path[1..] {
+            assert_eq!(
+                blockchain.get_block(ptr).is_some(),
+                ptr.depth >= oldest_kept
+            );
+            assert_eq!(
+                blockchain.undo_records.contains_key(&ptr.hash),
+                ptr.depth > oldest_kept
+            );
+        }
+        assert!(blockchain.get_block(&blockchain.best_path[0]).is_some());
+
+        // A block that forks off below the final block is rejected
+        let err = blockchain.add_block(fork_blocks[1].clone()).unwrap_err();
+        assert!(err.to_string().contains("final"));
+
+        blockchain.verify_chain().unwrap();
+        let mut wrong_snapshot = blockchain.clone();
+        wrong_snapshot.finalized.dynamic_ledger = wrong_snapshot.finalized.static_ledger.clone();
+        assert!(wrong_snapshot.verify_chain().is_err());
+    }
+
+    #[test]
+    fn test_finalization_prunes_forks_cut_off_below_it() {
+        let params = ChainParams::devnet();
+        let finality_depth = DeepestChain.finality_depth(&params);
+        let sk = SecretKey::generate();
+        let genesis = GenesisConfig::with_root_accounts(params, vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+        let mut blockchain = start(genesis, genesis_block);
+        let mine = |blockchain: &mut TestBlockchain, n: i64| {
+            for _ in 0..n {
+                let block = mine_new_block(blockchain, &sk).unwrap();
+                blockchain.add_block(block).unwrap();
+            }
+        };
+
+        mine(&mut blockchain, 3);
+        let mut fork = blockchain.clone();
+        mine(&mut blockchain, finality_depth - 1);
+        mine(&mut fork, finality_depth - 2);
+
+        // The fork branches off above the final block, so it is kept as a side fork
+        let fork_ptrs = fork.best_path[4..].to_vec();
+        for ptr in fork_ptrs.iter() {
+            blockchain
+                .add_block(fork.get_block(ptr).unwrap().clone())
+                .unwrap();
+        }
+        assert!(blockchain.finalized.block_ptr.depth < 3);
+        assert!(blockchain.get_block(&fork_ptrs[0]).is_some());
+
+        // Once the final block is above the branch, even the fork blocks above the final block are dropped
+        mine(&mut blockchain, 3);
+        assert!(blockchain.finalized.block_ptr.depth > 3);
+        assert!(
+            fork_ptrs
+                .iter()
+                .all(|ptr| blockchain.get_block(ptr).is_none())
+        );
+        blockchain.verify_chain().unwrap();
+    }
+
+    // Steps through the timeslots until one of `sks` wins, trying them in a random order
+    fn mine_with_any<F: ForkChoice>(
+        blockchain: &TestBlockchain<F>,
+        sks: &[SecretKey],
+        rng: &mut StdRng,
+    ) -> Block {
+        let slot_length = blockchain.params().slot_length as u128;
+        (0..10_000)
+            .find_map(|_| {
+                blockchain.clock().advance(slot_length);
+                let first = rng.random_range(0..sks.len());
+                (0..sks.len()).find_map(|i| blockchain.make_block(&sks[(first + i) % sks.len()]))
+            })
+            .expect("some timeslot is won")
+    }
+
+    // A transaction from a random key with money, usually one the key can pay for
+    fn random_transaction<F: ForkChoice>(
+        blockchain: &TestBlockchain<F>,
+        sks: &[SecretKey],
+        rng: &mut StdRng,
+    ) -> Option<Transaction> {
+        let sk = &sks[rng.random_range(0..sks.len())];
+        let from = sk.get_public_key();
+        let balance = blockchain.dynamic_ledger.get_balance(&from);
+        if balance <= MIN_RELAY_FEE {
+            return None;
+        }
+        let pending = blockchain
+            .transaction_buffer
+            .iter()
+            .filter(|transaction| transaction.from == from)
+            .count() as u64;
+        let nonce = blockchain.dynamic_ledger.get_nonce(&from) + pending;
+        let to = sks[rng.random_range(0..sks.len())].get_public_key();
+        let amount = if rng.random_bool(0.1) {
+            balance
+        } else {
+            rng.random_range(0..balance / 4)
+        };
+        Some(Transaction::new(sk, to, amount, MIN_RELAY_FEE, nonce))
+    }
+
+    // What must hold after any sequence of blocks, forks, orphans, transactions and rollbacks
+    fn check_invariants(blockchain: &TestBlockchain, sks: &[SecretKey], genesis_total: MiniLas) {
+        let block_reward = blockchain.params().block_reward;
+        let supply_at = |depth: i64| genesis_total + block_reward * depth as MiniLas;
+
+        // Fees only move money, so the supply only grows by the block rewards
+        let head_depth = blockchain.best_path_head().depth;
+        let ledger = &blockchain.dynamic_ledger;
+        assert_eq!(ledger.get_total_money_in_ledger(), supply_at(head_depth));
+        let static_depth = blockchain.static_depth_of(blockchain.best_path.len() as i64);
+        assert_eq!(
+            blockchain.static_ledger.get_total_money_in_ledger(),
+            supply_at(static_depth)
+        );
+        let final_depth = blockchain.finalized.block_ptr.depth;
+        assert_eq!(
+            blockchain
+                .finalized
+                .dynamic_ledger
+                .get_total_money_in_ledger(),
+            supply_at(final_depth)
+        );
+
+        // Every account that has money is one of the keys, so a balance that wrapped around would show
+        let balances = sks
+            .iter()
+            .map(|sk| ledger.get_balance(&sk.get_public_key()))
+            .try_fold(0 as MiniLas, MiniLas::checked_add);
+        assert_eq!(balances, Some(supply_at(head_depth)));
+
+        assert_eq!(
+            blockchain
+                .get_block(blockchain.best_path_head())
+                .unwrap()
+                .header
+                .state_root,
+            ledger.state_root()
+        );
+    }
+
+    // A history of blocks, forks, orphans, transactions and rollbacks that only depends on `seed`
+    fn random_history(seed: u64, steps: usize) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let sks = (0..8)
+            .map(|_| SecretKey::from_bytes(&rng.random()))
+            .collect::<Vec<_>>();
+        // Half of the keys are root accounts, the others only get money from transactions and blocks
+        let root_accounts = sks[..4].iter().map(|sk| sk.get_public_key()).collect();
+        let genesis = GenesisConfig::with_root_accounts(ChainParams::devnet(), root_accounts);
+        let genesis_total = genesis.params.root_amount * 4;
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sks[0]);
+        let mut blockchain = start(genesis, genesis_block);
+        // The last states of the chain, forks start from them
+        let mut history = VecDeque::from([blockchain.clone()]);
+
+        for step in 0..steps {
+            match rng.random_range(0..10) {
+                // Transactions, valid or not, end up in blocks or are rejected
+                0..=2 => {
+                    for _ in 0..rng.random_range(1..5) {
+                        if let Some(transaction) = random_transaction(&blockchain, &sks, &mut rng) {
+                            let _ = blockchain.add_transaction(transaction);
+                        }
+                    }
+                }
+                // A node that stopped following the chain some steps ago makes a fork, whose blocks
+                // arrive in any order
+                3 => {
+                    let mut fork = history[rng.random_range(0..history.len())].clone();
+                    let mut fork_blocks = Vec::new();
+                    for _ in 0..rng.random_range(1..5) {
+                        if let Some(transaction) = random_transaction(&fork, &sks, &mut rng) {
+                            let _ = fork.add_transaction(transaction);
+                        }
+                        let block = mine_with_any(&fork, &sks, &mut rng);
+                        fork.add_block(block.clone()).unwrap();
+                        fork_blocks.push(block);
+                    }
+                    fork_blocks.shuffle(&mut rng);
+                    for block in fork_blocks {
+                        // Blocks that fork off below the final block are refused
+                        if let Err(err) = blockchain.add_block(block) {
+                            assert!(err.to_string().contains("final"), "{err}");
+                        }
+                    }
+                }
+                // Rolling back any number of blocks above the final block and adding them again,
+                // in any order, leads to the same chain
+                4 => {
+                    let final_depth = blockchain.finalized.block_ptr.depth;
+                    let above_final = blockchain.best_path_head().depth - final_depth;
+                    if above_final > 0 {
+                        let before = blockchain.clone();
+                        let mut rolled_back = Vec::new();
+                        for _ in 0..rng.random_range(1..=above_final) {
+                            let head = blockchain.best_path_head().clone();
+                            rolled_back.push(blockchain.get_block(&head).unwrap().clone());
+                            blockchain.rollback_block(&head).unwrap();
+                        }
+
+                        rolled_back.shuffle(&mut rng);
+                        for block in rolled_back {
+                            blockchain.add_block(block).unwrap();
+                        }
+                        assert_eq!(blockchain.chain_state(), before.chain_state());
+                    }
+                }
+                _ => {
+                    let block = mine_with_any(&blockchain, &sks, &mut rng);
+                    blockchain.add_block(block).unwrap();
+                }
+            }
+            check_invariants(&blockchain, &sks, genesis_total);
+            // Replaying is slow, so it is only done now and then
+            if step % 10 == 0 {
+                blockchain.verify_chain().unwrap();
+            }
+
+            history.push_back(blockchain.clone());
+            if history.len() > 20 {
+                history.pop_front();
+            }
+        }
+        assert!(blockchain.finalized.block_ptr.depth > 0);
+        blockchain.verify_chain().unwrap();
+    }
+
+    #[test]
+    fn test_random_histories_keep_invariants() {
+        for seed in 0..RANDOM_HISTORIES {
+            if std::panic::catch_unwind(|| random_history(seed, RANDOM_HISTORY_STEPS)).is_err() {
+                panic!(
+                    "History with seed {seed} breaks an invariant, rerun it with `random_history({seed}, {RANDOM_HISTORY_STEPS})`"
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,98 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::watch;
+
+use crate::util::get_unix_timestamp;
+
+/// Where the time comes from, so tests and simulations can step through timeslots instead of waiting for them
+pub trait Clock {
+    /// Unix time in microseconds
+    fn now(&self) -> u128;
+
+    /// Resolves once `now` has reached `time`
+    fn sleep_until(&self, time: u128) -> impl Future<Output = ()> + Send;
+}
+
+/// The time of the system, used by a running node
+#[derive(Clone, Copy, Default, Eq, PartialEq, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u128 {
+        get_unix_timestamp()
+    }
+
+    async fn sleep_until(&self, time: u128) {
+        let time_to_sleep = time.saturating_sub(self.now());
+        tokio::time::sleep(Duration::from_micros(time_to_sleep as _)).await;
+    }
+}
+
+/// A clock that only moves when it is told to. Clones share the time, so a test can keep a clone
+/// to advance the clock it gave to a blockchain or a `ClockActor`
+#[derive(Clone, Debug)]
+pub struct MockClock(Arc<watch::Sender<u128>>);
+
+impl MockClock {
+    pub fn new(now: u128) -> Self {
+        Self(Arc::new(watch::Sender::new(now)))
+    }
+
+    pub fn set(&self, now: u128) {
+        self.0.send_replace(now);
+    }
+
+    pub fn advance(&self, micros: u128) {
+        self.0.send_modify(|now| *now += micros);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl PartialEq for MockClock {
+    fn eq(&self, other: &Self) -> bool {
+        self.now() == other.now()
+    }
+}
+
+impl Eq for MockClock {}
+
+impl Clock for MockClock {
+    fn now(&self) -> u128 {
+        *self.0.borrow()
+    }
+
+    async fn sleep_until(&self, time: u128) {
+        let mut receiver = self.0.subscribe();
+        // The sender is kept alive by `self`, so this only returns once the time is reached
+        let _ = receiver.wait_for(|&now| now >= time).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix::test]
+    async fn test_mock_clock() {
+        let clock = MockClock::new(100);
+        let shared = clock.clone();
+        shared.advance(50);
+        assert_eq!(clock.now(), 150);
+
+        let sleeper = tokio::spawn({
+            let clock = clock.clone();
+            async move { clock.sleep_until(200).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!sleeper.is_finished());
+
+        shared.set(200);
+        sleeper.await.unwrap();
+        assert_eq!(clock.now(), 200);
+    }
+}
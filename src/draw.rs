@@ -1,7 +1,7 @@
 use num_bigint::BigUint;
 use serde::{Deserialize, Serialize};
 use anyhow::Result;
-use crate::{keys::{PublicKey, SecretKey, Signature}, util::{hash, BlockPtr, SerToBytes, Timeslot}};
+use crate::{keys::{PublicKey, SecretKey, Signature}, params::ChainParams, util::{hash, BlockPtr, MiniLas, SerToBytes, Timeslot}};
 
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct Draw {
@@ -52,12 +52,27 @@ impl Draw {
 
         signature.verify(&self.signed_by, &data_to_sign)
     }
-}
 
-pub const SEED_AGE: i64 = 50;
+    /// Whether the draw wins the lottery for someone holding `balance` of the `total_money` that can be staked,
+    /// where the entire network has to beat the lottery hardness of `params` to win a timeslot
+    pub fn is_winner(&self, balance: MiniLas, total_money: MiniLas, params: &ChainParams) -> bool {
+        let hardness = &params.lottery_hardness;
+        let balance = BigUint::from(balance);
+        let max_hash = BigUint::from(2u64).pow(256);
+
+        // we must map the draw value which is in [0, 2^256] to [0, h + c(2^256 - h)] where h is hardness and c is the ratio of money we have
+        // we can map this by multiplying the draw with (h + c(2^256 - h))/(2^256)
+        // we can describe c as balance/total_money. Therefore we can multiply total_money to the hardness and write the multiplication factor as:
+        let mult_factor =
+            (hardness.clone() * total_money) + (balance * (max_hash.clone() - hardness.clone()));
+
+        // We win if we have a good draw and a big enough fraction of the money
+        self.value.clone() * mult_factor > hardness * total_money * max_hash.clone()
+    }
+}
 
-// The seed starts being a special genesis hash (hash of root accounts), once we reach block depth 51
-// it will be depth-50, this way the seed is unpredictable
+// The seed starts being a special genesis hash (hash of the genesis config), once we reach block depth 51
+// it will be depth-50 (with the seed age of 50 of mainnet), this way the seed is unpredictable
 // we only allow peers to stake if they have had enough money 
 // in the ledger for 100 rounds, that way they would have to predict the hash in 50 blocks
 // and we consider a rollback of more than 50 blocks very unlikely so it only makes sense 
@@ -68,9 +83,8 @@ pub struct Seed {
 }
 
 impl Seed {
-    pub fn correct_age(&self, best_depth: i64) -> bool {
-        let seed_age = best_depth - self.block_ptr.depth;
-        seed_age == SEED_AGE
+    pub fn correct_age(&self, best_depth: i64, params: &ChainParams) -> bool {
+        best_depth - self.block_ptr.depth == params.seed_age
     }
 }
 
@@ -109,8 +123,10 @@ mod tests {
                 depth: 100,
             },
         };
-        assert!(seed.correct_age(150));
-        assert!(!seed.correct_age(149));
-        assert!(!seed.correct_age(151));
+        let params = ChainParams::mainnet();
+        assert!(seed.correct_age(150, &params));
+        assert!(!seed.correct_age(149, &params));
+        assert!(!seed.correct_age(151, &params));
+        assert!(seed.correct_age(110, &ChainParams::devnet()));
     }
 }
\ No newline at end of file
@@ -0,0 +1,85 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{block::Block, params::ChainParams};
+
+/// The rule deciding which chain is the best one when a block does not extend the best head
+pub trait ForkChoice {
+    /// Whether the chain ending in `candidate` is better than the chain ending in `head`
+    fn prefers(&self, candidate: &Block, head: &Block) -> bool;
+
+    /// Blocks this deep below the best head are final, a fork branching off below them is never switched to
+    fn finality_depth(&self, params: &ChainParams) -> i64 {
+        // A seed is this old, so a deeper rollback would change the seed of the best head
+        params.seed_age
+    }
+}
+
+/// Prefers the deepest chain, between chains of the same depth the head that compares greatest wins
+#[derive(Clone, Copy, Default, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct DeepestChain;
+
+impl ForkChoice for DeepestChain {
+    fn prefers(&self, candidate: &Block, head: &Block) -> bool {
+        match candidate.header.depth.cmp(&head.header.depth) {
+            std::cmp::Ordering::Greater => true,
+            std::cmp::Ordering::Less => false,
+            std::cmp::Ordering::Equal => candidate > head,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        draw::Seed, keys::SecretKey, mempool::MIN_RELAY_FEE, transaction::Transaction,
+        util::BlockPtr,
+    };
+
+    fn block(timeslot: u64, depth: i64) -> Block {
+        block_with_transactions(timeslot, depth, 0)
+    }
+
+    fn block_with_transactions(timeslot: u64, depth: i64, n: u64) -> Block {
+        let seed = Seed {
+            block_ptr: BlockPtr::new([0; 32], 0),
+        };
+        let sk = SecretKey::generate();
+        let transactions = (0..n)
+            .map(|nonce| Transaction::new(&sk, sk.get_public_key(), 1u64, MIN_RELAY_FEE, nonce))
+            .collect();
+        Block::new(timeslot, [0; 32], depth, transactions, [0; 32], &sk, seed)
+    }
+
+    #[test]
+    fn test_deepest_chain() {
+        let head = block(5, 3);
+
+        // A deeper chain wins even with a later timeslot, and a shallower one loses with an earlier one
+        assert!(DeepestChain.prefers(&block(10, 4), &head));
+        assert!(!DeepestChain.prefers(&block(1, 2), &head));
+
+        // At the same depth the earlier timeslot wins
+        assert!(DeepestChain.prefers(&block(4, 3), &head));
+        assert!(!DeepestChain.prefers(&block(6, 3), &head));
+    }
+
+    #[test]
+    fn test_ties_are_broken_both_ways() {
+        // At the same depth and timeslot the block with more transactions wins, whichever is the head
+        let fuller = block_with_transactions(5, 3, 2);
+        for _ in 0..10 {
+            let emptier = block_with_transactions(5, 3, 1);
+            assert!(DeepestChain.prefers(&fuller, &emptier));
+            assert!(!DeepestChain.prefers(&emptier, &fuller));
+        }
+
+        // Then the hash decides, for exactly one of the two
+        let other = block_with_transactions(5, 3, 2);
+        assert_ne!(
+            DeepestChain.prefers(&fuller, &other),
+            DeepestChain.prefers(&other, &fuller)
+        );
+        assert!(!DeepestChain.prefers(&fuller, &fuller));
+    }
+}
@@ -0,0 +1,191 @@
+use std::{collections::HashSet, fs, path::Path};
+
+use anyhow::{Context, Result, anyhow, ensure};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::Clock,
+    keys::PublicKey,
+    params::ChainParams,
+    util::{
+        MiniLas, START_TIME, SerToBytes, Sha256Hash, Timeslot, as_string, calculate_timeslot, hash,
+    },
+};
+
+pub const DEFAULT_CHAIN_ID: &str = "lasagna";
+
+/// Everything the nodes of a network must agree on before the first block.
+/// Its hash is the seed of the genesis block, so the blocks of a network started from another config
+/// are never valid on this one
+#[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct GenesisConfig {
+    pub chain_id: String,
+    pub start_time: u64, // Unix time in microseconds where timeslot 0 begins
+    pub params: ChainParams,
+    // Every account given money in the genesis ledger is a root account, which can stake immediately
+    pub allocations: Vec<Allocation>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct Allocation {
+    #[serde(with = "as_string")]
+    pub account: PublicKey,
+    pub amount: MiniLas,
+}
+
+impl GenesisConfig {
+    /// A config where every root account is given the root amount of `params`, starting at `START_TIME`
+    pub fn with_root_accounts(params: ChainParams, root_accounts: Vec<PublicKey>) -> Self {
+        Self {
+            chain_id: DEFAULT_CHAIN_ID.to_string(),
+            start_time: START_TIME as u64,
+            allocations: root_accounts
+                .into_iter()
+                .map(|account| Allocation {
+                    account,
+                    amount: params.root_amount,
+                })
+                .collect(),
+            params,
+        }
+    }
+
+    pub fn root_accounts(&self) -> Vec<PublicKey> {
+        self.allocations
+            .iter()
+            .map(|allocation| allocation.account.clone())
+            .collect()
+    }
+
+    pub fn hash(&self) -> Sha256Hash {
+        hash(&self.into_bytes())
+    }
+
+    /// The timeslot we are in according to `clock`
+    pub fn current_timeslot(&self, clock: &impl Clock) -> Timeslot {
+        calculate_timeslot(
+            clock.now(),
+            self.start_time as u128,
+            self.params.slot_length as u128,
+        )
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        ensure!(!self.chain_id.is_empty(), "Chain id must not be empty");
+        self.params.validate()?;
+        ensure!(
+            !self.allocations.is_empty(),
+            "There must be at least one allocation"
+        );
+
+        let mut accounts = HashSet::new();
+        for allocation in self.allocations.iter() {
+            ensure!(
+                accounts.insert(&allocation.account),
+                "{} is allocated more than once",
+                allocation.account
+            );
+            ensure!(
+                allocation.amount > 0,
+                "{} is allocated nothing",
+                allocation.account
+            );
+        }
+        self.allocations
+            .iter()
+            .try_fold(0 as MiniLas, |total, allocation| {
+                total.checked_add(allocation.amount)
+            })
+            .ok_or(anyhow!("Total allocation overflows"))?;
+
+        Ok(())
+    }
+
+    pub fn from_toml(s: &str) -> Result<Self> {
+        let config: Self = toml::from_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("Unable to serialize")
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let s = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read genesis config {}", path.display()))?;
+        Self::from_toml(&s).with_context(|| format!("Invalid genesis config {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use num_bigint::BigUint;
+
+    use super::*;
+    use crate::keys::SecretKey;
+
+    #[test]
+    fn test_toml_roundtrip() {
+        let root_accounts = vec![
+            SecretKey::generate().get_public_key(),
+            SecretKey::generate().get_public_key(),
+        ];
+        let mut config =
+            GenesisConfig::with_root_accounts(ChainParams::mainnet(), root_accounts.clone());
+        config.allocations[1].amount = 5;
+
+        let toml = config.to_toml();
+        assert!(toml.contains(&root_accounts[0].to_string()));
+        assert_eq!(GenesisConfig::from_toml(&toml).unwrap(), config);
+        assert_eq!(config.root_accounts(), root_accounts);
+    }
+
+    #[test]
+    fn test_parse() {
+        let account = SecretKey::generate().get_public_key();
+        let toml = format!(
+            r#"
+            chain_id = "testnet"
+            start_time = 1761384740000000
+
+            [params]
+            slot_length = 2000000
+            block_reward = 1000000
+            root_amount = 0
+            minimum_stake_amount = 5000000
+            seed_age = 20
+            lottery_hardness = "100"
+
+            [[allocations]]
+            account = "{account}"
+            amount = 42
+            "#
+        );
+        let config = GenesisConfig::from_toml(&toml).unwrap();
+        assert_eq!(config.chain_id, "testnet");
+        assert_eq!(config.params.seed_age, 20);
+        assert_eq!(config.params.lottery_hardness, BigUint::from(100u64));
+        assert_eq!(
+            config.allocations,
+            vec![Allocation {
+                account: account.clone(),
+                amount: 42
+            }]
+        );
+
+        // Any difference in the config changes the seed of the genesis block
+        let mut other = config.clone();
+        other.chain_id = "mainnet".to_string();
+        assert_ne!(config.hash(), other.hash());
+
+        let mut duplicated = config.clone();
+        duplicated.allocations.push(config.allocations[0].clone());
+        let err = GenesisConfig::from_toml(&duplicated.to_toml()).unwrap_err();
+        assert!(err.to_string().contains("more than once"));
+        assert!(GenesisConfig::from_toml(&toml.replace("seed_age = 20", "seed_age = 0")).is_err());
+        assert!(GenesisConfig::from_toml(&toml.replace("amount = 42", "amount = 0")).is_err());
+        assert!(GenesisConfig::from_toml(&toml.replace(&account.to_string(), "00")).is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    draw::{Draw, Seed},
+    keys::{SecretKey, Signature},
+    util::{BlockPtr, SerToBytes, Sha256Hash, Timeslot, hash},
+};
+
+/// Everything in a block except the transactions, which it commits to through `tx_root`.
+/// A header is enough to check who produced the block and that they won the lottery.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub timeslot: Timeslot,
+    pub prev_hash: Sha256Hash,
+    pub depth: i64,
+    pub tx_root: Sha256Hash,    // Merkle root of the transaction hashes
+    pub state_root: Sha256Hash, // State root of the ledger after the block is applied
+    pub draw: Draw,
+    pub signature: Signature,
+    pub hash: Sha256Hash,
+}
+
+impl BlockHeader {
+    pub fn new(
+        timeslot: Timeslot,
+        prev_hash: Sha256Hash,
+        depth: i64,
+        tx_root: Sha256Hash,
+        state_root: Sha256Hash,
+        sk: &SecretKey,
+        seed: Seed,
+    ) -> Self {
+        let draw = Draw::new(timeslot, seed, sk);
+        let hash = Self::calculate_hash(timeslot, prev_hash, depth, &draw, tx_root, state_root);
+        let signature = Signature::sign(sk, &hash);
+        Self {
+            timeslot,
+            prev_hash,
+            depth,
+            tx_root,
+            state_root,
+            draw,
+            signature,
+            hash,
+        }
+    }
+
+    fn calculate_hash(
+        timeslot: Timeslot,
+        prev_hash: Sha256Hash,
+        depth: i64,
+        draw: &Draw,
+        tx_root: Sha256Hash,
+        state_root: Sha256Hash,
+    ) -> Sha256Hash {
+        hash(&(timeslot, prev_hash, depth, draw, tx_root, state_root).into_bytes())
+    }
+
+    /// Checks the hash and that it is signed by the winner of the draw
+    pub fn verify_signature(&self) -> Result<()> {
+        let hash = Self::calculate_hash(
+            self.timeslot,
+            self.prev_hash,
+            self.depth,
+            &self.draw,
+            self.tx_root,
+            self.state_root,
+        );
+        if hash != self.hash {
+            return Err(anyhow!("Computed hash does not match provided hash"));
+        }
+
+        self.signature.verify(&self.draw.signed_by, &hash)
+    }
+
+    /// Checks that the draw is genuine and made for the timeslot of this header
+    pub fn verify_draw(&self) -> Result<()> {
+        if self.draw.timeslot != self.timeslot {
+            return Err(anyhow!("Draw is not for the timeslot of the block"));
+        }
+
+        self.draw.verify()
+    }
+
+    pub fn ptr(&self) -> BlockPtr {
+        BlockPtr::new(self.hash, self.depth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::EMPTY_ROOT;
+
+    #[test]
+    fn test_verify() {
+        let sk = SecretKey::generate();
+        let seed = Seed {
+            block_ptr: BlockPtr::new([0; 32], 0),
+        };
+        let header = BlockHeader::new(3, [1; 32], 1, EMPTY_ROOT, [0; 32], &sk, seed.clone());
+        header.verify_signature().unwrap();
+        header.verify_draw().unwrap();
+
+        let mut tampered = header.clone();
+        tampered.tx_root = [2; 32];
+        assert!(tampered.verify_signature().is_err());
+        let mut tampered = header.clone();
+        tampered.state_root = [2; 32];
+        assert!(tampered.verify_signature().is_err());
+
+        // A draw from another timeslot can't be reused, even when the header is signed again
+        let other_draw = Draw::new(4, seed, &sk);
+        let mut reused = header.clone();
+        reused.draw = other_draw;
+        assert!(reused.verify_draw().is_err());
+    }
+}
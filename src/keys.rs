@@ -1,15 +1,42 @@
-use std::hash::Hash;
+use std::{fmt::Display, hash::Hash, str::FromStr};
 
 use ed25519_dalek::{ed25519::signature::Signer, SigningKey};
 use ed25519_dalek::Verifier;
 use rand::{rng};
 use serde::{Deserialize, Serialize};
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use data_encoding::HEXLOWER;
 
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct PublicKey(ed25519_dalek::VerifyingKey);
 
+impl PublicKey {
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8; 32]) -> Result<Self> {
+        Ok(Self(ed25519_dalek::VerifyingKey::from_bytes(bytes)?))
+    }
+}
+
+impl Display for PublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", HEXLOWER.encode(self.0.as_bytes()))
+    }
+}
+
+impl FromStr for PublicKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = HEXLOWER.decode(s.as_bytes())?;
+        let bytes = bytes.try_into().map_err(|_| anyhow!("A public key must be 32 bytes"))?;
+        Self::from_bytes(&bytes)
+    }
+}
+
 impl From<ed25519_dalek::VerifyingKey> for PublicKey {
     fn from(value: ed25519_dalek::VerifyingKey) -> Self {
         Self(value)
@@ -39,6 +66,14 @@ impl SecretKey {
     pub fn generate() -> Self {
         SigningKey::generate(&mut rng()).into()
     }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8; 32]) -> Self {
+        SigningKey::from_bytes(bytes).into()
+    }
 }
 
 impl Hash for SecretKey {
@@ -55,7 +90,13 @@ impl From<ed25519_dalek::SigningKey> for SecretKey {
 
 impl From<iroh::SecretKey> for SecretKey {
     fn from(value: iroh::SecretKey) -> Self {
-        Self(value.secret().clone().into())
+        Self(value.secret().clone())
+    }
+}
+
+impl From<&SecretKey> for iroh::SecretKey {
+    fn from(value: &SecretKey) -> Self {
+        iroh::SecretKey::from_bytes(&value.0.to_bytes())
     }
 }
 
@@ -95,4 +136,11 @@ mod tests {
 
         verification.unwrap();
     }
+
+    #[test]
+    fn public_key_roundtrip() {
+        let pk = SecretKey::generate().get_public_key();
+        assert_eq!(pk.to_string().parse::<PublicKey>().unwrap(), pk);
+        assert!("not a key".parse::<PublicKey>().is_err());
+    }
 }
\ No newline at end of file
@@ -1,53 +1,105 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap};
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    blockchain::TRANSACTION_FEE, keys::PublicKey, transaction::Transaction, util::{MiniLas, Sha256Hash}
+    keys::PublicKey,
+    merkle::merkle_root,
+    smt::{SparseMerkleProof, SparseMerkleTree},
+    transaction::Transaction,
+    util::{hash, MiniLas, SerToBytes, Sha256Hash},
 };
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, ensure, Result};
 
-// You must have this much and h SEED_AGE blocks to be considered stakable
-pub const MINIMUM_STAKE_AMOUNT: MiniLas = 10_000000;
+#[derive(Clone, Copy, Default, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct Account {
+    pub balance: MiniLas,
+    pub nonce: u64, // The nonce the next transaction from the account must have
+}
 
 #[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
+#[serde(try_from = "StoredLedger")]
 pub struct Ledger {
-    pub map: HashMap<PublicKey, MiniLas>,
-    pub previous_transactions: HashSet<Sha256Hash>,
-    pub published_accounts: HashMap<PublicKey, i64>, // Maps to the depth where the account was published
+    // Keyed by the hash of the public key, an account with no balance and no nonce is not stored
+    accounts: SparseMerkleTree<Account>,
+    published_accounts: HashMap<PublicKey, i64>, // Maps to the depth where the account was published
     pub root_accounts: Vec<PublicKey>,
+    // You must have more than this for a seed age of blocks to be considered stakable
+    pub minimum_stake_amount: MiniLas,
+    // Kept up to date with the accounts, so the state root does not go over all of them
+    #[serde(skip)]
+    total_money: MiniLas,
+    #[serde(skip)]
+    published_root: Sha256Hash,
+}
+
+// What is stored of a ledger, the rest is computed again when loading
+#[derive(Deserialize)]
+struct StoredLedger {
+    accounts: SparseMerkleTree<Account>,
+    published_accounts: HashMap<PublicKey, i64>,
+    root_accounts: Vec<PublicKey>,
+    minimum_stake_amount: MiniLas,
+}
+
+impl TryFrom<StoredLedger> for Ledger {
+    type Error = anyhow::Error;
+
+    fn try_from(stored: StoredLedger) -> Result<Self> {
+        let total_money = stored
+            .accounts
+            .values()
+            .try_fold(0, |total: MiniLas, account| {
+                total.checked_add(account.balance)
+            })
+            .ok_or(anyhow!("Total money overflows"))?;
+        Ok(Self {
+            total_money,
+            published_root: published_root(&stored.published_accounts),
+            accounts: stored.accounts,
+            published_accounts: stored.published_accounts,
+            root_accounts: stored.root_accounts,
+            minimum_stake_amount: stored.minimum_stake_amount,
+        })
+    }
 }
 
 impl Ledger {
-    pub fn new(root_accounts: Vec<PublicKey>) -> Self {
+    pub fn new(root_accounts: Vec<PublicKey>, minimum_stake_amount: MiniLas) -> Self {
         let stakeable_accounts = root_accounts.iter().map(|ra| (ra.clone(), 0)).collect();
         Self {
-            map: Default::default(),
-            previous_transactions: Default::default(),
+            accounts: Default::default(),
+            published_root: published_root(&stakeable_accounts),
             published_accounts: stakeable_accounts,
             root_accounts,
+            minimum_stake_amount,
+            total_money: 0,
         }
     }
 
     pub fn is_transaction_valid(&self, transaction: &Transaction) -> Result<()> {
         transaction.verify_signature()?;        
         
-        let amount = transaction.amount;
-        
-        if amount < TRANSACTION_FEE {
-            return Err(anyhow!("Cannot send less than transaction fee. Tried to send {}, fee {}", transaction.amount, TRANSACTION_FEE));
-        }
+        let cost = total_cost(transaction)?;
 
         let from = &transaction.from;
 
         let from_balance = self.get_balance(from);
 
-        if from_balance < amount + TRANSACTION_FEE {
+        if from_balance < cost {
             return Err(anyhow!("Cannot send more than in account, including transaction fee"));
         }
 
-        if self.previous_transactions.contains(&transaction.hash) {
-            return Err(anyhow!("Transaction was executed previously"));
+        self.check_nonce(transaction)
+    }
+
+    fn check_nonce(&self, transaction: &Transaction) -> Result<()> {
+        let expected = self.get_nonce(&transaction.from);
+        if transaction.nonce < expected {
+            return Err(anyhow!("Nonce {} was used previously", transaction.nonce));
+        }
+        if transaction.nonce > expected {
+            return Err(anyhow!("Nonce {} is out of order, expected {expected}", transaction.nonce));
         }
 
         Ok(())
@@ -56,85 +108,432 @@ impl Ledger {
     pub fn process_transaction(&mut self, transaction: &Transaction) -> Result<()> {
         transaction.verify_signature()?;        
         
-        let amount = transaction.amount;
-        
-        if amount < TRANSACTION_FEE {
-            return Err(anyhow!("Cannot send less than transaction fee. Tried to send {}, fee {}", transaction.amount, TRANSACTION_FEE));
-        }
+        let cost = total_cost(transaction)?;
+
+        self.check_nonce(transaction)?;
 
         let from = &transaction.from;
         let to = &transaction.to;
 
-        self.add_acount_if_absent(from);
-        self.add_acount_if_absent(to);
+        let mut from_account = self.get_account(from);
 
-        let from_balance = self.map.get_mut(from).unwrap();
-
-        if *from_balance < amount + TRANSACTION_FEE {
+        if from_account.balance < cost {
             return Err(anyhow!("Cannot send more than in account, including transaction fee"));
         }
 
-        if !self.previous_transactions.insert(transaction.hash) {
-            return Err(anyhow!("Transaction was executed previously"));
+        from_account.balance -= cost;
+        from_account.nonce = transaction.nonce + 1;
+
+        // Taken from the updated sender, as it can send to itself. Nothing is changed until both are known
+        let mut to_account = if to == from {
+            from_account
+        } else {
+            self.get_account(to)
+        };
+        to_account.balance = to_account
+            .balance
+            .checked_add(transaction.amount)
+            .ok_or(anyhow!("Balance of {to} overflows"))?;
+        if to != from {
+            self.set_account(from, from_account);
         }
+        self.set_account(to, to_account);
 
-        *from_balance -= amount + TRANSACTION_FEE;
-        
-        let to_balance = self.map.get_mut(to).unwrap();
-        *to_balance += amount;
+        Ok(())
+    }
 
+    // Rewards are the only way money is created, so no balance overflows as long as the total does not
+    pub fn reward_winner(&mut self, winner: &PublicKey, amount: MiniLas) -> Result<()> {
+        ensure!(
+            self.total_money.checked_add(amount).is_some(),
+            "Total money overflows with the reward of {winner}"
+        );
+        let mut account = self.get_account(winner);
+        account.balance += amount;
+        self.set_account(winner, account);
         Ok(())
     }
 
-    pub fn rollback_transaction(&mut self, transaction: &Transaction, depth: i64) {
-        let from = &transaction.from;
-        let to = &transaction.to;
-        let amount = transaction.amount;
+    /// Applies the transactions of a block and rewards its winner, nothing is changed if a transaction fails.
+    /// The returned record undoes exactly this
+    pub fn apply_block(
+        &mut self,
+        transactions: &[Transaction],
+        winner: &PublicKey,
+        reward: MiniLas,
+    ) -> Result<UndoRecord> {
+        let mut record = UndoRecord::default();
+        for t in transactions {
+            record.save(self, &t.from);
+            record.save(self, &t.to);
+        }
+        record.save(self, winner);
 
-        let from_balance = self.map.get_mut(from).unwrap();
-        *from_balance += amount + TRANSACTION_FEE;
-        let to_balance = self.map.get_mut(to).unwrap();
-        *to_balance -= amount;
+        for t in transactions {
+            if let Err(e) = self.process_transaction(t) {
+                self.undo(&record);
+                return Err(e);
+            }
+        }
+        if let Err(e) = self.reward_winner(winner, reward) {
+            self.undo(&record);
+            return Err(e);
+        }
 
-        self.previous_transactions.remove(&transaction.hash);
+        Ok(record)
     }
 
-    pub fn reward_winner(&mut self, winner: &PublicKey, amount: MiniLas) {
-        self.map
-            .entry(winner.clone())
-            .and_modify(|minilas| *minilas += amount)
-            .or_insert(amount);
+    /// Puts every account touched by a block back to how it was before the block
+    pub fn undo(&mut self, record: &UndoRecord) {
+        for (account, state) in record.accounts.iter() {
+            self.set_account(account, *state);
+        }
     }
 
-    pub fn rollback_reward(&mut self, winner: &PublicKey, amount: MiniLas) {
-        self.add_acount_if_absent(winner);
-        let balance = self.map.get_mut(winner).unwrap();
-        *balance -= amount;
+    pub fn get_account(&self, account: &PublicKey) -> Account {
+        self.accounts.get(&account_key(account)).copied().unwrap_or_default()
     }
 
-    pub fn add_acount_if_absent(&mut self, account: &PublicKey) {
-        if !self.map.contains_key(account) {
-            self.map.insert(account.clone(), 0);
+    // Empty accounts are removed, so the ledger does not depend on which accounts have been seen
+    fn set_account(&mut self, account: &PublicKey, state: Account) {
+        // Wraps, as an undo restores the accounts in any order and can pass through a total that is out of range
+        self.total_money = self
+            .total_money
+            .wrapping_sub(self.get_balance(account))
+            .wrapping_add(state.balance);
+        if state == Account::default() {
+            self.accounts.remove(&account_key(account));
+        } else {
+            self.accounts.insert(account_key(account), state);
         }
     }
 
     pub fn get_balance(&self, account: &PublicKey) -> u64 {
-        *self.map.get(account).unwrap_or(&0)
+        self.get_account(account).balance
+    }
+
+    pub fn get_nonce(&self, account: &PublicKey) -> u64 {
+        self.get_account(account).nonce
     }
 
     pub fn can_stake(&self, account: &PublicKey) -> bool {
-        if self.root_accounts.contains(account) {
-            return true; // root accounts can stake immediately
+        can_stake_with(
+            &self.root_accounts,
+            account,
+            self.get_balance(account),
+            self.minimum_stake_amount,
+        )
+    }
+
+    pub fn get_total_money_in_ledger(&self) -> MiniLas {
+        self.total_money
+    }
+
+    /// Commitment to the accounts, the total money and the published accounts.
+    /// The total money is part of it so a light client can check the lottery with only a `BalanceProof`
+    pub fn state_root(&self) -> Sha256Hash {
+        state_root(
+            &self.accounts.root(),
+            self.get_total_money_in_ledger(),
+            &self.published_root,
+        )
+    }
+
+    /// Proof of the balance and nonce of an account, both are zero for an account not in the ledger
+    pub fn prove_balance(&self, account: &PublicKey) -> BalanceProof {
+        BalanceProof {
+            account: self.get_account(account),
+            total_money: self.get_total_money_in_ledger(),
+            accounts_root: self.accounts.root(),
+            published_root: self.published_root,
+            proof: self.accounts.prove(&account_key(account)),
         }
+    }
+}
+
+/// Whether an account with `balance` can stake, for when only the balance of the account is known
+pub fn can_stake_with(
+    root_accounts: &[PublicKey],
+    account: &PublicKey,
+    balance: MiniLas,
+    minimum_stake_amount: MiniLas,
+) -> bool {
+    if root_accounts.contains(account) {
+        return true; // root accounts can stake immediately
+    }
+
+    balance > minimum_stake_amount
+}
+
+/// The accounts touched by a block as they were before it was applied.
+/// An account the block created is saved as empty, so undoing removes it again
+#[derive(Clone, Default, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct UndoRecord {
+    accounts: HashMap<PublicKey, Account>,
+}
+
+impl UndoRecord {
+    // Only the first state is kept, later ones are already changed by the block
+    fn save(&mut self, ledger: &Ledger, account: &PublicKey) {
+        if !self.accounts.contains_key(account) {
+            self.accounts.insert(account.clone(), ledger.get_account(account));
+        }
+    }
+}
 
-        let Some(&money) = self.map.get(account) else {
-            return false;
+fn published_root(published_accounts: &HashMap<PublicKey, i64>) -> Sha256Hash {
+    let published = published_accounts
+        .iter()
+        .map(|(account, &depth)| (account.to_bytes(), depth))
+        .collect::<BTreeMap<_, _>>();
+    let published_leaves = published
+        .iter()
+        .map(|entry| hash(&entry.into_bytes()))
+        .collect::<Vec<_>>();
+    merkle_root(&published_leaves)
+}
+
+fn account_key(account: &PublicKey) -> Sha256Hash {
+    hash(&account.to_bytes())
+}
+
+fn state_root(
+    accounts_root: &Sha256Hash,
+    total_money: MiniLas,
+    published_root: &Sha256Hash,
+) -> Sha256Hash {
+    hash(&(accounts_root, total_money, published_root).into_bytes())
+}
+
+/// The state of an account in the ledger with a given state root
+#[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct BalanceProof {
+    pub account: Account,
+    pub total_money: MiniLas,
+    accounts_root: Sha256Hash,
+    published_root: Sha256Hash,
+    proof: SparseMerkleProof,
+}
+
+impl BalanceProof {
+    pub fn verify(&self, account: &PublicKey, state_root: &Sha256Hash) -> Result<()> {
+        ensure!(
+            self::state_root(&self.accounts_root, self.total_money, &self.published_root) == *state_root,
+            "Proof does not match the state root"
+        );
+
+        let value = (self.account != Account::default()).then_some(&self.account);
+        self.proof.verify(&account_key(account), value, &self.accounts_root)
+    }
+}
+
+// What the sender pays, the amount goes to the receiver and the fee to the winner of the block
+fn total_cost(transaction: &Transaction) -> Result<MiniLas> {
+    transaction
+        .amount
+        .checked_add(transaction.fee)
+        .ok_or(anyhow!("Amount plus fee overflows"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Las, keys::SecretKey, mempool::MIN_RELAY_FEE, params::ChainParams, util::FromBytes,
+    };
+
+    fn ledger_with_root(sk: &SecretKey) -> Ledger {
+        let mut ledger = Ledger::new(
+            vec![sk.get_public_key()],
+            ChainParams::devnet().minimum_stake_amount,
+        );
+        ledger
+            .reward_winner(&sk.get_public_key(), Las(100).into_minilas())
+            .unwrap();
+        ledger
+    }
+
+    #[test]
+    fn test_nonces_must_be_in_order() {
+        let sk = SecretKey::generate();
+        let to = SecretKey::generate().get_public_key();
+        let mut ledger = ledger_with_root(&sk);
+
+        let skipped = Transaction::new(&sk, to.clone(), Las(1), MIN_RELAY_FEE, 1);
+        assert!(ledger.is_transaction_valid(&skipped).is_err());
+        assert!(ledger.process_transaction(&skipped).is_err());
+
+        let first = Transaction::new(&sk, to.clone(), Las(1), MIN_RELAY_FEE, 0);
+        ledger.process_transaction(&first).unwrap();
+        assert_eq!(ledger.get_nonce(&sk.get_public_key()), 1);
+
+        // Neither the same transaction nor a new one with a used nonce can be executed
+        assert!(ledger.process_transaction(&first).is_err());
+        let reused = Transaction::new(&sk, to.clone(), Las(2), MIN_RELAY_FEE, 0);
+        assert!(ledger.is_transaction_valid(&reused).is_err());
+
+        ledger.process_transaction(&skipped).unwrap();
+        assert_eq!(ledger.get_nonce(&sk.get_public_key()), 2);
+    }
+
+    #[test]
+    fn test_undo_restores_nonce() {
+        let sk = SecretKey::generate();
+        let to = SecretKey::generate().get_public_key();
+        let mut ledger = ledger_with_root(&sk);
+        let initial_ledger = ledger.clone();
+
+        let first = Transaction::new(&sk, to.clone(), Las(1), MIN_RELAY_FEE, 0);
+        let second = Transaction::new(&sk, to.clone(), Las(1), MIN_RELAY_FEE, 1);
+        let first_record = ledger.apply_block(&[first], &sk.get_public_key(), 0).unwrap();
+        let after_first = ledger.clone();
+        let second_record = ledger
+            .apply_block(std::slice::from_ref(&second), &sk.get_public_key(), 0)
+            .unwrap();
+
+        ledger.undo(&second_record);
+        assert_eq!(ledger, after_first);
+        ledger.is_transaction_valid(&second).unwrap();
+
+        ledger.undo(&first_record);
+        assert_eq!(ledger.get_nonce(&sk.get_public_key()), 0);
+        assert_eq!(ledger, initial_ledger);
+    }
+
+    #[test]
+    fn test_failed_block_changes_nothing() {
+        let sk = SecretKey::generate();
+        let to = SecretKey::generate().get_public_key();
+        let winner = SecretKey::generate().get_public_key();
+        let mut ledger = ledger_with_root(&sk);
+        let initial_ledger = ledger.clone();
+
+        let valid = Transaction::new(&sk, to.clone(), Las(1), MIN_RELAY_FEE, 0);
+        let reused = Transaction::new(&sk, to.clone(), Las(1), MIN_RELAY_FEE, 0);
+        assert!(ledger.apply_block(&[valid.clone(), reused], &winner, 1).is_err());
+        assert_eq!(ledger, initial_ledger);
+
+        // The receiver and the winner are created by the block, and removed by the undo
+        let record = ledger.apply_block(&[valid], &winner, 1).unwrap();
+        assert_eq!(ledger.get_balance(&winner), 1);
+        ledger.undo(&record);
+        assert_eq!(ledger, initial_ledger);
+    }
+
+    #[test]
+    fn test_fee_is_debited() {
+        let sk = SecretKey::generate();
+        let to = SecretKey::generate().get_public_key();
+        let mut ledger = ledger_with_root(&sk);
+
+        let fee = Las(2).into_minilas();
+        let transaction = Transaction::new(&sk, to.clone(), Las(1), fee, 0);
+        let record = ledger.apply_block(&[transaction], &to, 0).unwrap();
+        assert_eq!(
+            ledger.get_balance(&sk.get_public_key()),
+            Las(97).into_minilas()
+        );
+        assert_eq!(ledger.get_balance(&to), Las(1).into_minilas());
+
+        ledger.undo(&record);
+        assert_eq!(
+            ledger.get_balance(&sk.get_public_key()),
+            Las(100).into_minilas()
+        );
+
+        // The fee counts towards what the account can afford
+        let too_expensive = Transaction::new(&sk, to.clone(), Las(99), Las(2).into(), 0);
+        assert!(ledger.is_transaction_valid(&too_expensive).is_err());
+        let overflowing = Transaction::new(&sk, to.clone(), u64::MAX, 1, 0);
+        assert!(ledger.process_transaction(&overflowing).is_err());
+    }
+
+    #[test]
+    fn test_credits_do_not_overflow() {
+        let sk = SecretKey::generate();
+        let rich = SecretKey::generate().get_public_key();
+        let mut ledger = ledger_with_root(&sk);
+        let room = MiniLas::MAX - ledger.get_total_money_in_ledger();
+        ledger.reward_winner(&rich, room).unwrap();
+        let accounts = |ledger: &Ledger| {
+            (
+                ledger.get_account(&sk.get_public_key()),
+                ledger.get_account(&rich),
+            )
         };
+        let before = accounts(&ledger);
 
-        money > MINIMUM_STAKE_AMOUNT
+        assert!(ledger.reward_winner(&rich, 1).is_err());
+        assert!(ledger.reward_winner(&sk.get_public_key(), 1).is_err());
+        let transaction = Transaction::new(&sk, rich.clone(), Las(1), MIN_RELAY_FEE, 0);
+        assert!(ledger.apply_block(&[transaction], &rich, MIN_RELAY_FEE + 1).is_err());
+        assert_eq!(accounts(&ledger), before);
     }
 
-    pub fn get_total_money_in_ledger(&self) -> MiniLas {
-        self.map.values().sum()
+    #[test]
+    fn test_state_root() {
+        let sk = SecretKey::generate();
+        let to = SecretKey::generate().get_public_key();
+        let mut ledger = ledger_with_root(&sk);
+        let initial_root = ledger.state_root();
+
+        let transaction = Transaction::new(&sk, to.clone(), Las(1), MIN_RELAY_FEE, 0);
+        let record = ledger.apply_block(&[transaction], &to, 0).unwrap();
+        let root = ledger.state_root();
+        assert_ne!(root, initial_root);
+
+        // The same state reached in another order has the same root
+        let mut other = Ledger::new(
+            vec![sk.get_public_key()],
+            ChainParams::devnet().minimum_stake_amount,
+        );
+        other.reward_winner(&to, Las(1).into_minilas()).unwrap();
+        other.set_account(
+            &sk.get_public_key(),
+            Account {
+                balance: ledger.get_balance(&sk.get_public_key()),
+                nonce: 1,
+            },
+        );
+        assert_eq!(other.state_root(), root);
+        let loaded = Ledger::from_bytes(&ledger.into_bytes()).unwrap();
+        assert_eq!(loaded, ledger);
+        assert_eq!(loaded.state_root(), root);
+
+        // The receiver is removed again by the undo
+        ledger.undo(&record);
+        assert_eq!(ledger.state_root(), initial_root);
+
+        ledger.reward_winner(&sk.get_public_key(), 1).unwrap();
+        assert_ne!(ledger.state_root(), initial_root);
+    }
+
+    #[test]
+    fn test_balance_proofs() {
+        let sk = SecretKey::generate();
+        let to = SecretKey::generate().get_public_key();
+        let mut ledger = ledger_with_root(&sk);
+        let transaction = Transaction::new(&sk, to.clone(), Las(1), MIN_RELAY_FEE, 0);
+        ledger.process_transaction(&transaction).unwrap();
+        let state_root = ledger.state_root();
+
+        let proof = ledger.prove_balance(&to);
+        assert_eq!(proof.account.balance, Las(1).into_minilas());
+        assert_eq!(proof.total_money, ledger.get_total_money_in_ledger());
+        proof.verify(&to, &state_root).unwrap();
+        assert!(proof.verify(&sk.get_public_key(), &state_root).is_err());
+
+        let mut inflated = proof.clone();
+        inflated.account.balance += 1;
+        assert!(inflated.verify(&to, &state_root).is_err());
+        let mut inflated = proof.clone();
+        inflated.total_money += 1;
+        assert!(inflated.verify(&to, &state_root).is_err());
+
+        // An unknown account is proven to have nothing
+        let unknown = SecretKey::generate().get_public_key();
+        let proof = ledger.prove_balance(&unknown);
+        assert_eq!(proof.account, Account::default());
+        proof.verify(&unknown, &state_root).unwrap();
     }
 }
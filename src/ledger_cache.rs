@@ -0,0 +1,26 @@
+use std::collections::HashMap;
+
+use crate::{ledger::Ledger, util::BlockPtr};
+
+/// Copies of ledgers at some blocks, so a ledger near one of them is found with a few blocks
+/// instead of replaying from far away
+#[derive(Clone, Default, Debug)]
+pub struct LedgerCache {
+    ledgers: HashMap<BlockPtr, Ledger>,
+}
+
+impl LedgerCache {
+    pub fn insert(&mut self, ptr: BlockPtr, ledger: Ledger) {
+        self.ledgers.insert(ptr, ledger);
+    }
+
+    /// Iterates over the cached ledgers and the block they are at, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&BlockPtr, &Ledger)> {
+        self.ledgers.iter()
+    }
+
+    /// Drops the ledgers at blocks below `depth`
+    pub fn prune_below(&mut self, depth: i64) {
+        self.ledgers.retain(|ptr, _| ptr.depth >= depth);
+    }
+}
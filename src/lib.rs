@@ -3,12 +3,27 @@ use serde::{Deserialize, Serialize};
 use crate::util::MiniLas;
 
 pub mod blockchain;
+pub mod mempool;
+pub mod orphan_pool;
 pub mod block;
+pub mod header;
 pub mod ledger;
+pub mod ledger_cache;
+pub mod merkle;
+pub mod smt;
+pub mod snapshot;
 pub mod transaction;
 pub mod keys;
+pub mod clock;
 pub mod draw;
+pub mod fork_choice;
+pub mod genesis;
+pub mod params;
 pub mod util;
+pub mod storage;
+pub mod sync;
+pub mod light_client;
+pub mod sim;
 pub mod actors;
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
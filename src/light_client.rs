@@ -0,0 +1,347 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Result, anyhow, ensure};
+
+use crate::{
+    blockchain::Blockchain,
+    clock::{Clock, SystemClock},
+    draw::Seed,
+    genesis::GenesisConfig,
+    header::BlockHeader,
+    keys::PublicKey,
+    ledger::{BalanceProof, can_stake_with},
+    util::{BlockPtr, Sha256Hash},
+};
+
+/// Follows the best chain with only the headers of the last `header_window` depths.
+/// Instead of a static ledger, every header comes with a `BalanceProof` of the stake of its producer,
+/// which is checked against the state root of the header the static ledger belongs to
+#[derive(Debug, Clone)]
+pub struct LightClient<C = SystemClock> {
+    config: GenesisConfig,
+    root_accounts: Vec<PublicKey>,
+    genesis: BlockHeader,
+    headers: BTreeMap<i64, HashMap<Sha256Hash, BlockHeader>>, // Every header except genesis, by depth
+    head: BlockPtr,
+    clock: C,
+}
+
+impl LightClient {
+    pub fn new(config: GenesisConfig, genesis: BlockHeader) -> Result<Self> {
+        ensure!(genesis.depth == 0, "Genesis must be at depth 0");
+        ensure!(
+            genesis.prev_hash == config.hash(),
+            "Seed hash does not match the genesis config"
+        );
+        ensure!(
+            genesis.state_root == Blockchain::genesis_ledger(&config).state_root(),
+            "Genesis state root does not match the genesis config"
+        );
+        genesis.verify_signature()?;
+
+        Ok(Self {
+            root_accounts: config.root_accounts(),
+            config,
+            head: genesis.ptr(),
+            genesis,
+            headers: Default::default(),
+            clock: SystemClock,
+        })
+    }
+}
+
+impl<C: Clock> LightClient<C> {
+    /// The same client, reading the time from `clock` to know which timeslots have been reached
+    pub fn with_clock<D: Clock>(self, clock: D) -> LightClient<D> {
+        let Self {
+            config,
+            root_accounts,
+            genesis,
+            headers,
+            head,
+            clock: _,
+        } = self;
+        LightClient {
+            config,
+            root_accounts,
+            genesis,
+            headers,
+            head,
+            clock,
+        }
+    }
+
+    pub fn best_head(&self) -> &BlockPtr {
+        &self.head
+    }
+
+    pub fn get_header(&self, ptr: &BlockPtr) -> Option<&BlockHeader> {
+        if ptr.depth == 0 {
+            return (ptr.hash == self.genesis.hash).then_some(&self.genesis);
+        }
+
+        self.headers.get(&ptr.depth)?.get(&ptr.hash)
+    }
+
+    /// The headers that are kept, including those of forks
+    pub fn len(&self) -> usize {
+        self.headers.values().map(HashMap::len).sum::<usize>() + 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        false // The genesis header is always kept
+    }
+
+    /// How far below the head a fork can branch off and still be followed
+    pub fn max_fork_depth(&self) -> i64 {
+        self.config.params.seed_age
+    }
+
+    /// Headers are kept this many depths below the head, enough to find the seed and static ledger of a fork
+    pub fn header_window(&self) -> i64 {
+        self.config.params.seed_age + 1 + self.max_fork_depth()
+    }
+
+    /// Whether the block is an ancestor of the best head, or the head itself
+    pub fn is_on_best_path(&self, ptr: &BlockPtr) -> bool {
+        self.ancestor_ptr(self.head.clone(), ptr.depth).as_ref() == Some(ptr)
+    }
+
+    /// Checks the state of an account in the ledger after the best head
+    pub fn verify_balance(&self, account: &PublicKey, proof: &BalanceProof) -> Result<()> {
+        let head = self.get_header(&self.head).expect("the head is kept");
+        proof.verify(account, &head.state_root)
+    }
+
+    /// Validates the header against its known parent, and that its producer won the lottery with
+    /// the stake in `stake_proof`. The header becomes the head if it is deeper than the current head
+    pub fn add_header(&mut self, header: BlockHeader, stake_proof: &BalanceProof) -> Result<()> {
+        header.verify_signature()?;
+        header.verify_draw()?;
+
+        if header.timeslot > self.config.current_timeslot(&self.clock) {
+            return Err(anyhow!("Invalid timeslot"));
+        }
+
+        let parent_ptr = BlockPtr::new(header.prev_hash, header.depth - 1);
+        let parent = self
+            .get_header(&parent_ptr)
+            .ok_or(anyhow!("Unknown parent"))?;
+        if header.timeslot <= parent.timeslot {
+            return Err(anyhow!("Invalid timeslot in relation to parents"));
+        }
+
+        self.check_seed(&header, &parent_ptr)?;
+
+        // The static ledger of the header is the ledger after the block a seed age + 1 blocks before it
+        let seed_age = self.config.params.seed_age;
+        let static_depth = (header.depth - seed_age - 1).max(0);
+        let static_header = self
+            .ancestor_ptr(parent_ptr, static_depth)
+            .and_then(|ptr| self.get_header(&ptr))
+            .ok_or(anyhow!("Fork is too deep to find the static ledger"))?;
+        let producer = &header.draw.signed_by;
+        stake_proof.verify(producer, &static_header.state_root)?;
+        ensure!(
+            can_stake_with(
+                &self.root_accounts,
+                producer,
+                stake_proof.account.balance,
+                self.config.params.minimum_stake_amount
+            ),
+            "Producer can not stake"
+        );
+        ensure!(
+            header.draw.is_winner(
+                stake_proof.account.balance,
+                stake_proof.total_money,
+                &self.config.params
+            ),
+            "Draw is not a winner"
+        );
+
+        let ptr = header.ptr();
+        self.headers
+            .entry(header.depth)
+            .or_default()
+            .insert(header.hash, header);
+
+        if ptr.depth > self.head.depth {
+            self.head = ptr;
+            self.prune();
+        }
+
+        Ok(())
+    }
+
+    fn check_seed(&self, header: &BlockHeader, parent_ptr: &BlockPtr) -> Result<()> {
+        let seed_age = self.config.params.seed_age;
+        let expected_seed = if header.depth < seed_age {
+            // Block is close to genesis and must have the same seed as the genesis block
+            self.genesis.draw.seed.clone()
+        } else {
+            let block_ptr = self
+                .ancestor_ptr(parent_ptr.clone(), header.depth - seed_age)
+                .ok_or(anyhow!("Fork is too deep to find the seed block"))?;
+            let seed = Seed { block_ptr };
+            ensure!(
+                seed.correct_age(header.depth, &self.config.params),
+                "Seed has the wrong age"
+            );
+            seed
+        };
+
+        if header.draw.seed != expected_seed {
+            return Err(anyhow!("seed mismatch"));
+        }
+
+        Ok(())
+    }
+
+    // Walks back from `ptr` through the kept headers to the block at `depth`
+    fn ancestor_ptr(&self, mut ptr: BlockPtr, depth: i64) -> Option<BlockPtr> {
+        while ptr.depth > depth {
+            let header = self.get_header(&ptr)?;
+            ptr = BlockPtr::new(header.prev_hash, ptr.depth - 1);
+        }
+
+        (ptr.depth == depth && self.get_header(&ptr).is_some()).then_some(ptr)
+    }
+
+    // Drops the headers that are too far below the head to be needed again
+    fn prune(&mut self) {
+        let first_depth = self.head.depth - self.header_window();
+        self.headers = self.headers.split_off(&first_depth);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Las, block::Block, clock::MockClock, keys::SecretKey, mempool::MIN_RELAY_FEE,
+        params::ChainParams, transaction::Transaction,
+    };
+
+    fn mine_blocks(blockchain: &mut Blockchain, sk: &SecretKey, n: usize) {
+        let receiver = SecretKey::generate().get_public_key();
+        for _ in 0..n {
+            let nonce = blockchain.dynamic_ledger.get_nonce(&sk.get_public_key());
+            let transaction = Transaction::new(sk, receiver.clone(), Las(1), MIN_RELAY_FEE, nonce);
+            blockchain.add_transaction(transaction).unwrap();
+            let block = blockchain.make_next_block(sk);
+            blockchain.add_block(block).unwrap();
+        }
+    }
+
+    fn follow<C: Clock>(
+        client: &mut LightClient<C>,
+        blockchain: &Blockchain,
+        from_depth: i64,
+    ) -> Result<()> {
+        for header in blockchain.get_headers(from_depth, usize::MAX) {
+            let proof = blockchain.prove_stake(&header)?;
+            client.add_header(header, &proof)?;
+        }
+        Ok(())
+    }
+
+    fn start(sk: &SecretKey) -> (Blockchain, LightClient) {
+        let genesis =
+            GenesisConfig::with_root_accounts(ChainParams::devnet(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, sk);
+        let client = LightClient::new(genesis.clone(), genesis_block.header.clone()).unwrap();
+        (Blockchain::start(genesis, genesis_block), client)
+    }
+
+    #[test]
+    fn test_follows_the_chain_with_bounded_memory() {
+        let sk = SecretKey::generate();
+        let (mut blockchain, mut client) = start(&sk);
+        // The client keeps up with the chain, as the full node does not keep blocks far below the final block
+        let header_window = client.header_window() as usize;
+        for _ in 0..4 {
+            let from_depth = blockchain.best_path_head().depth + 1;
+            mine_blocks(&mut blockchain, &sk, header_window / 2);
+            follow(&mut client, &blockchain, from_depth).unwrap();
+        }
+        assert_eq!(client.best_head(), blockchain.best_path_head());
+        assert_eq!(client.len(), header_window + 2);
+        assert!(client.is_on_best_path(&blockchain.best_path[header_window + 10]));
+
+        let proof = blockchain
+            .dynamic_ledger
+            .prove_balance(&sk.get_public_key());
+        client.verify_balance(&sk.get_public_key(), &proof).unwrap();
+        let stale = blockchain.static_ledger.prove_balance(&sk.get_public_key());
+        assert!(client.verify_balance(&sk.get_public_key(), &stale).is_err());
+    }
+
+    #[test]
+    fn test_switches_to_deeper_fork() {
+        let sk = SecretKey::generate();
+        let (mut blockchain, mut client) = start(&sk);
+        mine_blocks(&mut blockchain, &sk, 5);
+        let mut fork = blockchain.clone();
+        mine_blocks(&mut blockchain, &sk, 5);
+        mine_blocks(&mut fork, &sk, 7);
+
+        follow(&mut client, &blockchain, 1).unwrap();
+        assert_eq!(client.best_head(), blockchain.best_path_head());
+        follow(&mut client, &fork, 6).unwrap();
+        assert_eq!(client.best_head(), fork.best_path_head());
+        assert!(!client.is_on_best_path(blockchain.best_path_head()));
+    }
+
+    #[test]
+    fn test_rejects_headers_without_stake() {
+        let sk = SecretKey::generate();
+        let (mut blockchain, mut client) = start(&sk);
+        let block = blockchain.make_next_block(&sk);
+        let proof = blockchain.prove_stake(&block.header).unwrap();
+
+        // The proof of someone else's stake is not accepted
+        let outsider = SecretKey::generate();
+        let forged = Block::new(
+            block.header.timeslot,
+            block.header.prev_hash,
+            block.header.depth,
+            Vec::new(),
+            block.header.state_root,
+            &outsider,
+            block.header.draw.seed.clone(),
+        );
+        assert!(client.add_header(forged.header.clone(), &proof).is_err());
+        let outsider_proof = blockchain.prove_stake(&forged.header).unwrap();
+        assert!(client.add_header(forged.header, &outsider_proof).is_err());
+
+        let mut inflated = proof.clone();
+        inflated.account.balance *= 2;
+        assert!(client.add_header(block.header.clone(), &inflated).is_err());
+
+        client.add_header(block.header.clone(), &proof).unwrap();
+        blockchain.add_block(block).unwrap();
+        assert_eq!(client.best_head(), blockchain.best_path_head());
+    }
+
+    #[test]
+    fn test_rejects_headers_from_the_future() {
+        let sk = SecretKey::generate();
+        let (mut blockchain, client) = start(&sk);
+        let config = client.config.clone();
+        let clock = MockClock::new(config.start_time as u128);
+        let mut client = client.with_clock(clock.clone());
+
+        let block = blockchain.make_next_block(&sk);
+        let proof = blockchain.prove_stake(&block.header).unwrap();
+        assert!(config.current_timeslot(&clock) < block.header.timeslot);
+        assert!(client.add_header(block.header.clone(), &proof).is_err());
+
+        while config.current_timeslot(&clock) < block.header.timeslot {
+            clock.advance(config.params.slot_length as u128);
+        }
+        client.add_header(block.header.clone(), &proof).unwrap();
+        blockchain.add_block(block).unwrap();
+        assert_eq!(client.best_head(), blockchain.best_path_head());
+    }
+}
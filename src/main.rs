@@ -1,20 +1,589 @@
-use lasagna_blockchain::{actors::{clock_actor::{ClockActor, Subscribe}, print_actor::{self}}, util::START_TIME};
-use actix::Actor;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use actix::{Actor, Addr};
+use anyhow::{Context, Result, anyhow};
+use clap::{Parser, Subcommand};
+use data_encoding::HEXLOWER;
+use iroh::{NodeAddr, NodeId, RelayMode};
+use lasagna_blockchain::{
+    actors::{
+        blockchain_actor::{self, BlockchainActor},
+        clock_actor::{self, ClockActor},
+        network_actor::{
+            GetNeighbors, GetNodeAddr, NetworkActor, NetworkConfig, PublishTransaction,
+            SyncFromNeighbors,
+        },
+    },
+    block::Block,
+    blockchain::Blockchain,
+    clock::SystemClock,
+    genesis::GenesisConfig,
+    keys::{PublicKey, SecretKey},
+    params::ChainParams,
+    snapshot::ChainSnapshot,
+    storage::BlockStore,
+    transaction::Transaction,
+    util::{FromBytes, MiniLas, SerToBytes, Sha256Hash},
+};
+use serde::{Deserialize, Serialize};
+
+// The minimum relay fee, written in LAS
+const DEFAULT_FEE: &str = "0.01";
+
+#[derive(Parser)]
+#[command(name = "lasagna", about = "Lasagna blockchain node and wallet")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a full node
+    Node {
+        #[command(subcommand)]
+        command: NodeCommand,
+    },
+    /// Create the genesis block of a new network
+    Genesis {
+        #[command(subcommand)]
+        command: GenesisCommand,
+    },
+    /// Manage key files
+    Key {
+        #[command(subcommand)]
+        command: KeyCommand,
+    },
+    /// Create and broadcast transactions
+    Tx {
+        #[command(subcommand)]
+        command: TxCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum NodeCommand {
+    Run {
+        /// Directory of the block store, created from the genesis file if it does not exist
+        #[arg(long)]
+        data_dir: PathBuf,
+        /// Key used for staking and as the identity of the node
+        #[arg(long)]
+        key_file: PathBuf,
+        /// Needed the first time the node is started, afterwards the data dir must be of the same network
+        #[arg(long)]
+        genesis: Option<PathBuf>,
+        /// Start a new data dir from a snapshot instead of genesis, it must be of the network of the genesis file.
+        /// Without --expected-hash the snapshot is trusted as it is
+        #[arg(long, requires = "genesis")]
+        snapshot: Option<PathBuf>,
+        /// Hash of the snapshot from a trusted source, in hex, as printed by export-snapshot
+        #[arg(long, requires = "snapshot", value_parser = parse_hash)]
+        expected_hash: Option<Sha256Hash>,
+        #[command(flatten)]
+        network: NetworkArgs,
+    },
+    /// Write the chain at its final block to a file that new nodes can start from
+    ExportSnapshot {
+        #[arg(long)]
+        data_dir: PathBuf,
+        #[arg(long)]
+        out: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum GenesisCommand {
+    Create {
+        /// TOML file with the allocations, timing and consensus parameters of the network
+        #[arg(
+            long,
+            required_unless_present = "root_accounts",
+            conflicts_with = "root_accounts"
+        )]
+        config: Option<PathBuf>,
+        /// Instead of a config, public keys of the accounts that start with the root amount and can stake
+        /// immediately, the rest of the config is the default
+        #[arg(long = "root")]
+        root_accounts: Vec<PublicKey>,
+        /// Parameters of a network made from root accounts: mainnet, testnet or devnet
+        #[arg(
+            long,
+            value_parser = ChainParams::preset,
+            default_value = "mainnet",
+            conflicts_with = "config"
+        )]
+        params: ChainParams,
+        /// Key used to sign the genesis block, does not have to be a root account
+        #[arg(long)]
+        key_file: PathBuf,
+        #[arg(long)]
+        out: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeyCommand {
+    Generate {
+        #[arg(long)]
+        out: PathBuf,
+    },
+    Show {
+        #[arg(long)]
+        key_file: PathBuf,
+    },
+}
+
+#[derive(Subcommand)]
+enum TxCommand {
+    Send {
+        #[arg(long)]
+        key_file: PathBuf,
+        #[arg(long)]
+        to: PublicKey,
+        /// Amount in LAS, up to 6 decimals
+        #[arg(long, value_parser = parse_las)]
+        amount: MiniLas,
+        /// Fee in LAS paid to the block producer, a higher fee gets the transaction included sooner
+        #[arg(long, value_parser = parse_las, default_value = DEFAULT_FEE)]
+        fee: MiniLas,
+        /// Number of transactions previously sent from this key, they must be executed in order
+        #[arg(long)]
+        nonce: u64,
+        #[arg(long)]
+        genesis: PathBuf,
+        #[command(flatten)]
+        network: NetworkArgs,
+    },
+}
+
+#[derive(clap::Args)]
+struct NetworkArgs {
+    /// Peers to connect to, given as <node id>@<ip:port>
+    #[arg(long = "bootstrap", value_parser = parse_peer)]
+    bootstrap: Vec<NodeAddr>,
+    #[arg(long, default_value_t = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))]
+    listen: SocketAddrV4,
+    /// Only connect directly to peers
+    #[arg(long)]
+    no_relay: bool,
+}
+
+impl NetworkArgs {
+    fn into_config(self, sk: SecretKey) -> NetworkConfig {
+        NetworkConfig {
+            bind_addr: self.listen,
+            bootstrap: self.bootstrap,
+            relay_mode: if self.no_relay {
+                RelayMode::Disabled
+            } else {
+                RelayMode::Default
+            },
+            ..NetworkConfig::new(sk)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct GenesisFile {
+    config: GenesisConfig,
+    block: Block,
+}
 
 #[actix::main]
-async fn main() {
+async fn main() -> Result<()> {
+    match Cli::parse().command {
+        Command::Node {
+            command:
+                NodeCommand::Run {
+                    data_dir,
+                    key_file,
+                    genesis,
+                    snapshot,
+                    expected_hash,
+                    network,
+                },
+        } => {
+            run_node(
+                data_dir,
+                key_file,
+                genesis,
+                snapshot,
+                expected_hash,
+                network,
+            )
+            .await
+        }
+        Command::Node {
+            command: NodeCommand::ExportSnapshot { data_dir, out },
+        } => {
+            let (_, blockchain) = BlockStore::open(&data_dir)?;
+            let snapshot = blockchain.snapshot()?;
+            snapshot.write(&out)?;
+            println!(
+                "Exported snapshot at depth {} with hash {}",
+                snapshot.final_ptr().depth,
+                HEXLOWER.encode(&snapshot.hash())
+            );
+            Ok(())
+        }
+        Command::Genesis {
+            command:
+                GenesisCommand::Create {
+                    config,
+                    root_accounts,
+                    params,
+                    key_file,
+                    out,
+                },
+        } => {
+            let sk = read_key(&key_file)?;
+            let config = match config {
+                Some(path) => GenesisConfig::read(&path)?,
+                None => GenesisConfig::with_root_accounts(params, root_accounts),
+            };
+            config.validate()?;
+            let block = Blockchain::produce_genesis_block(&config, &sk);
+            println!(
+                "Genesis block {} of chain {}",
+                HEXLOWER.encode(&block.header.hash),
+                config.chain_id
+            );
+            let genesis = GenesisFile { config, block };
+            fs::write(out, genesis.into_bytes())?;
+            Ok(())
+        }
+        Command::Key {
+            command: KeyCommand::Generate { out },
+        } => {
+            let sk = SecretKey::generate();
+            write_key(&out, &sk)?;
+            println!("Public key {}", sk.get_public_key());
+            Ok(())
+        }
+        Command::Key {
+            command: KeyCommand::Show { key_file },
+        } => {
+            let sk = read_key(&key_file)?;
+            println!("Public key {}", sk.get_public_key());
+            println!("Node id {}", iroh::SecretKey::from(&sk).public());
+            Ok(())
+        }
+        Command::Tx {
+            command:
+                TxCommand::Send {
+                    key_file,
+                    to,
+                    amount,
+                    fee,
+                    nonce,
+                    genesis,
+                    network,
+                },
+        } => {
+            let sk = read_key(&key_file)?;
+            let genesis = read_genesis(&genesis)?;
+            let transaction = Transaction::new(&sk, to, amount, fee, nonce);
+            send_transaction(
+                transaction,
+                genesis,
+                network.into_config(SecretKey::generate()),
+            )
+            .await
+        }
+    }
+}
+
+async fn run_node(
+    data_dir: PathBuf,
+    key_file: PathBuf,
+    genesis: Option<PathBuf>,
+    snapshot: Option<PathBuf>,
+    expected_hash: Option<Sha256Hash>,
+    network: NetworkArgs,
+) -> Result<()> {
+    let sk = read_key(&key_file)?;
+    let genesis = genesis.map(|path| read_genesis(&path)).transpose()?;
+    let snapshot = snapshot
+        .map(|path| ChainSnapshot::read(&path))
+        .transpose()?;
+    if let Some(snapshot) = &snapshot {
+        match expected_hash {
+            Some(expected_hash) => snapshot.check_hash(&expected_hash)?,
+            None => println!("Trusting the snapshot as it is, as no --expected-hash is given"),
+        }
+    }
+
+    let (store, blockchain) = open_store(&data_dir, genesis, snapshot)?;
+    let genesis_hash = blockchain.best_path[0].hash;
+    let genesis = blockchain.genesis.clone();
+    println!(
+        "Starting at depth {} with public key {}",
+        blockchain.best_path_head().depth,
+        sk.get_public_key()
+    );
+
+    let blockchain_actor = BlockchainActor::new(blockchain, sk.clone())
+        .with_store(store)
+        .start();
+
+    let has_bootstrap = !network.bootstrap.is_empty();
+    let network_actor = NetworkActor::spawn(
+        network.into_config(sk),
+        genesis_hash,
+        blockchain_actor.clone(),
+    )
+    .await?;
+    blockchain_actor.do_send(blockchain_actor::Subscribe(
+        network_actor.clone().recipient(),
+    ));
+    blockchain_actor.do_send(blockchain_actor::SubscribeTransactions(
+        network_actor.clone().recipient(),
+    ));
+    blockchain_actor.do_send(blockchain_actor::SubscribeOrphans(
+        network_actor.clone().recipient(),
+    ));
+    for peer in format_peers(&network_actor.send(GetNodeAddr).await?) {
+        println!("Listening as {peer}");
+    }
+
+    // Catch up before producing blocks, otherwise we would only make blocks on top of an old head
+    if has_bootstrap {
+        wait_for_neighbors(&network_actor).await?;
+        let added = network_actor.send(SyncFromNeighbors).await??;
+        println!("Synced {added} blocks");
+    }
+
     let clock_actor = ClockActor::new().start();
-    tokio::spawn(ClockActor::run_loop(clock_actor.clone(), START_TIME));
+    tokio::spawn(ClockActor::run_loop(
+        clock_actor.clone(),
+        genesis.start_time as u128,
+        genesis.params.clone(),
+        SystemClock,
+    ));
+    clock_actor.do_send(clock_actor::Subscribe(blockchain_actor.recipient()));
 
-    let print_actor = print_actor::PrintActor.start();
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+/// Opens the data dir, or creates it from the genesis file and the snapshot if there is one
+fn open_store(
+    data_dir: &Path,
+    genesis: Option<GenesisFile>,
+    snapshot: Option<ChainSnapshot>,
+) -> Result<(BlockStore, Blockchain)> {
+    if BlockStore::exists(data_dir) {
+        if snapshot.is_some() {
+            return Err(anyhow!("--snapshot can only be used for a new data dir"));
+        }
+        let (store, blockchain) = BlockStore::open(data_dir)?;
+        if let Some(genesis) = genesis
+            && genesis.block.ptr() != blockchain.best_path[0]
+        {
+            return Err(anyhow!(
+                "{} is of another network than the genesis file",
+                data_dir.display()
+            ));
+        }
+        return Ok((store, blockchain));
+    }
+
+    let genesis = genesis.ok_or(anyhow!("--genesis is required for a new data dir"))?;
+    match snapshot {
+        Some(snapshot) => BlockStore::create_from_snapshot(data_dir, snapshot, &genesis.block),
+        None => BlockStore::create(data_dir, genesis.config, genesis.block),
+    }
+}
+
+async fn send_transaction(
+    transaction: Transaction,
+    genesis: GenesisFile,
+    config: NetworkConfig,
+) -> Result<()> {
+    let genesis_hash = genesis.block.header.hash;
+    let blockchain = Blockchain::start(genesis.config, genesis.block);
+    // Never subscribed to a clock, it only exists to receive whatever the peers gossip
+    let blockchain_actor = BlockchainActor::new(blockchain, SecretKey::generate()).start();
+    let network_actor = NetworkActor::spawn(config, genesis_hash, blockchain_actor).await?;
+    wait_for_neighbors(&network_actor).await?;
+
+    let hash = transaction.hash;
+    network_actor
+        .send(PublishTransaction(transaction))
+        .await??;
+    // Give the gossip a moment to leave before the endpoint is closed
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    println!("Sent transaction {}", HEXLOWER.encode(&hash));
+    Ok(())
+}
+
+async fn wait_for_neighbors(network_actor: &Addr<NetworkActor>) -> Result<()> {
+    tokio::time::timeout(Duration::from_secs(30), async {
+        while network_actor.send(GetNeighbors).await?.is_empty() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        anyhow::Ok(())
+    })
+    .await
+    .context("Unable to connect to any peer")?
+}
+
+fn read_key(path: &Path) -> Result<SecretKey> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Unable to read key file {}", path.display()))?;
+    let bytes = HEXLOWER.decode(content.trim().as_bytes())?;
+    let bytes = bytes
+        .try_into()
+        .map_err(|_| anyhow!("A secret key must be 32 bytes"))?;
+    Ok(SecretKey::from_bytes(&bytes))
+}
+
+// Only the owner can read the key, and an existing key is never overwritten
+fn write_key(path: &Path, sk: &SecretKey) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Unable to create key file {}", path.display()))?;
+    file.write_all(HEXLOWER.encode(&sk.to_bytes()).as_bytes())?;
+    Ok(())
+}
 
-    clock_actor.do_send(Subscribe(print_actor.recipient()));
+fn read_genesis(path: &Path) -> Result<GenesisFile> {
+    let bytes = fs::read(path)
+        .with_context(|| format!("Unable to read genesis file {}", path.display()))?;
+    GenesisFile::from_bytes(&bytes)
+}
+
+fn parse_hash(s: &str) -> Result<Sha256Hash> {
+    HEXLOWER
+        .decode(s.as_bytes())?
+        .try_into()
+        .map_err(|_| anyhow!("Expected a hash of 32 bytes"))
+}
+
+fn parse_peer(s: &str) -> Result<NodeAddr> {
+    let (node_id, addr) = s
+        .split_once('@')
+        .ok_or(anyhow!("Expected <node id>@<ip:port>"))?;
+    let node_id: NodeId = node_id.parse()?;
+    let addr: SocketAddr = addr.parse()?;
+    Ok(NodeAddr::from_parts(node_id, None, [addr]))
+}
+
+fn format_peers(node_addr: &NodeAddr) -> Vec<String> {
+    node_addr
+        .direct_addresses()
+        .map(|addr| format!("{}@{addr}", node_addr.node_id))
+        .collect()
+}
 
-    tokio::signal::ctrl_c().await.unwrap();
+fn parse_las(s: &str) -> Result<MiniLas> {
+    let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+    if fraction.len() > 6 {
+        return Err(anyhow!("LAS has at most 6 decimals"));
+    }
+    let whole: MiniLas = whole.parse()?;
+    let fraction: MiniLas = if fraction.is_empty() {
+        0
+    } else {
+        format!("{fraction:0<6}").parse()?
+    };
+    whole
+        .checked_mul(1_000_000)
+        .and_then(|minilas| minilas.checked_add(fraction))
+        .ok_or(anyhow!("Amount is too large"))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn test_parse_las() {
+        assert_eq!(parse_las("2").unwrap(), 2_000000);
+        assert_eq!(parse_las("1.5").unwrap(), 1_500000);
+        assert_eq!(parse_las("0.000001").unwrap(), 1);
+        assert!(parse_las("0.0000001").is_err());
+        assert!(parse_las("abc").is_err());
+        assert_eq!(
+            parse_las(DEFAULT_FEE).unwrap(),
+            lasagna_blockchain::mempool::MIN_RELAY_FEE
+        );
+    }
 
+    #[test]
+    fn test_parse_peer() {
+        let node_id = iroh::SecretKey::from(&SecretKey::generate()).public();
+        let node_addr = parse_peer(&format!("{node_id}@127.0.0.1:4455")).unwrap();
+        assert_eq!(node_addr.node_id, node_id);
+        assert_eq!(
+            format_peers(&node_addr),
+            vec![format!("{node_id}@127.0.0.1:4455")]
+        );
+        assert!(parse_peer("127.0.0.1:4455").is_err());
+    }
 
+    #[test]
+    fn test_parse_hash() {
+        let hash = [7; 32];
+        assert_eq!(parse_hash(&HEXLOWER.encode(&hash)).unwrap(), hash);
+        assert!(parse_hash(&HEXLOWER.encode(&hash[1..])).is_err());
+        assert!(parse_hash("not hex").is_err());
+    }
 
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("lasagna-{name}-{}", rand::random::<u64>()))
+    }
 
+    #[test]
+    fn test_key_file() {
+        let path = temp_path("key");
+        let sk = SecretKey::generate();
+        write_key(&path, &sk).unwrap();
+        assert_eq!(read_key(&path).unwrap().to_bytes(), sk.to_bytes());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        assert!(write_key(&path, &SecretKey::generate()).is_err());
+        assert_eq!(read_key(&path).unwrap().to_bytes(), sk.to_bytes());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_data_dir_is_of_the_genesis() {
+        let genesis_file = |sk: &SecretKey| {
+            let config =
+                GenesisConfig::with_root_accounts(ChainParams::devnet(), vec![sk.get_public_key()]);
+            let block = Blockchain::produce_genesis_block(&config, sk);
+            GenesisFile { config, block }
+        };
+        let sk = SecretKey::generate();
+        let data_dir = temp_path("data");
+
+        assert!(open_store(&data_dir, None, None).is_err());
+        open_store(&data_dir, Some(genesis_file(&sk)), None).unwrap();
+        open_store(&data_dir, None, None).unwrap();
+        open_store(&data_dir, Some(genesis_file(&sk)), None).unwrap();
+
+        let err = open_store(&data_dir, Some(genesis_file(&SecretKey::generate())), None)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("another network"), "{err}");
+
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
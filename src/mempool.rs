@@ -0,0 +1,474 @@
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+
+use anyhow::{Result, anyhow, ensure};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    keys::PublicKey,
+    ledger::Ledger,
+    transaction::Transaction,
+    util::{MiniLas, SerToBytes},
+};
+
+pub const MEMPOOL_CAPACITY: usize = 10_000;
+// Transactions paying less are valid in a block but are not accepted or relayed by this node
+#[allow(clippy::zero_prefixed_literal)]
+pub const MIN_RELAY_FEE: MiniLas = 0_010000;
+
+/// Pending transactions, ordered by nonce for each sender and by fee across senders.
+/// Transactions with a nonce after a gap are kept until the missing ones arrive.
+#[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct Mempool {
+    by_sender: HashMap<PublicKey, BTreeMap<u64, Transaction>>,
+    len: usize,
+    capacity: usize,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new(MEMPOOL_CAPACITY)
+    }
+}
+
+impl Mempool {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            by_sender: Default::default(),
+            len: 0,
+            capacity,
+        }
+    }
+
+    /// Adds a transaction that can be executed on `ledger` now or after the senders other pending transactions.
+    /// A pending transaction is replaced by one with the same nonce that pays a higher fee.
+    pub fn insert(&mut self, transaction: Transaction, ledger: &Ledger) -> Result<()> {
+        transaction.verify_signature()?;
+        ensure!(
+            transaction.fee >= MIN_RELAY_FEE,
+            "Fee {} is below the minimum relay fee {MIN_RELAY_FEE}",
+            transaction.fee
+        );
+
+        let next_nonce = ledger.get_nonce(&transaction.from);
+        if transaction.nonce < next_nonce {
+            return Err(anyhow!("Nonce {} was used previously", transaction.nonce));
+        }
+        if transaction.nonce == next_nonce {
+            ledger.is_transaction_valid(&transaction)?;
+        }
+
+        let pending = self.by_sender.get(&transaction.from);
+        let replaced = pending.and_then(|pending| pending.get(&transaction.nonce));
+        if let Some(replaced) = replaced
+            && replaced.fee >= transaction.fee
+        {
+            return Err(anyhow!(
+                "A transaction with nonce {} and at least the same fee is already pending",
+                transaction.nonce
+            ));
+        }
+
+        let pending_cost = pending
+            .into_iter()
+            .flat_map(|pending| pending.values())
+            .filter(|pending| Some(*pending) != replaced)
+            .map(cost)
+            .fold(0, MiniLas::saturating_add);
+        if ledger.get_balance(&transaction.from) < pending_cost.saturating_add(cost(&transaction)) {
+            return Err(anyhow!("Pending transactions spend more than in account"));
+        }
+
+        let hash = transaction.hash;
+        self.insert_unchecked(transaction);
+
+        if self.len > self.capacity {
+            let evicted = self.evict().expect("mempool over capacity cannot be empty");
+            if evicted.hash == hash {
+                return Err(anyhow!("Mempool is full"));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds a transaction without checks, it must be followed by `revalidate`.
+    /// Used when transactions come back from a rolled back block.
+    pub fn insert_unchecked(&mut self, transaction: Transaction) {
+        let previous = self
+            .by_sender
+            .entry(transaction.from.clone())
+            .or_default()
+            .insert(transaction.nonce, transaction);
+        if previous.is_none() {
+            self.len += 1;
+        }
+    }
+
+    pub fn remove(&mut self, transaction: &Transaction) -> Option<Transaction> {
+        let pending = self.by_sender.get_mut(&transaction.from)?;
+        if pending.get(&transaction.nonce) != Some(transaction) {
+            return None;
+        }
+
+        let removed = pending.remove(&transaction.nonce);
+        if pending.is_empty() {
+            self.by_sender.remove(&transaction.from);
+        }
+        self.len -= 1;
+        removed
+    }
+
+    pub fn contains(&self, transaction: &Transaction) -> bool {
+        self.by_sender
+            .get(&transaction.from)
+            .and_then(|pending| pending.get(&transaction.nonce))
+            == Some(transaction)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Transaction> {
+        self.by_sender.values().flat_map(|pending| pending.values())
+    }
+
+    /// Drops the transactions that can no longer be executed on `ledger`.
+    /// Must be called whenever the ledger changes, i.e. on a new best head or a rollback.
+    pub fn revalidate(&mut self, ledger: &Ledger) {
+        for (sender, pending) in self.by_sender.iter_mut() {
+            let next_nonce = ledger.get_nonce(sender);
+            *pending = pending.split_off(&next_nonce);
+
+            // A transaction the sender can't afford blocks every later nonce as well
+            let mut balance = ledger.get_balance(sender);
+            let mut unaffordable = None;
+            for (expected_nonce, (&nonce, transaction)) in (next_nonce..).zip(pending.iter()) {
+                if nonce != expected_nonce {
+                    break;
+                }
+                if balance < cost(transaction) {
+                    unaffordable = Some(nonce);
+                    break;
+                }
+                balance -= cost(transaction);
+            }
+            if let Some(nonce) = unaffordable {
+                pending.split_off(&nonce);
+            }
+        }
+
+        self.by_sender.retain(|_, pending| !pending.is_empty());
+        self.len = self.by_sender.values().map(BTreeMap::len).sum();
+    }
+
+    /// Picks up to `max_transactions` transactions, serialized into at most `max_bytes`,
+    /// that can be executed in the returned order on `ledger`.
+    /// Takes the highest fee among the next transaction of every sender.
+    pub fn select(
+        &self,
+        ledger: &Ledger,
+        max_transactions: usize,
+        max_bytes: usize,
+    ) -> Vec<Transaction> {
+        let mut ledger = ledger.clone();
+        let mut candidates = BinaryHeap::new();
+        let push_next = |candidates: &mut BinaryHeap<_>, ledger: &Ledger, sender: &PublicKey| {
+            let next = self
+                .by_sender
+                .get(sender)
+                .and_then(|pending| pending.get(&ledger.get_nonce(sender)));
+            if let Some(transaction) = next {
+                candidates.push(Priority(transaction));
+            }
+        };
+
+        for sender in self.by_sender.keys() {
+            push_next(&mut candidates, &ledger, sender);
+        }
+
+        let mut selected = Vec::new();
+        let mut remaining_bytes = max_bytes;
+        while let Some(Priority(transaction)) = candidates.pop() {
+            if selected.len() >= max_transactions {
+                break;
+            }
+            // A smaller transaction from another sender might still fit
+            let size = transaction.into_bytes().len();
+            if size > remaining_bytes || ledger.process_transaction(transaction).is_err() {
+                continue;
+            }
+            remaining_bytes -= size;
+            selected.push(transaction.clone());
+            push_next(&mut candidates, &ledger, &transaction.from);
+        }
+
+        selected
+    }
+
+    // Removes the pending transaction least likely to be included: the lowest fee, furthest in the future
+    fn evict(&mut self) -> Option<Transaction> {
+        let victim = self
+            .by_sender
+            .values()
+            .filter_map(|pending| pending.values().next_back())
+            .min_by_key(|transaction| {
+                (
+                    transaction.fee,
+                    std::cmp::Reverse(transaction.nonce),
+                    transaction.hash,
+                )
+            })?
+            .clone();
+
+        self.remove(&victim)
+    }
+}
+
+fn cost(transaction: &Transaction) -> MiniLas {
+    transaction.amount.saturating_add(transaction.fee)
+}
+
+// Highest fee first, ties are broken by hash so every node selects the same way
+struct Priority<'a>(&'a Transaction);
+
+impl PartialEq for Priority<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.hash == other.0.hash
+    }
+}
+
+impl Eq for Priority<'_> {}
+
+impl PartialOrd for Priority<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Priority<'_> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0
+            .fee
+            .cmp(&other.0.fee)
+            .then_with(|| other.0.hash.cmp(&self.0.hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Las, keys::SecretKey, params::ChainParams};
+
+    fn ledger_with_roots(sks: &[&SecretKey]) -> Ledger {
+        let root_accounts = sks.iter().map(|sk| sk.get_public_key()).collect::<Vec<_>>();
+        let mut ledger = Ledger::new(
+            root_accounts.clone(),
+            ChainParams::devnet().minimum_stake_amount,
+        );
+        for pk in root_accounts.iter() {
+            ledger.reward_winner(pk, Las(10).into_minilas()).unwrap();
+        }
+        ledger
+    }
+
+    #[test]
+    fn test_select_orders_by_nonce() {
+        let sk1 = SecretKey::generate();
+        let sk2 = SecretKey::generate();
+        let to = SecretKey::generate().get_public_key();
+        let ledger = ledger_with_roots(&[&sk1, &sk2]);
+        let mut mempool = Mempool::default();
+
+        // Inserted out of order, the later nonces wait for the first one
+        for nonce in (0..3).rev() {
+            mempool
+                .insert(
+                    Transaction::new(&sk1, to.clone(), Las(1), MIN_RELAY_FEE, nonce),
+                    &ledger,
+                )
+                .unwrap();
+        }
+        mempool
+            .insert(
+                Transaction::new(&sk2, to.clone(), Las(1), MIN_RELAY_FEE, 0),
+                &ledger,
+            )
+            .unwrap();
+        assert_eq!(mempool.len(), 4);
+
+        let selected = mempool.select(&ledger, usize::MAX, usize::MAX);
+        assert_eq!(selected.len(), 4);
+        let sk1_nonces = selected
+            .iter()
+            .filter(|t| t.from == sk1.get_public_key())
+            .map(|t| t.nonce)
+            .collect::<Vec<_>>();
+        assert_eq!(sk1_nonces, vec![0, 1, 2]);
+
+        let mut ledger = ledger.clone();
+        for t in selected.iter() {
+            ledger.process_transaction(t).unwrap();
+        }
+
+        assert_eq!(mempool.select(&ledger, 2, usize::MAX).len(), 0);
+        mempool.revalidate(&ledger);
+        assert!(mempool.is_empty());
+    }
+
+    #[test]
+    fn test_gap_is_not_selected() {
+        let sk = SecretKey::generate();
+        let to = SecretKey::generate().get_public_key();
+        let ledger = ledger_with_roots(&[&sk]);
+        let mut mempool = Mempool::default();
+
+        mempool
+            .insert(
+                Transaction::new(&sk, to.clone(), Las(1), MIN_RELAY_FEE, 1),
+                &ledger,
+            )
+            .unwrap();
+        assert!(mempool.select(&ledger, usize::MAX, usize::MAX).is_empty());
+
+        mempool
+            .insert(
+                Transaction::new(&sk, to.clone(), Las(1), MIN_RELAY_FEE, 0),
+                &ledger,
+            )
+            .unwrap();
+        assert_eq!(mempool.select(&ledger, usize::MAX, usize::MAX).len(), 2);
+        assert_eq!(mempool.select(&ledger, 1, usize::MAX).len(), 1);
+        let size = mempool.iter().next().unwrap().into_bytes().len();
+        assert_eq!(mempool.select(&ledger, usize::MAX, 2 * size - 1).len(), 1);
+    }
+
+    #[test]
+    fn test_overdraft_is_rejected() {
+        let sk = SecretKey::generate();
+        let to = SecretKey::generate().get_public_key();
+        let ledger = ledger_with_roots(&[&sk]);
+        let mut mempool = Mempool::default();
+
+        mempool
+            .insert(
+                Transaction::new(&sk, to.clone(), Las(6), MIN_RELAY_FEE, 0),
+                &ledger,
+            )
+            .unwrap();
+        assert!(
+            mempool
+                .insert(
+                    Transaction::new(&sk, to.clone(), Las(6), MIN_RELAY_FEE, 1),
+                    &ledger
+                )
+                .is_err()
+        );
+        assert!(
+            mempool
+                .insert(
+                    Transaction::new(&sk, to.clone(), Las(2), MIN_RELAY_FEE, 0),
+                    &ledger
+                )
+                .is_err()
+        );
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn test_revalidate_after_balance_change() {
+        let sk = SecretKey::generate();
+        let to = SecretKey::generate().get_public_key();
+        let mut ledger = ledger_with_roots(&[&sk]);
+        let mut mempool = Mempool::default();
+
+        for nonce in 0..3 {
+            mempool
+                .insert(
+                    Transaction::new(&sk, to.clone(), Las(2), MIN_RELAY_FEE, nonce),
+                    &ledger,
+                )
+                .unwrap();
+        }
+
+        // A competing transaction with nonce 0 was executed instead, now only one more transaction fits
+        ledger
+            .process_transaction(&Transaction::new(&sk, to.clone(), Las(7), MIN_RELAY_FEE, 0))
+            .unwrap();
+        mempool.revalidate(&ledger);
+
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.iter().next().unwrap().nonce, 1);
+    }
+
+    #[test]
+    fn test_capacity_evicts_furthest_nonce() {
+        let sk1 = SecretKey::generate();
+        let sk2 = SecretKey::generate();
+        let to = SecretKey::generate().get_public_key();
+        let ledger = ledger_with_roots(&[&sk1, &sk2]);
+        let mut mempool = Mempool::new(3);
+
+        for nonce in 0..3 {
+            mempool
+                .insert(
+                    Transaction::new(&sk1, to.clone(), Las(1), MIN_RELAY_FEE, nonce),
+                    &ledger,
+                )
+                .unwrap();
+        }
+        let transaction = Transaction::new(&sk2, to.clone(), Las(1), MIN_RELAY_FEE, 0);
+        mempool.insert(transaction.clone(), &ledger).unwrap();
+
+        assert_eq!(mempool.len(), 3);
+        assert!(mempool.contains(&transaction));
+        assert!(!mempool.contains(&Transaction::new(
+            &sk1,
+            to.clone(),
+            Las(1),
+            MIN_RELAY_FEE,
+            2
+        )));
+    }
+
+    #[test]
+    fn test_higher_fee_is_selected_first() {
+        let sk1 = SecretKey::generate();
+        let sk2 = SecretKey::generate();
+        let to = SecretKey::generate().get_public_key();
+        let ledger = ledger_with_roots(&[&sk1, &sk2]);
+        let mut mempool = Mempool::default();
+
+        let cheap = Transaction::new(&sk1, to.clone(), Las(1), MIN_RELAY_FEE, 0);
+        let expensive = Transaction::new(&sk2, to.clone(), Las(1), 2 * MIN_RELAY_FEE, 0);
+        mempool.insert(cheap.clone(), &ledger).unwrap();
+        mempool.insert(expensive.clone(), &ledger).unwrap();
+        assert_eq!(mempool.select(&ledger, 1, usize::MAX), vec![expensive]);
+
+        // Paying more replaces the pending transaction, paying the same does not
+        let same_fee = Transaction::new(&sk1, to.clone(), Las(2), MIN_RELAY_FEE, 0);
+        assert!(mempool.insert(same_fee, &ledger).is_err());
+        let bumped = Transaction::new(&sk1, to.clone(), Las(1), 3 * MIN_RELAY_FEE, 0);
+        mempool.insert(bumped.clone(), &ledger).unwrap();
+        assert_eq!(mempool.len(), 2);
+        assert!(!mempool.contains(&cheap));
+        assert_eq!(mempool.select(&ledger, 1, usize::MAX), vec![bumped]);
+    }
+
+    #[test]
+    fn test_min_relay_fee() {
+        let sk = SecretKey::generate();
+        let to = SecretKey::generate().get_public_key();
+        let ledger = ledger_with_roots(&[&sk]);
+        let mut mempool = Mempool::default();
+
+        let transaction = Transaction::new(&sk, to.clone(), Las(1), MIN_RELAY_FEE - 1, 0);
+        assert!(mempool.insert(transaction.clone(), &ledger).is_err());
+        // It is still valid in a block
+        ledger.is_transaction_valid(&transaction).unwrap();
+    }
+}
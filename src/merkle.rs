@@ -0,0 +1,183 @@
+use anyhow::{Result, anyhow, ensure};
+use serde::{Deserialize, Serialize};
+
+use crate::util::{Sha256Hash, hash};
+
+// Leaves and inner nodes are hashed with different prefixes, so an inner node can't be passed off as a leaf
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// Root of a block without transactions
+pub const EMPTY_ROOT: Sha256Hash = [0; 32];
+
+fn hash_leaf(leaf: &Sha256Hash) -> Sha256Hash {
+    let mut bytes = Vec::with_capacity(33);
+    bytes.push(LEAF_PREFIX);
+    bytes.extend_from_slice(leaf);
+    hash(&bytes)
+}
+
+pub(crate) fn hash_node(left: &Sha256Hash, right: &Sha256Hash) -> Sha256Hash {
+    let mut bytes = Vec::with_capacity(65);
+    bytes.push(NODE_PREFIX);
+    bytes.extend_from_slice(left);
+    bytes.extend_from_slice(right);
+    hash(&bytes)
+}
+
+// The last node of a level with an odd length is moved up unchanged instead of being paired with itself,
+// duplicating it would give two different lists of leaves the same root
+fn next_level(level: &[Sha256Hash]) -> Vec<Sha256Hash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_node(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+pub fn merkle_root(leaves: &[Sha256Hash]) -> Sha256Hash {
+    if leaves.is_empty() {
+        return EMPTY_ROOT;
+    }
+
+    let mut level = leaves.iter().map(hash_leaf).collect::<Vec<_>>();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+/// Proves that a leaf is at `index` among `leaf_count` leaves with a given root,
+/// without knowing the other leaves
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub index: u64,
+    pub leaf_count: u64,
+    pub siblings: Vec<Sha256Hash>,
+}
+
+impl MerkleProof {
+    pub fn generate(leaves: &[Sha256Hash], index: usize) -> Option<Self> {
+        if index >= leaves.len() {
+            return None;
+        }
+
+        let mut siblings = Vec::new();
+        let mut level = leaves.iter().map(hash_leaf).collect::<Vec<_>>();
+        let mut position = index;
+        while level.len() > 1 {
+            if let Some(sibling) = level.get(position ^ 1) {
+                siblings.push(*sibling);
+            }
+            level = next_level(&level);
+            position /= 2;
+        }
+
+        Some(Self {
+            index: index as u64,
+            leaf_count: leaves.len() as u64,
+            siblings,
+        })
+    }
+
+    pub fn verify(&self, leaf: &Sha256Hash, root: &Sha256Hash) -> Result<()> {
+        ensure!(self.index < self.leaf_count, "Index is out of bounds");
+
+        let mut siblings = self.siblings.iter();
+        let mut node = hash_leaf(leaf);
+        let mut position = self.index;
+        let mut level_len = self.leaf_count;
+        while level_len > 1 {
+            // Without a sibling the node is the odd one out and moves up unchanged
+            let has_sibling = position ^ 1 < level_len;
+            if has_sibling {
+                let sibling = siblings
+                    .next()
+                    .ok_or(anyhow!("Proof has too few siblings"))?;
+                node = if position.is_multiple_of(2) {
+                    hash_node(&node, sibling)
+                } else {
+                    hash_node(sibling, &node)
+                };
+            }
+            position /= 2;
+            level_len = level_len.div_ceil(2);
+        }
+
+        ensure!(siblings.next().is_none(), "Proof has too many siblings");
+        ensure!(&node == root, "Proof does not match the root");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(n: u8) -> Vec<Sha256Hash> {
+        (0..n).map(|i| hash(&[i])).collect()
+    }
+
+    #[test]
+    fn test_proofs_for_every_leaf() {
+        for n in 1..=17 {
+            let leaves = leaves(n);
+            let root = merkle_root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = MerkleProof::generate(&leaves, index).unwrap();
+                proof.verify(leaf, &root).unwrap();
+
+                // The proof is only valid for this leaf at this position
+                assert!(proof.verify(&hash(b"other"), &root).is_err());
+                if n > 1 {
+                    let moved = MerkleProof {
+                        index: (proof.index + 1) % proof.leaf_count,
+                        ..proof.clone()
+                    };
+                    assert!(moved.verify(leaf, &root).is_err());
+                }
+            }
+            assert!(MerkleProof::generate(&leaves, n as usize).is_none());
+        }
+    }
+
+    #[test]
+    fn test_root_commits_to_leaves() {
+        assert_eq!(merkle_root(&[]), EMPTY_ROOT);
+
+        let leaves = leaves(5);
+        let root = merkle_root(&leaves);
+        assert_ne!(merkle_root(&leaves[..4]), root);
+
+        // Repeating the last leaf must not give the same root
+        let mut repeated = leaves.clone();
+        repeated.push(leaves[4]);
+        assert_ne!(merkle_root(&repeated), root);
+
+        let mut swapped = leaves.clone();
+        swapped.swap(0, 1);
+        assert_ne!(merkle_root(&swapped), root);
+    }
+
+    #[test]
+    fn test_tampered_proof() {
+        let leaves = leaves(6);
+        let root = merkle_root(&leaves);
+        let proof = MerkleProof::generate(&leaves, 2).unwrap();
+
+        let mut tampered = proof.clone();
+        tampered.siblings[0] = hash(b"other");
+        assert!(tampered.verify(&leaves[2], &root).is_err());
+
+        let mut truncated = proof.clone();
+        truncated.siblings.pop();
+        assert!(truncated.verify(&leaves[2], &root).is_err());
+
+        let mut extended = proof;
+        extended.siblings.push(hash(b"other"));
+        assert!(extended.verify(&leaves[2], &root).is_err());
+    }
+}
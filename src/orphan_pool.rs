@@ -0,0 +1,327 @@
+use std::collections::HashMap;
+
+use anyhow::{Result, anyhow};
+use iroh::NodeId;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::Block,
+    util::{BlockPtr, Sha256Hash, Timeslot},
+};
+
+pub const MAX_ORPHANS: usize = 1_000;
+pub const MAX_ORPHAN_BYTES: usize = 32 * 1024 * 1024;
+// A single peer can only fill a part of the pool, so it can't push out the orphans of everyone else
+pub const MAX_ORPHANS_PER_PEER: usize = 100;
+pub const MAX_ORPHAN_BYTES_PER_PEER: usize = 4 * 1024 * 1024;
+// Orphans whose parent has not arrived this many timeslots after their own timeslot are dropped
+pub const ORPHAN_EXPIRY: Timeslot = 600;
+
+#[derive(Clone, Copy, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct OrphanLimits {
+    pub max_count: usize,
+    pub max_bytes: usize,
+    pub max_count_per_peer: usize,
+    pub max_bytes_per_peer: usize,
+    pub expiry: Timeslot,
+}
+
+impl Default for OrphanLimits {
+    fn default() -> Self {
+        Self {
+            max_count: MAX_ORPHANS,
+            max_bytes: MAX_ORPHAN_BYTES,
+            max_count_per_peer: MAX_ORPHANS_PER_PEER,
+            max_bytes_per_peer: MAX_ORPHAN_BYTES_PER_PEER,
+            expiry: ORPHAN_EXPIRY,
+        }
+    }
+}
+
+// The orphans a peer has in the pool
+#[derive(Clone, Copy, Default, Debug)]
+struct Usage {
+    count: usize,
+    bytes: usize,
+}
+
+/// Blocks whose parent is unknown, keyed by the hash of the parent.
+/// When the pool is over one of its limits the deepest orphans are dropped, as they are the furthest from
+/// being added. Which peer sent an orphan is only used for the limits, so it is not part of what makes two
+/// pools equal
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct OrphanPool {
+    by_parent: HashMap<Sha256Hash, Vec<Block>>,
+    len: usize,
+    bytes: usize,
+    #[serde(skip)]
+    peers: HashMap<Sha256Hash, NodeId>, // The peer each orphan came from, keyed by block hash
+    #[serde(skip)]
+    usage: HashMap<NodeId, Usage>,
+    limits: OrphanLimits,
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        Self::new(OrphanLimits::default())
+    }
+}
+
+impl OrphanPool {
+    pub fn new(limits: OrphanLimits) -> Self {
+        Self {
+            by_parent: Default::default(),
+            len: 0,
+            bytes: 0,
+            peers: Default::default(),
+            usage: Default::default(),
+            limits,
+        }
+    }
+
+    /// Adds an orphan, `peer` is the peer it came from when it is known.
+    /// Fails if the orphan is dropped right away because the pool or the share of the peer is full
+    pub fn insert(&mut self, block: Block, peer: Option<NodeId>) -> Result<()> {
+        if self.contains(&block.ptr()) {
+            return Ok(());
+        }
+
+        let ptr = block.ptr();
+        let size = block.size();
+        self.by_parent
+            .entry(block.header.prev_hash)
+            .or_default()
+            .push(block);
+        self.len += 1;
+        self.bytes += size;
+        if let Some(peer) = peer {
+            self.peers.insert(ptr.hash, peer);
+            let usage = self.usage.entry(peer).or_default();
+            usage.count += 1;
+            usage.bytes += size;
+        }
+
+        let mut evicted = Vec::new();
+        if let Some(peer) = peer {
+            while self.usage.get(&peer).is_some_and(|usage| {
+                usage.count > self.limits.max_count_per_peer
+                    || usage.bytes > self.limits.max_bytes_per_peer
+            }) {
+                let deepest = self.deepest(|hash| self.peers.get(hash) == Some(&peer));
+                evicted.extend(deepest.and_then(|ptr| self.remove(&ptr)));
+            }
+        }
+        while self.len > self.limits.max_count || self.bytes > self.limits.max_bytes {
+            let deepest = self.deepest(|_| true);
+            evicted.extend(deepest.and_then(|ptr| self.remove(&ptr)));
+        }
+
+        if evicted.iter().any(|block| block.ptr() == ptr) {
+            return Err(anyhow!("Orphan pool is full"));
+        }
+
+        Ok(())
+    }
+
+    pub fn contains(&self, ptr: &BlockPtr) -> bool {
+        self.get(ptr).is_some()
+    }
+
+    pub fn get(&self, ptr: &BlockPtr) -> Option<&Block> {
+        self.iter().find(|block| block.ptr() == *ptr)
+    }
+
+    /// The peer an orphan came from, if it is known
+    pub fn peer_of(&self, ptr: &BlockPtr) -> Option<&NodeId> {
+        self.peers.get(&ptr.hash)
+    }
+
+    /// Takes out the orphans whose parent is the block with hash `parent`, in the order they arrived
+    pub fn remove_children(&mut self, parent: &Sha256Hash) -> Vec<Block> {
+        let children = self.by_parent.remove(parent).unwrap_or_default();
+        for child in children.iter() {
+            self.forget(child);
+        }
+        children
+    }
+
+    /// Drops the orphans that have waited too long for their parent
+    pub fn expire(&mut self, timeslot: Timeslot) {
+        let expiry = self.limits.expiry;
+        self.retain(|block| block.header.timeslot.saturating_add(expiry) >= timeslot);
+    }
+
+    /// Keeps only the orphans for which `f` is true
+    pub fn retain(&mut self, mut f: impl FnMut(&Block) -> bool) {
+        let removed = self
+            .iter()
+            .filter(|block| !f(block))
+            .map(Block::ptr)
+            .collect::<Vec<_>>();
+        for ptr in removed {
+            self.remove(&ptr);
+        }
+    }
+
+    /// Every orphan, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = &Block> {
+        self.by_parent.values().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The serialized size of all the orphans
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn limits(&self) -> OrphanLimits {
+        self.limits
+    }
+
+    fn remove(&mut self, ptr: &BlockPtr) -> Option<Block> {
+        let parent = self.get(ptr)?.header.prev_hash;
+        let siblings = self.by_parent.get_mut(&parent)?;
+        let i = siblings.iter().position(|block| block.ptr() == *ptr)?;
+        let block = siblings.remove(i);
+        if siblings.is_empty() {
+            self.by_parent.remove(&parent);
+        }
+        self.forget(&block);
+        Some(block)
+    }
+
+    // Updates the accounting after the block has been taken out of `by_parent`
+    fn forget(&mut self, block: &Block) {
+        let size = block.size();
+        self.len -= 1;
+        self.bytes -= size;
+        let Some(peer) = self.peers.remove(&block.header.hash) else {
+            return;
+        };
+        let usage = self
+            .usage
+            .get_mut(&peer)
+            .expect("a peer with orphans has usage");
+        usage.count -= 1;
+        usage.bytes -= size;
+        if usage.count == 0 {
+            self.usage.remove(&peer);
+        }
+    }
+
+    // The deepest of the orphans whose hash `f` is true for
+    fn deepest(&self, f: impl Fn(&Sha256Hash) -> bool) -> Option<BlockPtr> {
+        self.iter()
+            .filter(|block| f(&block.header.hash))
+            .max_by_key(|block| block.header.depth)
+            .map(Block::ptr)
+    }
+}
+
+impl PartialEq for OrphanPool {
+    fn eq(&self, other: &Self) -> bool {
+        self.by_parent == other.by_parent && self.limits == other.limits
+    }
+}
+
+impl Eq for OrphanPool {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{draw::Seed, keys::SecretKey};
+
+    fn orphan(timeslot: Timeslot, depth: i64) -> Block {
+        let seed = Seed {
+            block_ptr: BlockPtr::new([0; 32], 0),
+        };
+        let sk = SecretKey::generate();
+        Block::new(
+            timeslot,
+            [depth as u8; 32],
+            depth,
+            Vec::new(),
+            [0; 32],
+            &sk,
+            seed,
+        )
+    }
+
+    fn peer() -> NodeId {
+        iroh::SecretKey::from(&SecretKey::generate()).public()
+    }
+
+    #[test]
+    fn test_deepest_orphans_are_dropped() {
+        let limits = OrphanLimits {
+            max_count: 2,
+            ..Default::default()
+        };
+        let mut pool = OrphanPool::new(limits);
+        let (shallow, middle, deep) = (orphan(1, 5), orphan(1, 6), orphan(1, 7));
+
+        pool.insert(deep.clone(), None).unwrap();
+        pool.insert(shallow.clone(), None).unwrap();
+        pool.insert(middle.clone(), None).unwrap();
+        assert_eq!(pool.len(), 2);
+        assert!(!pool.contains(&deep.ptr()));
+
+        let deeper = orphan(1, 8);
+        assert!(pool.insert(deeper, None).is_err());
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.bytes(), shallow.size() + middle.size());
+
+        assert_eq!(
+            pool.remove_children(&shallow.header.prev_hash),
+            vec![shallow]
+        );
+        assert_eq!(pool.len(), 1);
+    }
+
+    #[test]
+    fn test_peer_limits() {
+        let limits = OrphanLimits {
+            max_count_per_peer: 1,
+            ..Default::default()
+        };
+        let mut pool = OrphanPool::new(limits);
+        let (spammer, honest) = (peer(), peer());
+
+        pool.insert(orphan(1, 5), Some(spammer)).unwrap();
+        assert!(pool.insert(orphan(1, 6), Some(spammer)).is_err());
+        // Another peer and orphans of unknown peers are not limited by the share of the spammer
+        let from_honest = orphan(1, 7);
+        pool.insert(from_honest.clone(), Some(honest)).unwrap();
+        pool.insert(orphan(1, 8), None).unwrap();
+        assert_eq!(pool.len(), 3);
+        assert_eq!(pool.peer_of(&from_honest.ptr()), Some(&honest));
+
+        // Once its orphan is gone the peer can add another one
+        pool.retain(|block| block.header.depth != 5);
+        pool.insert(orphan(1, 6), Some(spammer)).unwrap();
+        assert_eq!(pool.len(), 3);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut pool = OrphanPool::default();
+        let old = orphan(10, 5);
+        let recent = orphan(100, 6);
+        pool.insert(old.clone(), Some(peer())).unwrap();
+        pool.insert(recent.clone(), None).unwrap();
+
+        pool.expire(10 + ORPHAN_EXPIRY);
+        assert_eq!(pool.len(), 2);
+        pool.expire(11 + ORPHAN_EXPIRY);
+        assert!(!pool.contains(&old.ptr()));
+        assert!(pool.contains(&recent.ptr()));
+        assert_eq!(pool.bytes(), recent.size());
+    }
+}
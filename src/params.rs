@@ -0,0 +1,89 @@
+use anyhow::{Result, anyhow, ensure};
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+
+use crate::util::{MiniLas, as_string};
+
+/// The rules of a network that every node must agree on, they are part of the genesis config
+#[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct ChainParams {
+    pub slot_length: u64, // In microseconds
+    pub block_reward: MiniLas,
+    // Given to every root account of a genesis config made from root accounts only
+    pub root_amount: MiniLas,
+    // Accounts that are not root accounts need more than this to stake
+    pub minimum_stake_amount: MiniLas,
+    // How many blocks the block of the seed is below the block, it is also the default finality depth
+    pub seed_age: i64,
+    // A draw, which is in [0, 2^256), must beat this for the entire network to win a timeslot.
+    // Written as a decimal string, as it does not fit in the integers of TOML
+    #[serde(with = "as_string")]
+    pub lottery_hardness: BigUint,
+}
+
+impl ChainParams {
+    pub fn mainnet() -> Self {
+        Self {
+            slot_length: 1_000_000,
+            block_reward: 3_000000,
+            root_amount: 100_000000,
+            minimum_stake_amount: 10_000000,
+            seed_age: 50,
+            // The entire network has a total 10% chance of beating this at a given timeslot
+            lottery_hardness: BigUint::from(10421u64) * BigUint::from(10u64).pow(73),
+        }
+    }
+
+    /// Like mainnet, but anyone with a bit of test money can stake
+    pub fn testnet() -> Self {
+        Self {
+            minimum_stake_amount: 1_000000,
+            ..Self::mainnet()
+        }
+    }
+
+    /// A local network with short slots and a short seed age, so blocks are made and finalized quickly
+    pub fn devnet() -> Self {
+        Self {
+            slot_length: 100_000,
+            seed_age: 10,
+            ..Self::testnet()
+        }
+    }
+
+    pub fn preset(name: &str) -> Result<Self> {
+        match name {
+            "mainnet" => Ok(Self::mainnet()),
+            "testnet" => Ok(Self::testnet()),
+            "devnet" => Ok(Self::devnet()),
+            _ => Err(anyhow!(
+                "Unknown preset {name}, expected mainnet, testnet or devnet"
+            )),
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.slot_length > 0, "Slot length must be positive");
+        ensure!(self.seed_age > 0, "Seed age must be positive");
+        ensure!(
+            self.lottery_hardness < BigUint::from(2u64).pow(256),
+            "Lottery hardness must be below 2^256"
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets() {
+        for name in ["mainnet", "testnet", "devnet"] {
+            ChainParams::preset(name).unwrap().validate().unwrap();
+        }
+        assert!(ChainParams::preset("moonnet").is_err());
+        assert!(ChainParams::devnet().slot_length < ChainParams::mainnet().slot_length);
+        assert!(ChainParams::devnet().seed_age < ChainParams::mainnet().seed_age);
+    }
+}
@@ -0,0 +1,461 @@
+use std::{collections::BTreeMap, fmt};
+
+use anyhow::{Result, ensure};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::{
+    block::Block,
+    blockchain::Blockchain,
+    clock::{Clock, MockClock},
+    fork_choice::DeepestChain,
+    genesis::{Allocation, GenesisConfig},
+    keys::SecretKey,
+    params::ChainParams,
+    util::{BlockPtr, MiniLas, START_TIME, Timeslot},
+};
+
+/// How a simulated network is set up and how its messages are delivered
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    // Every key and random choice comes from it, so the same config always gives the same run
+    pub seed: u64,
+    pub params: ChainParams,
+    // The genesis allocation of each node, there is one node per stake
+    pub stakes: Vec<MiniLas>,
+    // Nodes that keep the blocks they make to themselves, the others are honest
+    pub withholding: Vec<usize>,
+    pub timeslots: Timeslot, // How many timeslots the nodes make blocks for
+    pub min_latency: u128,   // In microseconds
+    pub max_latency: u128,
+    pub loss: f64, // Chance that a message is dropped
+    pub partitions: Vec<Partition>,
+}
+
+/// From timeslot `start` until `end`, the nodes in `side` and the other nodes can not reach each other
+#[derive(Clone, Debug)]
+pub struct Partition {
+    pub start: Timeslot,
+    pub end: Timeslot,
+    pub side: Vec<usize>,
+}
+
+impl Partition {
+    fn separates(&self, timeslot: Timeslot, a: usize, b: usize) -> bool {
+        (self.start..self.end).contains(&timeslot)
+            && self.side.contains(&a) != self.side.contains(&b)
+    }
+}
+
+impl SimConfig {
+    /// Honest nodes with the same stake on a devnet, where messages take at most a tenth of a slot
+    pub fn new(nodes: usize) -> Self {
+        let params = ChainParams::devnet();
+        let max_latency = params.slot_length as u128 / 10;
+        Self {
+            seed: 0,
+            stakes: vec![params.root_amount; nodes],
+            params,
+            withholding: Vec::new(),
+            timeslots: 500,
+            min_latency: max_latency / 10,
+            max_latency,
+            loss: 0.0,
+            partitions: Vec::new(),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        let nodes = self.stakes.len();
+        ensure!(nodes > 0, "There must be at least one node");
+        ensure!(
+            self.min_latency <= self.max_latency,
+            "Minimum latency is above the maximum latency"
+        );
+        ensure!(
+            (0.0..=1.0).contains(&self.loss),
+            "Loss must be between 0 and 1"
+        );
+        ensure!(
+            self.withholding.iter().all(|&node| node < nodes),
+            "Withholding node does not exist"
+        );
+        ensure!(
+            self.withholding.len() < nodes,
+            "There must be at least one honest node"
+        );
+        ensure!(
+            self.partitions
+                .iter()
+                .all(|partition| partition.side.iter().all(|&node| node < nodes)),
+            "Partitioned node does not exist"
+        );
+        Ok(())
+    }
+}
+
+/// What a simulation found, forks and reorgs are only counted for honest nodes
+#[derive(Clone, PartialEq, Debug)]
+pub struct SimReport {
+    pub blocks_produced: usize,
+    pub best_depth: i64, // Depth of the best head of the first honest node
+    // Share of the blocks made by honest nodes that are not on the best path of the first honest node
+    pub fork_rate: f64,
+    pub reorgs: usize, // How many times an honest node switched to a fork
+    pub max_reorg_depth: i64,
+    pub messages_sent: usize,
+    pub messages_dropped: usize,
+    pub converged: bool, // Whether every honest node ends with the same best path
+}
+
+impl fmt::Display for SimReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} blocks, best depth {}, fork rate {:.1}%, {} reorgs up to {} deep, {}/{} messages dropped, {}",
+            self.blocks_produced,
+            self.best_depth,
+            self.fork_rate * 100.0,
+            self.reorgs,
+            self.max_reorg_depth,
+            self.messages_dropped,
+            self.messages_sent,
+            if self.converged {
+                "converged"
+            } else {
+                "not converged"
+            }
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+enum SimMessage {
+    Block(Box<Block>),
+    GetBlock(BlockPtr),
+}
+
+#[derive(Clone, Debug)]
+struct Envelope {
+    from: usize,
+    to: usize,
+    message: SimMessage,
+}
+
+struct SimNode {
+    sk: SecretKey,
+    blockchain: Blockchain<DeepestChain, MockClock>,
+    honest: bool,
+}
+
+/// Nodes that each have their own blockchain, connected by a message bus with latency, loss and partitions.
+/// A shared virtual clock moves from one message or timeslot to the next, so nothing waits on real time.
+/// The producer of a block sends it to every other node, and a node that gets an orphan asks the sender for
+/// the first block it misses below it. After the last timeslot the network becomes reliable and every honest node sends its best head
+/// to the others, like nodes syncing with their neighbors, so the report shows if the forks can still be resolved
+pub struct Simulation {
+    config: SimConfig,
+    clock: MockClock,
+    nodes: Vec<SimNode>,
+    rng: StdRng,
+    timeslot: Timeslot,
+    settled: bool, // Once set, messages are no longer dropped
+    in_flight: BTreeMap<(u128, u64), Envelope>, // Keyed by delivery time and send order
+    honest_blocks: Vec<BlockPtr>,
+    report: SimReport,
+}
+
+impl Simulation {
+    pub fn new(config: SimConfig) -> Result<Self> {
+        config.validate()?;
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let sks = config
+            .stakes
+            .iter()
+            .map(|_| SecretKey::from_bytes(&rng.random()))
+            .collect::<Vec<_>>();
+
+        let genesis = GenesisConfig {
+            chain_id: "sim".to_string(),
+            start_time: START_TIME as u64,
+            params: config.params.clone(),
+            allocations: sks
+                .iter()
+                .zip(config.stakes.iter())
+                .map(|(sk, &amount)| Allocation {
+                    account: sk.get_public_key(),
+                    amount,
+                })
+                .collect(),
+        };
+        genesis.validate()?;
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sks[0]);
+
+        let clock = MockClock::new(START_TIME);
+        let nodes = sks
+            .into_iter()
+            .enumerate()
+            .map(|(i, sk)| SimNode {
+                sk,
+                blockchain: Blockchain::start(genesis.clone(), genesis_block.clone())
+                    .with_clock(clock.clone()),
+                honest: !config.withholding.contains(&i),
+            })
+            .collect();
+
+        Ok(Self {
+            config,
+            clock,
+            nodes,
+            rng,
+            timeslot: 0,
+            settled: false,
+            in_flight: Default::default(),
+            honest_blocks: Vec::new(),
+            report: SimReport {
+                blocks_produced: 0,
+                best_depth: 0,
+                fork_rate: 0.0,
+                reorgs: 0,
+                max_reorg_depth: 0,
+                messages_sent: 0,
+                messages_dropped: 0,
+                converged: false,
+            },
+        })
+    }
+
+    pub fn run(mut self) -> SimReport {
+        let slot_length = self.config.params.slot_length as u128;
+        for timeslot in 1..=self.config.timeslots {
+            let slot_start = START_TIME + slot_length * timeslot as u128;
+            self.deliver_until(slot_start);
+            self.clock.set(slot_start);
+            self.timeslot = timeslot;
+
+            for i in 0..self.nodes.len() {
+                let node = &mut self.nodes[i];
+                node.blockchain.orphans.expire(timeslot);
+                let Some(block) = node.blockchain.make_block(&node.sk) else {
+                    continue;
+                };
+                self.report.blocks_produced += 1;
+                self.add_block(i, block.clone());
+                if self.nodes[i].honest {
+                    self.honest_blocks.push(block.ptr());
+                    self.broadcast(i, &block);
+                }
+            }
+        }
+
+        self.settled = true;
+        for i in self.honest_nodes() {
+            let blockchain = &self.nodes[i].blockchain;
+            let head = blockchain.get_block(blockchain.best_path_head()).unwrap();
+            if head.header.depth > 0 {
+                let head = head.clone();
+                self.broadcast(i, &head);
+            }
+        }
+        self.deliver_until(u128::MAX);
+
+        self.finish()
+    }
+
+    fn honest_nodes(&self) -> Vec<usize> {
+        (0..self.nodes.len())
+            .filter(|&i| self.nodes[i].honest)
+            .collect()
+    }
+
+    fn broadcast(&mut self, from: usize, block: &Block) {
+        for to in (0..self.nodes.len()).filter(|&to| to != from) {
+            self.send(from, to, SimMessage::Block(Box::new(block.clone())));
+        }
+    }
+
+    fn send(&mut self, from: usize, to: usize, message: SimMessage) {
+        self.report.messages_sent += 1;
+        let partitioned = self
+            .config
+            .partitions
+            .iter()
+            .any(|partition| partition.separates(self.timeslot, from, to));
+        if !self.settled && (partitioned || self.rng.random_bool(self.config.loss)) {
+            self.report.messages_dropped += 1;
+            return;
+        }
+
+        let latency = self
+            .rng
+            .random_range(self.config.min_latency..=self.config.max_latency);
+        let key = (self.clock.now() + latency, self.report.messages_sent as u64);
+        self.in_flight.insert(key, Envelope { from, to, message });
+    }
+
+    // Delivers the messages in the order they arrive, moving the clock to each of them
+    fn deliver_until(&mut self, time: u128) {
+        while let Some(entry) = self.in_flight.first_entry() {
+            let (deliver_at, _) = *entry.key();
+            if deliver_at > time {
+                break;
+            }
+            let envelope = entry.remove();
+            self.clock.set(deliver_at);
+            self.receive(envelope);
+        }
+    }
+
+    fn receive(&mut self, Envelope { from, to, message }: Envelope) {
+        match message {
+            SimMessage::Block(block) => {
+                let ptr = block.ptr();
+                let blockchain = &self.nodes[to].blockchain;
+                if blockchain.get_block(&ptr).is_some() {
+                    return;
+                }
+                if !blockchain.orphans.contains(&ptr) {
+                    self.add_block(to, *block);
+                }
+
+                // Ask for the first missing block below an orphan, even when it was asked for
+                // before, as that request may have been lost
+                let blockchain = &self.nodes[to].blockchain;
+                let mut missing = ptr.clone();
+                while let Some(orphan) = blockchain.orphans.get(&missing) {
+                    missing = BlockPtr::new(orphan.header.prev_hash, missing.depth - 1);
+                }
+                if missing != ptr && blockchain.get_block(&missing).is_none() {
+                    self.send(to, from, SimMessage::GetBlock(missing));
+                }
+            }
+            SimMessage::GetBlock(ptr) => {
+                let blockchain = &self.nodes[to].blockchain;
+                if let Some(block) = blockchain
+                    .get_block(&ptr)
+                    .or(blockchain.orphans.get(&ptr))
+                    .cloned()
+                {
+                    self.send(to, from, SimMessage::Block(Box::new(block)));
+                }
+            }
+        }
+    }
+
+    // Adds the block to the blockchain of the node, and counts the reorg if the node switches to a fork
+    fn add_block(&mut self, node: usize, block: Block) {
+        let node = &mut self.nodes[node];
+        let old_best_path = node.blockchain.best_path.clone();
+        // A block that is invalid to the node, or that forks below its final block, is ignored like any peer would
+        if node.blockchain.add_block(block).is_err() || !node.honest {
+            return;
+        }
+
+        let best_path = &node.blockchain.best_path;
+        let common = old_best_path
+            .iter()
+            .zip(best_path.iter())
+            .take_while(|(old, new)| old == new)
+            .count();
+        let rolled_back = (old_best_path.len() - common) as i64;
+        if rolled_back > 0 {
+            self.report.reorgs += 1;
+            self.report.max_reorg_depth = self.report.max_reorg_depth.max(rolled_back);
+        }
+    }
+
+    fn finish(mut self) -> SimReport {
+        let honest = self.honest_nodes();
+        let best_path = &self.nodes[honest[0]].blockchain.best_path;
+        let forked = self
+            .honest_blocks
+            .iter()
+            .filter(|ptr| best_path.get(ptr.depth as usize) != Some(ptr))
+            .count();
+
+        self.report.best_depth = best_path.len() as i64 - 1;
+        if !self.honest_blocks.is_empty() {
+            self.report.fork_rate = forked as f64 / self.honest_blocks.len() as f64;
+        }
+        self.report.converged = honest
+            .iter()
+            .all(|&i| self.nodes[i].blockchain.best_path == *best_path);
+        self.report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(config: SimConfig) -> SimReport {
+        Simulation::new(config).unwrap().run()
+    }
+
+    #[test]
+    fn test_honest_nodes_converge() {
+        let mut config = SimConfig::new(5);
+        config.withholding = vec![4];
+        let report = run(config.clone());
+
+        assert!(report.converged, "{report}");
+        assert!(report.best_depth > 10, "{report}");
+        assert!(report.fork_rate < 0.5, "{report}");
+        assert_eq!(report.messages_dropped, 0);
+
+        // The same config gives the same run
+        assert_eq!(run(config), report);
+    }
+
+    #[test]
+    fn test_lost_blocks_are_fetched() {
+        let mut config = SimConfig::new(4);
+        config.loss = 0.3;
+        let report = run(config);
+
+        assert!(report.messages_dropped > 0, "{report}");
+        assert!(report.converged, "{report}");
+    }
+
+    #[test]
+    fn test_partition_heals_with_reorg() {
+        let mut config = SimConfig::new(4);
+        config.partitions = vec![Partition {
+            start: 0,
+            end: 100,
+            side: vec![0, 1],
+        }];
+        let report = run(config);
+
+        assert!(report.converged, "{report}");
+        assert!(report.max_reorg_depth >= 1, "{report}");
+        assert!(report.fork_rate > 0.0, "{report}");
+    }
+
+    #[test]
+    fn test_partition_deeper_than_finality_does_not_heal() {
+        let mut config = SimConfig::new(4);
+        // Each side makes around 30 blocks while they are apart, far more than the finality depth
+        config.partitions = vec![Partition {
+            start: 0,
+            end: 600,
+            side: vec![0, 1],
+        }];
+        config.timeslots = config.partitions[0].end + 50;
+        let report = run(config);
+
+        assert!(!report.converged, "{report}");
+    }
+
+    #[test]
+    fn test_invalid_configs_are_rejected() {
+        let mut config = SimConfig::new(2);
+        config.withholding = vec![0, 1];
+        assert!(Simulation::new(config).is_err());
+
+        let mut config = SimConfig::new(2);
+        config.loss = 2.0;
+        assert!(Simulation::new(config).is_err());
+
+        assert!(Simulation::new(SimConfig::new(0)).is_err());
+    }
+}
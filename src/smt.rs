@@ -0,0 +1,398 @@
+use std::collections::{BTreeMap, HashMap};
+
+use anyhow::{Result, anyhow, ensure};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    merkle::hash_node,
+    util::{SerToBytes, Sha256Hash, hash},
+};
+
+// Levels below the root, one for every bit of a key
+const HEIGHT: usize = 256;
+
+// Differs from the prefix of inner nodes, so an inner node can't be passed off as a leaf
+const LEAF_PREFIX: u8 = 0;
+
+// Root of a subtree without leaves
+const EMPTY_ROOT: Sha256Hash = [0; 32];
+
+// The leaf commits to its key, so it can't be moved to another position
+fn hash_leaf(key: &Sha256Hash, value_hash: &Sha256Hash) -> Sha256Hash {
+    let mut bytes = Vec::with_capacity(65);
+    bytes.push(LEAF_PREFIX);
+    bytes.extend_from_slice(key);
+    bytes.extend_from_slice(value_hash);
+    hash(&bytes)
+}
+
+// Bits are counted from the most significant bit of the first byte, which decides the branch at the root
+fn bit(key: &Sha256Hash, index: usize) -> bool {
+    key[index / 8] & (0x80 >> (index % 8)) != 0
+}
+
+// Hashes a node at `height` with its sibling, the bit of the key tells which side the node is on
+fn hash_parent(
+    key: &Sha256Hash,
+    height: usize,
+    node: &Sha256Hash,
+    sibling: &Sha256Hash,
+) -> Sha256Hash {
+    if bit(key, HEIGHT - 1 - height) {
+        hash_node(sibling, node)
+    } else {
+        hash_node(node, sibling)
+    }
+}
+
+// The smallest and the largest key in the subtree at `height` that `key` is in
+fn subtree_range(key: &Sha256Hash, height: usize) -> (Sha256Hash, Sha256Hash) {
+    let (mut low, mut high) = (*key, *key);
+    let shared = HEIGHT - height;
+    for byte in shared / 8..32 {
+        let below = if byte == shared / 8 {
+            0xff >> (shared % 8)
+        } else {
+            0xff
+        };
+        low[byte] &= !below;
+        high[byte] |= below;
+    }
+    (low, high)
+}
+
+/// Merkle tree with a position for every possible 256 bit key, almost all of which are empty.
+/// As every key has a fixed position, a proof can show what a key maps to, including that it maps to nothing.
+/// A subtree without leaves is `EMPTY_ROOT` and a subtree with a single leaf is that leaf,
+/// so only the nodes where keys branch off are hashed. Their hashes are kept,
+/// a change only rehashes the nodes on the path to its leaf
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SparseMerkleTree<V> {
+    leaves: BTreeMap<Sha256Hash, V>,
+    // Keyed by height and the smallest key of the subtree, leaves are at height 0
+    nodes: HashMap<(usize, Sha256Hash), Sha256Hash>,
+}
+
+impl<V> Default for SparseMerkleTree<V> {
+    fn default() -> Self {
+        Self {
+            leaves: Default::default(),
+            nodes: Default::default(),
+        }
+    }
+}
+
+impl<V: Serialize> SparseMerkleTree<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn from_leaves(leaves: BTreeMap<Sha256Hash, V>) -> Self {
+        let mut tree = Self {
+            leaves,
+            nodes: Default::default(),
+        };
+        let hashed_leaves = tree
+            .leaves
+            .iter()
+            .map(|(key, value)| (*key, hash_leaf(key, &hash(&value.into_bytes()))))
+            .collect::<Vec<_>>();
+        tree.nodes
+            .extend(hashed_leaves.iter().map(|(key, leaf)| ((0, *key), *leaf)));
+        tree.hash_subtree(&hashed_leaves, HEIGHT);
+        tree
+    }
+
+    pub fn get(&self, key: &Sha256Hash) -> Option<&V> {
+        self.leaves.get(key)
+    }
+
+    pub fn insert(&mut self, key: Sha256Hash, value: V) -> Option<V> {
+        let previous = self.leaves.insert(key, value);
+        self.update_path(&key);
+        previous
+    }
+
+    pub fn remove(&mut self, key: &Sha256Hash) -> Option<V> {
+        let previous = self.leaves.remove(key);
+        self.update_path(key);
+        previous
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.leaves.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn root(&self) -> Sha256Hash {
+        self.node(&[0; 32], HEIGHT)
+    }
+
+    pub fn prove(&self, key: &Sha256Hash) -> SparseMerkleProof {
+        let mut non_empty = [0; 32];
+        let mut siblings = Vec::new();
+
+        // Walk down towards the key until its subtree has at most one leaf
+        let mut height = HEIGHT;
+        while self.keys_below(key, height).nth(1).is_some() {
+            height -= 1;
+            let mut sibling = *key;
+            sibling[(HEIGHT - 1 - height) / 8] ^= 0x80 >> ((HEIGHT - 1 - height) % 8);
+            if self.keys_below(&sibling, height).next().is_some() {
+                non_empty[height / 8] |= 0x80 >> (height % 8);
+                siblings.push(self.node(&sibling, height));
+            }
+        }
+        siblings.reverse();
+
+        // The key is absent if another key is alone in its subtree
+        let other_leaf = match self.keys_below(key, height).next() {
+            Some(other) if other != key => {
+                let value = self.leaves.get(other).expect("the leaf is in the tree");
+                Some((*other, hash(&value.into_bytes())))
+            }
+            _ => None,
+        };
+
+        SparseMerkleProof {
+            non_empty,
+            siblings,
+            other_leaf,
+        }
+    }
+
+    fn keys_below(&self, key: &Sha256Hash, height: usize) -> impl Iterator<Item = &Sha256Hash> {
+        let (low, high) = subtree_range(key, height);
+        self.leaves.range(low..=high).map(|(key, _)| key)
+    }
+
+    // Hash of the subtree at `height` that `key` is in
+    fn node(&self, key: &Sha256Hash, height: usize) -> Sha256Hash {
+        let mut keys = self.keys_below(key, height);
+        match (keys.next(), keys.next()) {
+            (None, _) => EMPTY_ROOT,
+            (Some(leaf), None) => self.nodes[&(0, *leaf)],
+            _ => self.nodes[&(height, subtree_range(key, height).0)],
+        }
+    }
+
+    // Rehashes the nodes above a changed key, from the leaf up
+    fn update_path(&mut self, key: &Sha256Hash) {
+        match self.leaves.get(key) {
+            Some(value) => {
+                let leaf = hash_leaf(key, &hash(&value.into_bytes()));
+                self.nodes.insert((0, *key), leaf);
+            }
+            None => {
+                self.nodes.remove(&(0, *key));
+            }
+        }
+
+        for height in 1..=HEIGHT {
+            let (low, high) = subtree_range(key, height);
+            if self.keys_below(key, height).nth(1).is_some() {
+                let node = hash_node(&self.node(&low, height - 1), &self.node(&high, height - 1));
+                self.nodes.insert((height, low), node);
+            } else {
+                self.nodes.remove(&(height, low));
+            }
+        }
+    }
+
+    // Hashes the nodes of a new tree, `leaves` are sorted by key and share the bits above `height`
+    fn hash_subtree(&mut self, leaves: &[(Sha256Hash, Sha256Hash)], height: usize) -> Sha256Hash {
+        match leaves {
+            [] => EMPTY_ROOT,
+            [(_, leaf)] => *leaf,
+            [(first, _), ..] => {
+                let split = leaves.partition_point(|(key, _)| !bit(key, HEIGHT - height));
+                let left = self.hash_subtree(&leaves[..split], height - 1);
+                let right = self.hash_subtree(&leaves[split..], height - 1);
+                let node = hash_node(&left, &right);
+                self.nodes
+                    .insert((height, subtree_range(first, height).0), node);
+                node
+            }
+        }
+    }
+}
+
+// Only the leaves are stored, the hashes are computed again when loading so they always match the leaves
+impl<V: Serialize> Serialize for SparseMerkleTree<V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.leaves.serialize(serializer)
+    }
+}
+
+impl<'de, V: Serialize + Deserialize<'de>> Deserialize<'de> for SparseMerkleTree<V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Ok(Self::from_leaves(BTreeMap::deserialize(deserializer)?))
+    }
+}
+
+/// Proves what a key maps to in a tree with a given root, or that it maps to nothing
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SparseMerkleProof {
+    // Bit `height` is set when the sibling at that height is not empty, only those siblings are sent, lowest first
+    non_empty: [u8; 32],
+    siblings: Vec<Sha256Hash>,
+    // Key and value hash of the leaf taking up the position of an absent key
+    other_leaf: Option<(Sha256Hash, Sha256Hash)>,
+}
+
+impl SparseMerkleProof {
+    /// `value` is `None` to verify that the key is not in the tree
+    pub fn verify<V: Serialize>(
+        &self,
+        key: &Sha256Hash,
+        value: Option<&V>,
+        root: &Sha256Hash,
+    ) -> Result<()> {
+        let mut node = match (value, self.other_leaf) {
+            (Some(value), None) => hash_leaf(key, &hash(&value.into_bytes())),
+            (None, None) => EMPTY_ROOT,
+            (None, Some((other, value_hash))) => {
+                ensure!(&other != key, "Proof shows that the key is in the tree");
+                hash_leaf(&other, &value_hash)
+            }
+            (Some(_), Some(_)) => {
+                return Err(anyhow!("Proof shows that the key is not in the tree"));
+            }
+        };
+
+        // Until the first sibling the subtree has at most one leaf, which is the subtree itself
+        let mut siblings = self.siblings.iter();
+        let mut alone = true;
+        for height in 0..HEIGHT {
+            if bit(&self.non_empty, height) {
+                let sibling = siblings
+                    .next()
+                    .ok_or(anyhow!("Proof has too few siblings"))?;
+                node = hash_parent(key, height, &node, sibling);
+                alone = false;
+            } else if !alone {
+                node = hash_parent(key, height, &node, &EMPTY_ROOT);
+            }
+        }
+
+        ensure!(siblings.next().is_none(), "Proof has too many siblings");
+        ensure!(&node == root, "Proof does not match the root");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::FromBytes;
+
+    fn keys(n: u8) -> Vec<Sha256Hash> {
+        (0..n).map(|i| hash(&[i])).collect()
+    }
+
+    #[test]
+    fn test_root_only_depends_on_contents() {
+        let keys = keys(10);
+        let mut tree = SparseMerkleTree::new();
+        assert_eq!(tree.root(), EMPTY_ROOT);
+
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(*key, i as u64);
+        }
+        let mut reversed = SparseMerkleTree::new();
+        for (i, key) in keys.iter().enumerate().rev() {
+            reversed.insert(*key, i as u64);
+        }
+        assert_eq!(tree.root(), reversed.root());
+
+        let root = tree.root();
+        tree.insert(hash(b"other"), 1);
+        assert_ne!(tree.root(), root);
+        tree.remove(&hash(b"other"));
+        assert_eq!(tree.root(), root);
+        tree.insert(keys[0], 1);
+        assert_ne!(tree.root(), root);
+    }
+
+    #[test]
+    fn test_kept_hashes_match_the_leaves() {
+        let keys = keys(40);
+        let mut tree = SparseMerkleTree::new();
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(*key, i as u64);
+            if i % 3 == 0 {
+                tree.insert(keys[i / 2], i as u64);
+            }
+            if i % 5 == 0 {
+                tree.remove(&keys[i / 3]);
+            }
+            let rebuilt = SparseMerkleTree::from_leaves(tree.leaves.clone());
+            assert_eq!(tree.nodes, rebuilt.nodes);
+        }
+
+        // Hashes are not stored, so a loaded tree can't have hashes that differ from its leaves
+        let loaded = SparseMerkleTree::<u64>::from_bytes(&tree.into_bytes()).unwrap();
+        assert_eq!(loaded, tree);
+
+        for key in keys.iter() {
+            tree.remove(key);
+        }
+        assert!(tree.nodes.is_empty());
+        assert_eq!(tree.root(), EMPTY_ROOT);
+    }
+
+    #[test]
+    fn test_proofs() {
+        let keys = keys(10);
+        let mut tree = SparseMerkleTree::new();
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(*key, i as u64);
+        }
+        let root = tree.root();
+
+        for (i, key) in keys.iter().enumerate() {
+            let proof = tree.prove(key);
+            proof.verify(key, Some(&(i as u64)), &root).unwrap();
+            assert!(proof.verify(key, Some(&(i as u64 + 1)), &root).is_err());
+            assert!(proof.verify::<u64>(key, None, &root).is_err());
+            assert!(
+                proof
+                    .verify(&hash(b"other"), Some(&(i as u64)), &root)
+                    .is_err()
+            );
+        }
+
+        // Absent keys end up both at empty subtrees and at the leaf of another key
+        let mut at_other_leaf = 0;
+        for i in 0..50 {
+            let absent = hash(&[i, 0]);
+            let proof = tree.prove(&absent);
+            proof.verify::<u64>(&absent, None, &root).unwrap();
+            assert!(proof.verify(&absent, Some(&0u64), &root).is_err());
+            at_other_leaf += proof.other_leaf.is_some() as usize;
+        }
+        assert!(0 < at_other_leaf && at_other_leaf < 50);
+
+        let mut tampered = tree.prove(&keys[0]);
+        tampered.siblings.pop();
+        assert!(tampered.verify(&keys[0], Some(&0u64), &root).is_err());
+
+        // The only leaf of a tree is its root, and has no siblings
+        let mut single = SparseMerkleTree::new();
+        single.insert(keys[0], 0u64);
+        let proof = single.prove(&keys[0]);
+        assert!(proof.siblings.is_empty());
+        proof.verify(&keys[0], Some(&0u64), &single.root()).unwrap();
+        single
+            .prove(&keys[1])
+            .verify::<u64>(&keys[1], None, &single.root())
+            .unwrap();
+    }
+}
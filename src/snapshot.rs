@@ -0,0 +1,193 @@
+use std::{fs, path::Path};
+
+use anyhow::{Context, Result, ensure};
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::Block,
+    blockchain::Finalized,
+    genesis::GenesisConfig,
+    util::{BlockPtr, FromBytes, SerToBytes, Sha256Hash, hash},
+};
+
+// Snapshot files start with this, followed by the hash of the snapshot and the snapshot itself
+const SNAPSHOT_MAGIC: &[u8; 8] = b"LASSNAP1";
+
+/// A blockchain at its final block, which a node can start from instead of replaying every block since genesis.
+/// The ledgers are taken as they are, only the blocks from a seed age below the final block are included,
+/// as they are what is needed for the seeds and static ledgers of the blocks after it.
+/// Importing checks that the snapshot is consistent, not that it is the chain of the network,
+/// so a snapshot is trusted as it is unless its hash is checked against a trusted one
+#[derive(Clone, Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub struct ChainSnapshot {
+    pub genesis: GenesisConfig,
+    pub genesis_block: Block,
+    pub best_path: Vec<BlockPtr>, // Up to and including the final block
+    pub blocks: Vec<Block>, // The best path from a seed age below the final block, except genesis
+    pub finalized: Finalized,
+}
+
+impl ChainSnapshot {
+    pub fn final_ptr(&self) -> &BlockPtr {
+        &self.finalized.block_ptr
+    }
+
+    pub fn hash(&self) -> Sha256Hash {
+        hash(&self.into_bytes())
+    }
+
+    /// Checks the snapshot against a hash from a trusted source, such as another node of the operator
+    pub fn check_hash(&self, expected: &Sha256Hash) -> Result<()> {
+        ensure!(
+            self.hash() == *expected,
+            "Snapshot hash {} is not the expected {}",
+            HEXLOWER.encode(&self.hash()),
+            HEXLOWER.encode(expected)
+        );
+        Ok(())
+    }
+
+    /// The contents of a snapshot file, the hash lets a corrupted file be detected before it is used
+    pub fn to_file_bytes(&self) -> Vec<u8> {
+        let payload = self.into_bytes();
+        let mut bytes = Vec::with_capacity(SNAPSHOT_MAGIC.len() + 32 + payload.len());
+        bytes.extend_from_slice(SNAPSHOT_MAGIC);
+        bytes.extend_from_slice(&hash(&payload));
+        bytes.extend_from_slice(&payload);
+        bytes
+    }
+
+    pub fn from_file_bytes(bytes: &[u8]) -> Result<Self> {
+        let payload = bytes
+            .strip_prefix(SNAPSHOT_MAGIC)
+            .context("Not a snapshot file")?;
+        ensure!(payload.len() >= 32, "Snapshot file is too short");
+        let (checksum, payload) = payload.split_at(32);
+        ensure!(hash(payload) == checksum, "Snapshot file is corrupted");
+        Self::from_bytes(payload)
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        fs::write(path, self.to_file_bytes())?;
+        Ok(())
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)
+            .with_context(|| format!("Unable to read snapshot file {}", path.display()))?;
+        Self::from_file_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Las, blockchain::Blockchain, draw::Seed, keys::SecretKey, mempool::MIN_RELAY_FEE,
+        params::ChainParams, transaction::Transaction,
+    };
+    use pretty_assertions::assert_eq;
+
+    fn mine_blocks(blockchain: &mut Blockchain, sk: &SecretKey, n: usize) {
+        let receiver = SecretKey::generate().get_public_key();
+        for _ in 0..n {
+            let nonce = blockchain.dynamic_ledger.get_nonce(&sk.get_public_key());
+            let transaction = Transaction::new(sk, receiver.clone(), Las(1), MIN_RELAY_FEE, nonce);
+            blockchain.add_transaction(transaction).unwrap();
+            let block = blockchain.make_next_block(sk);
+            blockchain.add_block(block).unwrap();
+        }
+    }
+
+    fn start(sk: &SecretKey) -> (Blockchain, Block) {
+        let genesis =
+            GenesisConfig::with_root_accounts(ChainParams::devnet(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, sk);
+        (
+            Blockchain::start(genesis, genesis_block.clone()),
+            genesis_block,
+        )
+    }
+
+    #[test]
+    fn test_start_from_snapshot() {
+        let params = ChainParams::devnet();
+        let sk = SecretKey::generate();
+        let (mut blockchain, genesis_block) = start(&sk);
+        mine_blocks(&mut blockchain, &sk, 3 * params.seed_age as usize);
+
+        let snapshot = blockchain.snapshot().unwrap();
+        assert_eq!(snapshot.final_ptr(), &blockchain.finalized.block_ptr);
+        assert_eq!(snapshot.blocks.len(), params.seed_age as usize + 1);
+        let snapshot = ChainSnapshot::from_file_bytes(&snapshot.to_file_bytes()).unwrap();
+
+        let mut imported = Blockchain::start_from_snapshot(snapshot, &genesis_block).unwrap();
+        assert_eq!(imported.best_path_head(), &blockchain.finalized.block_ptr);
+        imported.verify_chain().unwrap();
+
+        // The blocks after the final block are added as if the chain was replayed from genesis
+        let final_depth = blockchain.finalized.block_ptr.depth as usize;
+        for ptr in blockchain.best_path[final_depth + 1..].iter() {
+            let block = blockchain.get_block(ptr).unwrap().clone();
+            imported.add_block(block).unwrap();
+        }
+        assert_eq!(imported, blockchain);
+    }
+
+    #[test]
+    fn test_invalid_snapshots_are_rejected() {
+        let params = ChainParams::devnet();
+        let sk = SecretKey::generate();
+        let (mut blockchain, genesis_block) = start(&sk);
+        mine_blocks(&mut blockchain, &sk, 2 * params.seed_age as usize);
+        let snapshot = blockchain.snapshot().unwrap();
+
+        let (_, other_genesis) = start(&SecretKey::generate());
+        let err = Blockchain::start_from_snapshot(snapshot.clone(), &other_genesis).unwrap_err();
+        assert!(err.to_string().contains("genesis"));
+
+        let mut bytes = snapshot.to_file_bytes();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        let err = ChainSnapshot::from_file_bytes(&bytes).unwrap_err();
+        assert!(err.to_string().contains("corrupted"));
+
+        // A snapshot with a ledger that does not match its blocks is rejected even with a valid hash
+        let mut tampered = snapshot.clone();
+        tampered.finalized.dynamic_ledger = tampered.finalized.static_ledger.clone();
+        let tampered = ChainSnapshot::from_file_bytes(&tampered.to_file_bytes()).unwrap();
+        let tampered_hash = tampered.hash();
+        assert!(Blockchain::start_from_snapshot(tampered, &genesis_block).is_err());
+
+        let mut missing_block = snapshot.clone();
+        missing_block.blocks.pop();
+        assert!(Blockchain::start_from_snapshot(missing_block, &genesis_block).is_err());
+
+        // A final block with a seed off the best path is rejected, even when it is signed by its producer
+        let mut wrong_seed = snapshot.clone();
+        let last = wrong_seed.blocks.pop().unwrap();
+        let seed = Seed {
+            block_ptr: wrong_seed.best_path[1].clone(),
+        };
+        assert_ne!(last.header.draw.seed, seed);
+        let resigned = Block::new(
+            last.header.timeslot,
+            last.header.prev_hash,
+            last.header.depth,
+            last.transactions,
+            last.header.state_root,
+            &sk,
+            seed,
+        );
+        *wrong_seed.best_path.last_mut().unwrap() = resigned.ptr();
+        wrong_seed.finalized.block_ptr = resigned.ptr();
+        wrong_seed.blocks.push(resigned);
+        let err = Blockchain::start_from_snapshot(wrong_seed, &genesis_block).unwrap_err();
+        assert!(err.to_string().contains("Seed of a block"));
+
+        snapshot.check_hash(&snapshot.hash()).unwrap();
+        assert!(snapshot.check_hash(&tampered_hash).is_err());
+    }
+}
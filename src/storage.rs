@@ -0,0 +1,689 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Result, anyhow, ensure};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::Block,
+    blockchain::Blockchain,
+    genesis::GenesisConfig,
+    snapshot::ChainSnapshot,
+    util::{BlockPtr, FromBytes, SerToBytes, Sha256Hash, hash},
+};
+
+const LOG_FILE: &str = "blocks.log";
+const BEST_PATH_FILE: &str = "best_path";
+
+// Every record is prefixed by the length of the payload and the hash of the payload
+const RECORD_HEADER_LEN: usize = 4 + 32;
+// Once this many blocks were appended since the last compaction, the log is rewritten to start at the final block
+pub const COMPACTION_INTERVAL: usize = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Record {
+    Genesis { config: GenesisConfig, block: Block },
+    Block(Block),
+    // Replaces the genesis record for a store started from a snapshot or compacted
+    Snapshot(Box<ChainSnapshot>),
+}
+
+/// Append only log of every block `Blockchain::add_block` added to the chain, in an order they can be added in.
+/// Orphans are only logged once their parent arrives, as which of them the pool dropped is not logged.
+/// On startup the log is replayed in order, which rebuilds the same blocks and best path.
+/// A record that was only partially written when the node crashed is detected by its hash and cut off.
+/// The best path is stored next to the log, and the replay must end on it.
+/// The log is compacted to a snapshot at the final block followed by the blocks above it, so it does not grow
+/// forever and a restart only replays the blocks that are not final.
+pub struct BlockStore {
+    dir: PathBuf,
+    log: File,
+    // Blocks appended since the log was last compacted, the blocks a compaction keeps do not count
+    appended: usize,
+    // The first write that failed, the chain in memory may since have blocks that the log does not
+    failed: Option<String>,
+}
+
+impl BlockStore {
+    pub fn create(
+        dir: impl AsRef<Path>,
+        genesis: GenesisConfig,
+        genesis_block: Block,
+    ) -> Result<(Self, Blockchain)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let log = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))?;
+
+        let mut store = Self {
+            dir,
+            log,
+            appended: 0,
+            failed: None,
+        };
+        let blockchain = Blockchain::start(genesis.clone(), genesis_block.clone());
+        store.append(&Record::Genesis {
+            config: genesis,
+            block: genesis_block,
+        })?;
+        store.write_best_path(&blockchain.best_path)?;
+
+        Ok((store, blockchain))
+    }
+
+    /// Creates a store that starts at the final block of the snapshot instead of genesis
+    pub fn create_from_snapshot(
+        dir: impl AsRef<Path>,
+        snapshot: ChainSnapshot,
+        genesis_block: &Block,
+    ) -> Result<(Self, Blockchain)> {
+        let blockchain = Blockchain::start_from_snapshot(snapshot.clone(), genesis_block)?;
+
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let log = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(dir.join(LOG_FILE))?;
+
+        let mut store = Self {
+            dir,
+            log,
+            appended: 0,
+            failed: None,
+        };
+        store.append(&Record::Snapshot(Box::new(snapshot)))?;
+        store.write_best_path(&blockchain.best_path)?;
+
+        Ok((store, blockchain))
+    }
+
+    pub fn open(dir: impl AsRef<Path>) -> Result<(Self, Blockchain)> {
+        let dir = dir.as_ref().to_path_buf();
+        let (records, valid_len) = read_log(&dir.join(LOG_FILE))?;
+
+        let mut records = records.into_iter();
+        let mut blockchain = match records.next() {
+            Some(Record::Genesis { config, block }) => Blockchain::start(config, block),
+            Some(Record::Snapshot(snapshot)) => {
+                // The genesis block was checked when the store was created
+                let genesis_block = snapshot.genesis_block.clone();
+                Blockchain::start_from_snapshot(*snapshot, &genesis_block)?
+            }
+            _ => return Err(anyhow!("Block log does not start with a genesis block")),
+        };
+
+        let mut blocks = records
+            .map(|record| match record {
+                Record::Block(block) => Ok(block),
+                _ => Err(anyhow!("Unexpected genesis block in the middle of the log")),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // Which of the blocks a compaction kept is not known, so a log with many of them is compacted once more
+        let appended = blocks.len();
+        let last_block = blocks.pop();
+        let is_new = last_block.is_none();
+        for block in blocks {
+            blockchain.add_block(block)?;
+        }
+        let previous_best_path = blockchain.best_path.clone();
+        if let Some(block) = last_block {
+            blockchain.add_block(block)?;
+        }
+
+        let log = OpenOptions::new().append(true).open(dir.join(LOG_FILE))?;
+        let mut store = Self {
+            dir,
+            log,
+            appended,
+            failed: None,
+        };
+
+        // The best path is written after the block is appended, so it can lag behind the log by one block.
+        // Anything else means the log no longer replays to the chain the node had, e.g. after a change of
+        // the consensus rules
+        let stored_best_path = match store.read_best_path() {
+            Ok(best_path) => best_path,
+            // The node crashed before the best path of a new store was written
+            Err(_) if is_new => previous_best_path.clone(),
+            Err(e) => return Err(e.context("Unable to read the stored best path")),
+        };
+        ensure!(
+            stored_best_path == blockchain.best_path || stored_best_path == previous_best_path,
+            "Replaying the block log does not result in the stored best path"
+        );
+        if stored_best_path != blockchain.best_path {
+            store.write_best_path(&blockchain.best_path)?;
+        }
+        if store.log.metadata()?.len() > valid_len {
+            // The node crashed while appending the last record
+            store.log.set_len(valid_len)?;
+            store.log.sync_all()?;
+        }
+
+        Ok((store, blockchain))
+    }
+
+    pub fn exists(dir: impl AsRef<Path>) -> bool {
+        dir.as_ref().join(LOG_FILE).exists()
+    }
+
+    /// Appends a block that was added to the chain, its parent must already be in the log
+    pub fn append_block(&mut self, block: &Block) -> Result<()> {
+        self.append(&Record::Block(block.clone()))?;
+        self.appended += 1;
+        Ok(())
+    }
+
+    /// Must be called for every block that `Blockchain::add_block` accepted and was not in the chain before.
+    /// Appends the block, if it is in the chain, followed by the orphans it connected
+    pub fn append_added_block(&mut self, blockchain: &Blockchain, block: &Block) -> Result<()> {
+        if blockchain.get_block(&block.ptr()).is_none() {
+            return Ok(());
+        }
+        self.append_block(block)?;
+
+        // The block is new, so every block above it on one of its paths was an orphan until now
+        let mut parents = HashSet::from([block.header.hash]);
+        for blocks in blockchain
+            .blocks
+            .iter()
+            .skip(block.header.depth as usize + 1)
+        {
+            let children = blocks
+                .values()
+                .filter(|child| parents.contains(&child.header.prev_hash))
+                .collect::<Vec<_>>();
+            if children.is_empty() {
+                break;
+            }
+            for child in children.iter() {
+                self.append_block(child)?;
+            }
+            parents = children.iter().map(|child| child.header.hash).collect();
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, record: &Record) -> Result<()> {
+        let bytes = encode(record);
+        self.write(|store| {
+            store.log.write_all(&bytes)?;
+            store.log.sync_data()?;
+            Ok(())
+        })
+    }
+
+    /// Fails once a write failed, after which the store refuses to write until the node is restarted.
+    /// Must be checked before changing the chain in memory, as the change could not be stored
+    pub fn check(&self) -> Result<()> {
+        match &self.failed {
+            Some(e) => Err(anyhow!("Block store stopped after a failed write: {e}")),
+            None => Ok(()),
+        }
+    }
+
+    // Writing after a failed write could log a block whose parent is missing from the log, which would then
+    // no longer replay to the stored best path
+    fn write(&mut self, f: impl FnOnce(&mut Self) -> Result<()>) -> Result<()> {
+        self.check()?;
+        f(self).inspect_err(|e| self.failed = Some(e.to_string()))
+    }
+
+    /// Compacts the log once `COMPACTION_INTERVAL` blocks were appended since it was last compacted
+    pub fn compact_if_due(&mut self, blockchain: &Blockchain) -> Result<()> {
+        if self.appended >= COMPACTION_INTERVAL {
+            self.compact(blockchain)?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the log with a snapshot at the final block of `blockchain`, which must be the chain of this
+    /// store, followed by the best path above it and the forks in an order they can be added in.
+    /// The new log is written next to the old one and moved over it, so a crash leaves one of the two
+    pub fn compact(&mut self, blockchain: &Blockchain) -> Result<()> {
+        let final_depth = blockchain.finalized.block_ptr.depth as usize;
+        let best_blocks = blockchain.best_path[final_depth + 1..]
+            .iter()
+            .map(|ptr| blockchain.get_block(ptr).ok_or(anyhow!("invalid deref")))
+            .collect::<Result<Vec<_>>>()?;
+        let side_blocks = blockchain.blocks[final_depth + 1..]
+            .iter()
+            .flat_map(|blocks| blocks.values())
+            .filter(|block| !best_blocks.contains(block));
+        let blocks = best_blocks
+            .iter()
+            .copied()
+            .chain(side_blocks)
+            .collect::<Vec<_>>();
+
+        let mut bytes = encode(&Record::Snapshot(Box::new(blockchain.snapshot()?)));
+        for block in blocks.iter() {
+            bytes.extend_from_slice(&encode(&Record::Block((*block).clone())));
+        }
+
+        self.write(|store| {
+            let tmp_path = store.dir.join(format!("{LOG_FILE}.tmp"));
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&bytes)?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, store.dir.join(LOG_FILE))?;
+            File::open(&store.dir)?.sync_all()?;
+
+            store.log = OpenOptions::new()
+                .append(true)
+                .open(store.dir.join(LOG_FILE))?;
+            store.appended = 0;
+            Ok(())
+        })
+    }
+
+    /// Atomically replaces the stored best path, a crash leaves either the old or the new one
+    pub fn write_best_path(&mut self, best_path: &[BlockPtr]) -> Result<()> {
+        let payload = best_path.to_vec().into_bytes();
+        self.write(|store| {
+            let tmp_path = store.dir.join(format!("{BEST_PATH_FILE}.tmp"));
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&hash(&payload))?;
+            file.write_all(&payload)?;
+            file.sync_all()?;
+            fs::rename(tmp_path, store.dir.join(BEST_PATH_FILE))?;
+            Ok(())
+        })
+    }
+
+    pub fn read_best_path(&self) -> Result<Vec<BlockPtr>> {
+        let bytes = fs::read(self.dir.join(BEST_PATH_FILE))?;
+        ensure!(bytes.len() >= 32, "Best path file is too short");
+        let (checksum, payload) = bytes.split_at(32);
+        ensure!(hash(payload) == checksum, "Best path file is corrupted");
+        Vec::<BlockPtr>::from_bytes(payload)
+    }
+}
+
+fn encode(record: &Record) -> Vec<u8> {
+    let payload = record.into_bytes();
+    let mut bytes = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&hash(&payload));
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+/// Reads all complete records and returns how many bytes they take, a torn last record is left out.
+/// Any other damage is an error, as dropping it would also drop every valid record after it
+fn read_log(path: &Path) -> Result<(Vec<Record>, u64)> {
+    let bytes = fs::read(path)?;
+
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let Some((record, len)) = read_record(&bytes[offset..])
+            .map_err(|e| anyhow!("Block log is corrupted at offset {offset}: {e}"))?
+        else {
+            break;
+        };
+        records.push(record);
+        offset += len;
+    }
+
+    Ok((records, offset as u64))
+}
+
+/// The first record and its length including the header, `None` if it is the last one and was not
+/// completely written
+fn read_record(bytes: &[u8]) -> Result<Option<(Record, usize)>> {
+    if bytes.len() < RECORD_HEADER_LEN {
+        return Ok(None);
+    }
+
+    let len = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+    let checksum: Sha256Hash = bytes[4..RECORD_HEADER_LEN].try_into().unwrap();
+    let end = RECORD_HEADER_LEN + len;
+    let Some(payload) = bytes.get(RECORD_HEADER_LEN..end) else {
+        return Ok(None);
+    };
+    if hash(payload) != checksum {
+        // Only the last record can be partially written, its pages may reach the disk in any order
+        ensure!(end == bytes.len(), "Checksum mismatch");
+        return Ok(None);
+    }
+
+    Ok(Some((Record::from_bytes(payload)?, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        keys::SecretKey,
+        orphan_pool::{OrphanLimits, OrphanPool},
+        params::ChainParams,
+    };
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lasagna-store-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn store_with_blocks(dir: &Path, n: usize) -> (SecretKey, Blockchain) {
+        let sk = SecretKey::generate();
+        let genesis =
+            GenesisConfig::with_root_accounts(ChainParams::devnet(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+        let (mut store, mut blockchain) = BlockStore::create(dir, genesis, genesis_block).unwrap();
+
+        for _ in 0..n {
+            let block = blockchain.make_next_block(&sk);
+            blockchain.add_block(block.clone()).unwrap();
+            store.append_block(&block).unwrap();
+            store.write_best_path(&blockchain.best_path).unwrap();
+        }
+
+        (sk, blockchain)
+    }
+
+    #[test]
+    fn test_restart() {
+        let dir = temp_dir();
+        let (_, blockchain) = store_with_blocks(&dir, 10);
+
+        let (store, restored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(restored, blockchain);
+        assert_eq!(store.read_best_path().unwrap(), blockchain.best_path);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_connected_orphans_are_restored() {
+        let dir = temp_dir();
+        let (sk, mut blockchain) = store_with_blocks(&dir, 3);
+
+        let mut ahead = blockchain.clone();
+        let parent = ahead.make_next_block(&sk);
+        ahead.add_block(parent.clone()).unwrap();
+        let orphan = ahead.make_next_block(&sk);
+
+        let (mut store, _) = BlockStore::open(&dir).unwrap();
+        blockchain.add_block(orphan.clone()).unwrap();
+        store.append_added_block(&blockchain, &orphan).unwrap();
+        assert_eq!(blockchain.orphans.len(), 1);
+
+        // An orphan is not logged while it waits for its parent
+        let (_, restored) = BlockStore::open(&dir).unwrap();
+        assert!(restored.orphans.is_empty());
+        assert_eq!(restored.best_path, blockchain.best_path);
+
+        // Once the parent arrives, the orphan is logged after it
+        blockchain.add_block(parent.clone()).unwrap();
+        store.append_added_block(&blockchain, &parent).unwrap();
+        store.write_best_path(&blockchain.best_path).unwrap();
+        assert_eq!(blockchain.best_path_head(), &orphan.ptr());
+        let (_, restored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(restored, blockchain);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_dropped_orphans_are_not_replayed() {
+        let dir = temp_dir();
+        let (sk, mut blockchain) = store_with_blocks(&dir, 3);
+        blockchain.orphans = OrphanPool::new(OrphanLimits {
+            max_count: 2,
+            expiry: 1,
+            ..Default::default()
+        });
+
+        // The first block of a chain is missing, so the others fill the pool until the deepest are dropped
+        let mut ahead = blockchain.clone();
+        let missing = ahead.make_next_block(&sk);
+        ahead.add_block(missing.clone()).unwrap();
+        let (mut store, _) = BlockStore::open(&dir).unwrap();
+        let mut last_timeslot = missing.header.timeslot;
+        for _ in 0..4 {
+            let orphan = ahead.make_next_block(&sk);
+            ahead.add_block(orphan.clone()).unwrap();
+            last_timeslot = orphan.header.timeslot;
+            if blockchain.add_block(orphan.clone()).is_ok() {
+                store.append_added_block(&blockchain, &orphan).unwrap();
+            }
+        }
+        assert_eq!(blockchain.orphans.len(), 2);
+        blockchain.orphans.expire(last_timeslot + 2);
+        assert!(blockchain.orphans.is_empty());
+
+        // The missing block arrives after its children are gone, replaying must not bring them back
+        blockchain.add_block(missing.clone()).unwrap();
+        store.append_added_block(&blockchain, &missing).unwrap();
+        store.write_best_path(&blockchain.best_path).unwrap();
+        assert_eq!(blockchain.best_path_head(), &missing.ptr());
+        let (_, restored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(restored.best_path, blockchain.best_path);
+        assert!(restored.orphans.is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_start_from_snapshot() {
+        let params = ChainParams::devnet();
+        let dir = temp_dir();
+        let (sk, blockchain) = store_with_blocks(&dir, 2 * params.seed_age as usize);
+        let genesis_block = blockchain.get_block(&blockchain.best_path[0]).unwrap();
+
+        let snapshot_dir = temp_dir();
+        let (mut store, mut imported) = BlockStore::create_from_snapshot(
+            &snapshot_dir,
+            blockchain.snapshot().unwrap(),
+            genesis_block,
+        )
+        .unwrap();
+        let block = imported.make_next_block(&sk);
+        imported.add_block(block.clone()).unwrap();
+        store.append_block(&block).unwrap();
+
+        let (_, restored) = BlockStore::open(&snapshot_dir).unwrap();
+        assert_eq!(restored, imported);
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(snapshot_dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_write_is_truncated() {
+        let dir = temp_dir();
+        let (sk, blockchain) = store_with_blocks(&dir, 5);
+        let log_path = dir.join(LOG_FILE);
+        let valid_len = fs::metadata(&log_path).unwrap().len();
+
+        // Simulate a crash in the middle of appending a block
+        let block = blockchain.make_next_block(&sk);
+        let payload = Record::Block(block).into_bytes();
+        let mut log = OpenOptions::new().append(true).open(&log_path).unwrap();
+        log.write_all(&(payload.len() as u32).to_le_bytes())
+            .unwrap();
+        log.write_all(&hash(&payload)).unwrap();
+        log.write_all(&payload[..payload.len() / 2]).unwrap();
+        drop(log);
+
+        let (mut store, restored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(restored, blockchain);
+        assert_eq!(fs::metadata(&log_path).unwrap().len(), valid_len);
+
+        // The store is usable after the recovery
+        let block = restored.make_next_block(&sk);
+        store.append_block(&block).unwrap();
+        let (_, restored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(restored.best_path.len(), blockchain.best_path.len() + 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_last_record_is_dropped_after_a_crash() {
+        let dir = temp_dir();
+        let (_, blockchain) = store_with_blocks(&dir, 5);
+        let log_path = dir.join(LOG_FILE);
+        let previous_best_path = &blockchain.best_path[..blockchain.best_path.len() - 1];
+
+        // A crash while the last block was written, so its best path was not written yet
+        let (mut store, _) = BlockStore::open(&dir).unwrap();
+        store.write_best_path(previous_best_path).unwrap();
+        let mut bytes = fs::read(&log_path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&log_path, bytes).unwrap();
+
+        let (_, restored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(restored.best_path, previous_best_path);
+
+        // Once the best path includes the last block, the block was complete and the damage is not a crash
+        let (mut store, _) = BlockStore::open(&dir).unwrap();
+        let block = blockchain.get_block(blockchain.best_path_head()).unwrap();
+        store.append_block(block).unwrap();
+        store.write_best_path(&blockchain.best_path).unwrap();
+        let mut bytes = fs::read(&log_path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&log_path, bytes).unwrap();
+        assert!(BlockStore::open(&dir).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_record_in_the_middle_is_an_error() {
+        let dir = temp_dir();
+        store_with_blocks(&dir, 5);
+        let log_path = dir.join(LOG_FILE);
+        let len = fs::metadata(&log_path).unwrap().len();
+
+        let mut bytes = fs::read(&log_path).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 0xff;
+        fs::write(&log_path, bytes).unwrap();
+
+        // The valid blocks after the damaged one are kept for the operator to recover
+        let err = BlockStore::open(&dir).err().unwrap();
+        assert!(err.to_string().contains("corrupted"), "{err}");
+        assert_eq!(fs::metadata(&log_path).unwrap().len(), len);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_best_path_is_checked() {
+        let dir = temp_dir();
+        let (sk, blockchain) = store_with_blocks(&dir, 5);
+
+        // A crash between appending a block and writing the best path leaves it one block behind
+        let (mut store, _) = BlockStore::open(&dir).unwrap();
+        let block = blockchain.make_next_block(&sk);
+        store.append_block(&block).unwrap();
+        let (mut store, restored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(restored.best_path_head(), &block.ptr());
+        assert_eq!(store.read_best_path().unwrap(), restored.best_path);
+
+        // But a log that replays to another chain is not silently accepted
+        store.write_best_path(&blockchain.best_path[..3]).unwrap();
+        let err = BlockStore::open(&dir).err().unwrap();
+        assert!(err.to_string().contains("best path"), "{err}");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_compaction() {
+        let params = ChainParams::devnet();
+        let dir = temp_dir();
+        let (sk, mut blockchain) = store_with_blocks(&dir, 3 * params.seed_age as usize);
+        let log_path = dir.join(LOG_FILE);
+        let len = fs::metadata(&log_path).unwrap().len();
+
+        // A fork above the final block is kept
+        let mut fork = blockchain.clone();
+        let head = fork.best_path_head().clone();
+        let head_timeslot = fork.get_block(&head).unwrap().header.timeslot;
+        fork.rollback_block(&head).unwrap();
+        let fork_block = (head_timeslot + 1..)
+            .find_map(|timeslot| fork.make_block_at(&sk, timeslot))
+            .unwrap();
+        fork.add_block(fork_block.clone()).unwrap();
+
+        let (mut store, _) = BlockStore::open(&dir).unwrap();
+        blockchain.add_block(fork_block.clone()).unwrap();
+        store.append_added_block(&blockchain, &fork_block).unwrap();
+        assert_eq!(blockchain.blocks[fork_block.header.depth as usize].len(), 2);
+
+        store.compact(&blockchain).unwrap();
+        assert!(fs::metadata(&log_path).unwrap().len() < len);
+        let (mut store, restored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(restored, blockchain);
+
+        // The compacted log is appended to like any other
+        let block = restored.make_next_block(&sk);
+        blockchain.add_block(block.clone()).unwrap();
+        store.append_block(&block).unwrap();
+        store.write_best_path(&blockchain.best_path).unwrap();
+        let (_, restored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(restored, blockchain);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_kept_blocks_do_not_count_towards_compaction() {
+        let dir = temp_dir();
+        let (sk, mut blockchain) = store_with_blocks(&dir, 5);
+        let (mut store, _) = BlockStore::open(&dir).unwrap();
+        assert_eq!(store.appended, 5);
+
+        // The blocks above the final block are in the new log, but were not appended since
+        store.compact(&blockchain).unwrap();
+        assert_eq!(store.appended, 0);
+        let block = blockchain.make_next_block(&sk);
+        blockchain.add_block(block.clone()).unwrap();
+        store.append_added_block(&blockchain, &block).unwrap();
+        assert_eq!(store.appended, 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_writes_stop_after_a_failed_write() {
+        let dir = temp_dir();
+        let (sk, mut blockchain) = store_with_blocks(&dir, 3);
+        let (mut store, _) = BlockStore::open(&dir).unwrap();
+
+        // A log that can not be written to, like on a full disk
+        store.log = File::open(dir.join(LOG_FILE)).unwrap();
+        let block = blockchain.make_next_block(&sk);
+        blockchain.add_block(block.clone()).unwrap();
+        assert!(store.append_added_block(&blockchain, &block).is_err());
+
+        // Once the disk works again, the children of the block that is missing from the log are still refused
+        store.log = OpenOptions::new()
+            .append(true)
+            .open(dir.join(LOG_FILE))
+            .unwrap();
+        let child = blockchain.make_next_block(&sk);
+        blockchain.add_block(child.clone()).unwrap();
+        assert!(store.check().is_err());
+        assert!(store.append_added_block(&blockchain, &child).is_err());
+        assert!(store.write_best_path(&blockchain.best_path).is_err());
+
+        let (_, restored) = BlockStore::open(&dir).unwrap();
+        assert_eq!(restored.best_path, blockchain.best_path[..4]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,464 @@
+use std::fmt;
+
+use actix::Addr;
+use anyhow::{Result, anyhow, ensure};
+use iroh::{
+    Endpoint, NodeId,
+    endpoint::Connection,
+    protocol::{AcceptError, ProtocolHandler},
+};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::task::JoinSet;
+
+use crate::{
+    actors::blockchain_actor::{
+        AddBlock, AddBlockFrom, BlockchainActor, GetBestHead, GetBlock, GetBodies, GetChainParams,
+        GetHeaders, HasBlock, ValidateHeaders,
+    },
+    block::Block,
+    blockchain::MAX_BLOCK_SIZE,
+    header::BlockHeader,
+    transaction::Transaction,
+    util::{BlockPtr, FromBytes, SerToBytes},
+};
+
+pub const SYNC_ALPN: &[u8] = b"lasagna/sync/0";
+pub const HEADERS_PER_REQUEST: usize = 512;
+pub const BODIES_PER_REQUEST: usize = 32;
+// Fetched ancestors are kept in memory until the oldest connects, a longer gap is fetched in several rounds
+pub const MAX_FETCHED_ANCESTORS: usize = 32;
+
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+const MAX_RESPONSE_SIZE: usize = BODIES_PER_REQUEST * MAX_BLOCK_SIZE;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum SyncRequest {
+    Headers { from_depth: i64, count: usize },
+    Bodies(Vec<BlockPtr>),
+    Block(BlockPtr),
+}
+
+/// Somewhere headers and transactions of blocks on the best path can be downloaded from
+pub trait BlockSource: Clone + Send + Sync + 'static {
+    fn get_headers(
+        &self,
+        from_depth: i64,
+        count: usize,
+    ) -> impl Future<Output = Result<Vec<BlockHeader>>> + Send;
+
+    /// The transactions of each block, in the same order
+    fn get_bodies(
+        &self,
+        ptrs: Vec<BlockPtr>,
+    ) -> impl Future<Output = Result<Vec<Vec<Transaction>>>> + Send;
+
+    /// A block that does not have to be on the best path
+    fn get_block(&self, ptr: BlockPtr) -> impl Future<Output = Result<Block>> + Send;
+}
+
+impl BlockSource for Addr<BlockchainActor> {
+    async fn get_headers(&self, from_depth: i64, count: usize) -> Result<Vec<BlockHeader>> {
+        Ok(self.send(GetHeaders { from_depth, count }).await?)
+    }
+
+    async fn get_bodies(&self, ptrs: Vec<BlockPtr>) -> Result<Vec<Vec<Transaction>>> {
+        self.send(GetBodies(ptrs))
+            .await?
+            .ok_or(anyhow!("Source does not have the blocks"))
+    }
+
+    async fn get_block(&self, ptr: BlockPtr) -> Result<Block> {
+        self.send(GetBlock(ptr))
+            .await?
+            .ok_or(anyhow!("Source does not have the block"))
+    }
+}
+
+/// Downloads and validates the chain of headers first, so a source can't make us download bodies of invalid blocks.
+/// The bodies are then fetched in parallel, spread over all the sources, and the blocks are added in order.
+/// Returns the number of blocks that were added.
+pub async fn headers_first_sync<S: BlockSource>(
+    blockchain: &Addr<BlockchainActor>,
+    sources: &[S],
+) -> Result<usize> {
+    ensure!(!sources.is_empty(), "No sources to sync from");
+
+    let seed_age = blockchain.send(GetChainParams).await?.seed_age;
+    let mut added = 0;
+    let mut next_depth = None;
+    loop {
+        // Start a bit behind our best head, so a source on a fork shallower than the seed age still connects
+        let from_depth = match next_depth {
+            Some(depth) => depth,
+            None => (blockchain.send(GetBestHead).await?.depth - seed_age).max(0),
+        };
+
+        let headers = longest_headers(sources, from_depth).await;
+        let new_headers = blockchain.send(ValidateHeaders(headers)).await??;
+        let Some(last) = new_headers.last() else {
+            return Ok(added);
+        };
+        let last_depth = last.depth;
+
+        let bodies = fetch_bodies(sources, &new_headers).await?;
+        for (header, transactions) in new_headers.into_iter().zip(bodies) {
+            let block = Block::from_parts(header, transactions)?;
+            blockchain.send(AddBlock(block)).await??;
+            added += 1;
+        }
+
+        next_depth = Some(last_depth + 1);
+    }
+}
+
+/// Fetches `parent`, the missing parent of an orphan, and its ancestors until one we have, from the first
+/// source that has each of them. Unlike `headers_first_sync`, the blocks can be on any fork of the sources.
+/// The blocks are added oldest first, if the gap is longer than `MAX_FETCHED_ANCESTORS` the oldest one is an
+/// orphan again and its parent is requested in turn, from `peer` if the blocks came from it.
+/// Returns the number of blocks that were added.
+pub async fn fetch_ancestors<S: BlockSource>(
+    blockchain: &Addr<BlockchainActor>,
+    sources: &[S],
+    parent: BlockPtr,
+    peer: Option<NodeId>,
+) -> Result<usize> {
+    ensure!(!sources.is_empty(), "No sources to fetch from");
+
+    let mut blocks = Vec::new();
+    let mut next = parent;
+    while blocks.len() < MAX_FETCHED_ANCESTORS && !blockchain.send(HasBlock(next.clone())).await? {
+        let block = fetch_block(sources, &next).await?;
+        let is_genesis = block.header.depth == 0;
+        next = BlockPtr::new(block.header.prev_hash, block.header.depth - 1);
+        blocks.push(block);
+        if is_genesis {
+            break;
+        }
+    }
+
+    let added = blocks.len();
+    for block in blocks.into_iter().rev() {
+        match peer {
+            Some(peer) => blockchain.send(AddBlockFrom(block, peer)).await??,
+            None => blockchain.send(AddBlock(block)).await??,
+        }
+    }
+    Ok(added)
+}
+
+// The block from the first source that has it, a source answering with another block is skipped
+async fn fetch_block<S: BlockSource>(sources: &[S], ptr: &BlockPtr) -> Result<Block> {
+    for source in sources {
+        if let Ok(block) = source.get_block(ptr.clone()).await
+            && block.ptr() == *ptr
+        {
+            return Ok(block);
+        }
+    }
+    Err(anyhow!("No source has the block"))
+}
+
+// The headers of the source that is furthest ahead, sources that fail to answer are skipped
+async fn longest_headers<S: BlockSource>(sources: &[S], from_depth: i64) -> Vec<BlockHeader> {
+    let mut longest = Vec::new();
+    for source in sources {
+        let Ok(headers) = source.get_headers(from_depth, HEADERS_PER_REQUEST).await else {
+            continue;
+        };
+        if headers.last().map(|h| h.depth) > longest.last().map(|h: &BlockHeader| h.depth) {
+            longest = headers;
+        }
+    }
+    longest
+}
+
+async fn fetch_bodies<S: BlockSource>(
+    sources: &[S],
+    headers: &[BlockHeader],
+) -> Result<Vec<Vec<Transaction>>> {
+    let mut tasks = JoinSet::new();
+    let batches = headers.chunks(BODIES_PER_REQUEST).collect::<Vec<_>>();
+    for (i, batch) in batches.iter().enumerate() {
+        let ptrs = batch.iter().map(BlockHeader::ptr).collect::<Vec<_>>();
+        // Every batch starts at a different source and falls back to the others
+        let sources = sources
+            .iter()
+            .cycle()
+            .skip(i)
+            .take(sources.len())
+            .cloned()
+            .collect::<Vec<_>>();
+        tasks.spawn(async move {
+            for source in sources {
+                if let Ok(bodies) = source.get_bodies(ptrs.clone()).await
+                    && bodies.len() == ptrs.len()
+                {
+                    return Ok((i, bodies));
+                }
+            }
+            Err(anyhow!("No source has the blocks"))
+        });
+    }
+
+    let mut bodies = vec![Vec::new(); batches.len()];
+    while let Some(result) = tasks.join_next().await {
+        let (i, batch) = result??;
+        bodies[i] = batch;
+    }
+
+    Ok(bodies.into_iter().flatten().collect())
+}
+
+/// Answers the sync requests of other nodes, every request is sent on its own stream
+#[derive(Clone)]
+pub struct SyncProtocol {
+    blockchain: Addr<BlockchainActor>,
+}
+
+impl SyncProtocol {
+    pub fn new(blockchain: Addr<BlockchainActor>) -> Self {
+        Self { blockchain }
+    }
+
+    async fn respond(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let response = match SyncRequest::from_bytes(request)? {
+            SyncRequest::Headers { from_depth, count } => {
+                let count = count.min(HEADERS_PER_REQUEST);
+                self.blockchain
+                    .get_headers(from_depth, count)
+                    .await?
+                    .into_bytes()
+            }
+            SyncRequest::Bodies(ptrs) => {
+                ensure!(
+                    ptrs.len() <= BODIES_PER_REQUEST,
+                    "Too many bodies requested"
+                );
+                self.blockchain.send(GetBodies(ptrs)).await?.into_bytes()
+            }
+            SyncRequest::Block(ptr) => self.blockchain.send(GetBlock(ptr)).await?.into_bytes(),
+        };
+        Ok(response)
+    }
+}
+
+impl fmt::Debug for SyncProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyncProtocol").finish_non_exhaustive()
+    }
+}
+
+impl ProtocolHandler for SyncProtocol {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+            let request = recv
+                .read_to_end(MAX_REQUEST_SIZE)
+                .await
+                .map_err(AcceptError::from_err)?;
+            // A peer sending invalid requests is not worth answering
+            let Ok(response) = self.respond(&request).await else {
+                break;
+            };
+            send.write_all(&response)
+                .await
+                .map_err(AcceptError::from_err)?;
+            send.finish().map_err(AcceptError::from_err)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A peer that is synced from over the sync protocol
+#[derive(Clone)]
+pub struct RemoteSource {
+    connection: Connection,
+}
+
+impl RemoteSource {
+    pub async fn connect(endpoint: &Endpoint, node_id: NodeId) -> Result<Self> {
+        let connection = endpoint.connect(node_id, SYNC_ALPN).await?;
+        Ok(Self { connection })
+    }
+
+    async fn request<T: DeserializeOwned>(&self, request: SyncRequest) -> Result<T> {
+        let (mut send, mut recv) = self.connection.open_bi().await?;
+        send.write_all(&request.into_bytes()).await?;
+        send.finish()?;
+        let bytes = recv.read_to_end(MAX_RESPONSE_SIZE).await?;
+        T::from_bytes(&bytes)
+    }
+}
+
+impl BlockSource for RemoteSource {
+    async fn get_headers(&self, from_depth: i64, count: usize) -> Result<Vec<BlockHeader>> {
+        self.request(SyncRequest::Headers { from_depth, count })
+            .await
+    }
+
+    async fn get_bodies(&self, ptrs: Vec<BlockPtr>) -> Result<Vec<Vec<Transaction>>> {
+        self.request::<Option<_>>(SyncRequest::Bodies(ptrs))
+            .await?
+            .ok_or(anyhow!("Source does not have the blocks"))
+    }
+
+    async fn get_block(&self, ptr: BlockPtr) -> Result<Block> {
+        self.request::<Option<_>>(SyncRequest::Block(ptr))
+            .await?
+            .ok_or(anyhow!("Source does not have the block"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use actix::Actor;
+
+    use super::*;
+    use crate::{
+        Las, blockchain::Blockchain, genesis::GenesisConfig, keys::SecretKey,
+        mempool::MIN_RELAY_FEE, params::ChainParams,
+    };
+
+    fn mine_blocks(blockchain: &mut Blockchain, sk: &SecretKey, n: usize) {
+        let receiver = SecretKey::generate().get_public_key();
+        for _ in 0..n {
+            let nonce = blockchain.dynamic_ledger.get_nonce(&sk.get_public_key());
+            let transaction = Transaction::new(sk, receiver.clone(), Las(1), MIN_RELAY_FEE, nonce);
+            blockchain.add_transaction(transaction).unwrap();
+            let block = blockchain.make_next_block(sk);
+            blockchain.add_block(block).unwrap();
+        }
+    }
+
+    // Hands out few headers at a time, so the sync has to make several rounds
+    #[derive(Clone)]
+    struct FewHeaders(Addr<BlockchainActor>);
+
+    impl BlockSource for FewHeaders {
+        async fn get_headers(&self, from_depth: i64, _: usize) -> Result<Vec<BlockHeader>> {
+            self.0.get_headers(from_depth, 16).await
+        }
+
+        async fn get_bodies(&self, ptrs: Vec<BlockPtr>) -> Result<Vec<Vec<Transaction>>> {
+            self.0.get_bodies(ptrs).await
+        }
+
+        async fn get_block(&self, ptr: BlockPtr) -> Result<Block> {
+            self.0.get_block(ptr).await
+        }
+    }
+
+    #[actix::test]
+    async fn test_sync_from_several_sources() {
+        let params = ChainParams::devnet();
+        let sk = SecretKey::generate();
+        let genesis = GenesisConfig::with_root_accounts(params.clone(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+        let empty = Blockchain::start(genesis, genesis_block);
+
+        // Short enough that the sources have not pruned any block yet
+        let len = 2 * params.seed_age as usize;
+        let mut synced = empty.clone();
+        mine_blocks(&mut synced, &sk, len);
+
+        // One source only knows the first half of the chain
+        let mut behind = empty.clone();
+        for ptr in synced.best_path[1..len / 2].iter() {
+            behind
+                .add_block(synced.get_block(ptr).unwrap().clone())
+                .unwrap();
+        }
+
+        let sources = [synced.clone(), behind, synced.clone()]
+            .map(|blockchain| FewHeaders(BlockchainActor::new(blockchain, sk.clone()).start()));
+        let node = BlockchainActor::new(empty, sk.clone()).start();
+
+        let added = headers_first_sync(&node, &sources).await.unwrap();
+        assert_eq!(added, len);
+        assert_eq!(
+            node.send(GetBestHead).await.unwrap(),
+            synced.best_path_head().clone()
+        );
+
+        // Nothing happens when we are already synced
+        assert_eq!(headers_first_sync(&node, &sources).await.unwrap(), 0);
+    }
+
+    #[actix::test]
+    async fn test_ancestors_on_a_fork_are_fetched() {
+        let sk = SecretKey::generate();
+        let genesis =
+            GenesisConfig::with_root_accounts(ChainParams::devnet(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+        let mut base = Blockchain::start(genesis, genesis_block);
+        mine_blocks(&mut base, &sk, 3);
+
+        // The node follows a longer chain than the fork of the source, so syncing would not fetch the fork
+        let mut longer = base.clone();
+        mine_blocks(&mut longer, &sk, 4);
+        let head_timeslot = longer
+            .get_block(longer.best_path_head())
+            .unwrap()
+            .header
+            .timeslot;
+        let mut fork = base.clone();
+        let block = (head_timeslot + 1..)
+            .find_map(|timeslot| fork.make_block_at(&sk, timeslot))
+            .unwrap();
+        fork.add_block(block).unwrap();
+        mine_blocks(&mut fork, &sk, 2);
+
+        let sources = [BlockchainActor::new(fork.clone(), sk.clone()).start()];
+        let node = BlockchainActor::new(longer.clone(), sk.clone()).start();
+        let parent = fork.best_path_head().clone();
+        let added = fetch_ancestors(&node, &sources, parent.clone(), None)
+            .await
+            .unwrap();
+        assert_eq!(added, 3);
+        assert!(node.send(HasBlock(parent.clone())).await.unwrap());
+        assert_eq!(
+            node.send(GetBestHead).await.unwrap(),
+            longer.best_path_head().clone()
+        );
+
+        // Nothing is fetched once we have the block
+        let added = fetch_ancestors(&node, &sources, parent, None)
+            .await
+            .unwrap();
+        assert_eq!(added, 0);
+    }
+
+    #[actix::test]
+    async fn test_invalid_headers_are_rejected() {
+        let sk = SecretKey::generate();
+        let genesis =
+            GenesisConfig::with_root_accounts(ChainParams::devnet(), vec![sk.get_public_key()]);
+        let genesis_block = Blockchain::produce_genesis_block(&genesis, &sk);
+        let empty = Blockchain::start(genesis, genesis_block);
+
+        // Blocks signed by a key that can't stake
+        let mut forged = empty.clone();
+        let block = forged.make_next_block(&sk);
+        let outsider = SecretKey::generate();
+        let forged_block = Block::new(
+            block.header.timeslot,
+            block.header.prev_hash,
+            block.header.depth,
+            Vec::new(),
+            block.header.state_root,
+            &outsider,
+            block.header.draw.seed.clone(),
+        );
+        forged.best_path.push(forged_block.ptr());
+        forged
+            .blocks
+            .push(HashMap::from([(forged_block.header.hash, forged_block)]));
+
+        let source = BlockchainActor::new(forged, sk.clone()).start();
+        let node = BlockchainActor::new(empty, sk.clone()).start();
+        assert!(headers_first_sync(&node, &[source]).await.is_err());
+        assert_eq!(node.send(GetBestHead).await.unwrap().depth, 0);
+    }
+}
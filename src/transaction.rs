@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -11,39 +11,69 @@ pub struct Transaction {
     pub from: PublicKey,
     pub to: PublicKey,
     pub amount: u64,
+    pub fee: MiniLas, // Paid to the winner of the block that includes the transaction
     pub nonce: u64,
     pub signature: Signature,
     pub hash: Sha256Hash,
 }
 
 impl Transaction {
-    pub fn new(from: &SecretKey, to: PublicKey, amount: impl Into<MiniLas>, nonce: u64) -> Self {
+    pub fn new(
+        from: &SecretKey,
+        to: PublicKey,
+        amount: impl Into<MiniLas>,
+        fee: MiniLas,
+        nonce: u64,
+    ) -> Self {
         let amount = amount.into();
         let from_pk = from.get_public_key().clone();
-        let public_values = ("Transaction", &from_pk, &to, amount, nonce);
+        let public_values = ("Transaction", &from_pk, &to, amount, fee, nonce);
         let signature = Signature::sign(from, &public_values.into_bytes());
-        
-        let hash = hash(&(public_values, signature.clone()).into_bytes());
 
-        Self {
+        let mut transaction = Self {
             from: from_pk,
             to: to.clone(),
             amount,
+            fee,
             nonce,
             signature,
-            hash,
-        }
+            hash: [0; 32],
+        };
+        transaction.hash = transaction.compute_hash();
+        transaction
+    }
+
+    fn public_values(&self) -> (&str, &PublicKey, &PublicKey, MiniLas, MiniLas, u64) {
+        (
+            "Transaction",
+            &self.from,
+            &self.to,
+            self.amount,
+            self.fee,
+            self.nonce,
+        )
     }
 
+    /// The hash of the contents, which the `hash` field must be equal to
+    pub fn compute_hash(&self) -> Sha256Hash {
+        hash(&(self.public_values(), &self.signature).into_bytes())
+    }
+
+    /// Checks the hash and the signature of the sender
     pub fn verify_signature(&self) -> Result<()> {
-        let public_values = ("Transaction", &self.from, &self.to, self.amount, self.nonce);
-        self.signature.verify(&self.from, &public_values.into_bytes())
+        if self.compute_hash() != self.hash {
+            return Err(anyhow!("Computed hash does not match provided hash"));
+        }
+
+        self.signature
+            .verify(&self.from, &self.public_values().into_bytes())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::mempool::MIN_RELAY_FEE;
 
     #[test]
     fn test_signature() {
@@ -51,12 +81,25 @@ mod tests {
 
         let sk2 = SecretKey::generate();
         let pk2 = sk2.get_public_key();
-        let mut transaction = Transaction::new(&sk1, pk2, 42u64, 1);
+        let mut transaction = Transaction::new(&sk1, pk2, 42u64, MIN_RELAY_FEE, 1);
 
         transaction.verify_signature().unwrap();
 
         transaction.amount = 41;
 
         assert!(transaction.verify_signature().is_err());
+
+        // The fee is signed as well, so it can't be lowered by whoever relays the transaction
+        transaction.amount = 42;
+        transaction.fee = 0;
+        assert!(transaction.verify_signature().is_err());
+
+        // The body of another transaction can't be passed off under the hash of this one
+        transaction.fee = MIN_RELAY_FEE;
+        transaction.verify_signature().unwrap();
+        let mut tampered = Transaction::new(&sk1, sk2.get_public_key(), 42u64, MIN_RELAY_FEE, 2);
+        tampered.verify_signature().unwrap();
+        tampered.hash = transaction.hash;
+        assert!(tampered.verify_signature().is_err());
     }
 }
\ No newline at end of file
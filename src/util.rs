@@ -1,5 +1,5 @@
 use bincode::config::Configuration;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use sha2::{Digest, Sha256};
 
 pub type Sha256Hash = [u8; 32];
@@ -12,6 +12,7 @@ pub fn hash(bytes: &[u8]) -> Sha256Hash {
 }
 
 pub trait SerToBytes {
+    #[allow(clippy::wrong_self_convention)]
     fn into_bytes(&self) -> Vec<u8>;
 }
 
@@ -25,7 +26,21 @@ impl<T: Serialize> SerToBytes for T {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub trait FromBytes: Sized {
+    fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self>;
+}
+
+impl<T: DeserializeOwned> FromBytes for T {
+    fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let (value, _) = bincode::serde::decode_from_slice::<_, Configuration>(
+            bytes,
+            bincode::config::Configuration::default(),
+        )?;
+        Ok(value)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct BlockPtr {
     pub hash: Sha256Hash,
     pub depth: i64,
@@ -39,19 +54,13 @@ impl BlockPtr {
 
 pub type Timeslot = u64;
 
-#[cfg(not(test))]
-pub const SLOT_LENGTH: u128 = 1_000_000;
-#[cfg(test)]
-pub const SLOT_LENGTH: u128 = 1; // 0.001 millisecond for testing
-
 pub const START_TIME: u128 = 1761384740000000;
 
 pub type MiniLas = u64; // 1 millionth of a LAS
 
-pub fn calculate_timeslot(start_time: u128) -> Timeslot {
-    let now = get_unix_timestamp();
+pub fn calculate_timeslot(now: u128, start_time: u128, slot_length: u128) -> Timeslot {
     let start = start_time;
-    let timeslot = (now - start) / SLOT_LENGTH;
+    let timeslot = now.saturating_sub(start) / slot_length;
     timeslot as _
 }
 
@@ -61,4 +70,28 @@ pub fn get_unix_timestamp() -> u128 {
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_micros()
-}
\ No newline at end of file
+}
+
+// Serializes with `Display` and deserializes with `FromStr`, for values that are written by hand
+pub(crate) mod as_string {
+    use std::{fmt::Display, str::FromStr};
+
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<T: Display, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: FromStr<Err: Display>,
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}